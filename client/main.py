@@ -1,94 +1,546 @@
 import socket
+import ssl
 import argparse
+import base64
+import hashlib
+import hmac
+import json
+import os
+import time
 
-def read_value(key: int, ip_addr: str) -> bytes:
+DEFAULT_PORT = 52525
+ERROR_MESSAGE_TYPE = 255
+AUTHENTICATE_MESSAGE_TYPE = 15
+ENABLE_CHECKSUMS_MESSAGE_TYPE = 16
+ERROR_CODES = {1: 'bad request', 2: 'unavailable', 3: 'internal', 4: 'unauthorized', 5: 'corrupted', 6: 'storage full',
+               7: 'conflict'}
+EXPORT_MAGIC = b'DSKV'
+EXPORT_FORMAT_VERSION = 1
+STORAGES = ['leader', 'backup']
+
+class DatastoreError(Exception):
+    """
+    An error response sent by the datastore.
+    """
+
+    def __init__(self, code: int, message: str):
+        super().__init__(f'{ERROR_CODES.get(code, "unknown")} error: {message}')
+        self.code = code
+
+def recv_exact(s: socket.socket, length: int) -> bytes:
+    """
+    Receive exactly the given number of bytes from the socket.
+    """
+
+    data = b''
+    while len(data) < length:
+        chunk = s.recv(length - len(data))
+        if not chunk:
+            raise ConnectionError("connection closed unexpectedly")
+        data += chunk
+    return data
+
+def crc32c_table() -> list[int]:
+    """
+    Build the lookup table of the CRC32C (Castagnoli) checksum.
+    """
+
+    table = []
+    for byte in range(256):
+        crc = byte
+        for _ in range(8):
+            crc = (crc >> 1) ^ 0x82F63B78 if crc & 1 else crc >> 1
+        table.append(crc)
+    return table
+
+CRC32C_TABLE = crc32c_table()
+
+def crc32c(data: bytes) -> int:
+    """
+    Compute the CRC32C checksum of the given bytes, the checksum that follows the messages of a connection.
+    """
+
+    crc = 0xFFFFFFFF
+    for byte in data:
+        crc = CRC32C_TABLE[(crc ^ byte) & 0xFF] ^ (crc >> 8)
+    return crc ^ 0xFFFFFFFF
+
+class ChecksummedSocket:
+    """
+    A connection to a node whose messages are followed by their CRC32C checksums in both directions.
+    Every `sendall` must send exactly one message, and `recv` returns the bytes of checked messages only.
+    """
+
+    def __init__(self, s: socket.socket):
+        self.s = s
+        self.buffer = b''
+
+    def sendall(self, message: bytes) -> None:
+        self.s.sendall(message + int.to_bytes(crc32c(message), 4))
+
+    def recv(self, length: int) -> bytes:
+        if not self.buffer:
+            header = recv_exact(self.s, 5)
+            message = header + recv_exact(self.s, int.from_bytes(header[1:5]) - 5)
+            if int.from_bytes(recv_exact(self.s, 4)) != crc32c(message):
+                self.s.close()
+                raise ValueError("message corrupted on the way from the node")
+            self.buffer = message
+        data, self.buffer = self.buffer[:length], self.buffer[length:]
+        return data
+
+    def close(self) -> None:
+        self.s.close()
+
+def raise_if_error(s: socket.socket, header: bytes) -> None:
+    """
+    Raise `DatastoreError` if the message with the given header is an error response.
+    """
+
+    if header[0] != ERROR_MESSAGE_TYPE:
+        return
+    payload = recv_exact(s, int.from_bytes(header[1:5]) - 5)
+    s.close()
+    raise DatastoreError(payload[0], payload[1:].decode(errors='replace'))
+
+def authentication_message(user: str, secret: str) -> bytes:
+    """
+    Build the message proving that this client knows the secret of the given user.
+    """
+
+    name = user.encode()
+    signed = bytes([1]) + int.to_bytes(int(time.time()), 8) + os.urandom(8) + name
+    mac = hmac.new(secret.encode(), signed, hashlib.sha256).digest()
+    payload = signed[:17] + mac + name
+    return bytes([AUTHENTICATE_MESSAGE_TYPE]) + int.to_bytes(len(payload) + 5, 4) + payload
+
+def open_connection(ip_addr: str, port: int, tls_ca: str | None,
+                    credentials: tuple[str, str] | None) -> ChecksummedSocket:
+    """
+    Open a connection to a node, secured with TLS if the path to the cluster CA certificate is given
+    and authenticated if the user name and secret are given.
+    Every later message of the connection carries its checksum, so corruption on the way is detected.
+    """
+
+    s = socket.create_connection((ip_addr, port))
+    if tls_ca is not None:
+        context = ssl.create_default_context(cafile=tls_ca)
+        s = context.wrap_socket(s, server_hostname=ip_addr)
+    if credentials is not None:
+        s.sendall(authentication_message(*credentials))
+
+    # the node acknowledges enabling checksums with a message without checksum
+    s.sendall(bytes([ENABLE_CHECKSUMS_MESSAGE_TYPE]) + int.to_bytes(5, 4))
+    header = recv_exact(s, 5)
+    raise_if_error(s, header)
+    if header + recv_exact(s, 2) != bytes([0]) + int.to_bytes(7, 4) + bytes([111, 107]):
+        raise ValueError("malformed response to enabling checksums")
+    return ChecksummedSocket(s)
+
+def read_value(key: int, ip_addr: str, port: int = DEFAULT_PORT, tls_ca: str | None = None,
+               credentials: tuple[str, str] | None = None, version: int | None = None,
+               at: float | None = None) -> tuple[bytes | None, int]:
     """
     Read a value of the given key from the datastore.
+    Returns the value, `None` if the key has no value, and the version of the key.
+    With a version or a time, reads the past value from the history of the key.
 
     :param key: The key whose value to read.
     :param ip_addr: The IP address of any node in the datastore system.
+    :param port: The port of that node.
+    :param tls_ca: Path to the cluster CA certificate if the datastore uses TLS.
+    :param credentials: User name and secret if the datastore requires client authentication.
+    :param version: The version of the key to read.
+    :param at: The time to read the value at, in seconds since the Unix epoch.
     """
 
     # send request
-    s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
-    s.connect((ip_addr, 52525))
-    s.sendall(bytes([200]) + int.to_bytes(13, 4) + int.to_bytes(key, 8))
+    s = open_connection(ip_addr, port, tls_ca, credentials)
+    if version is not None:
+        s.sendall(bytes([200]) + int.to_bytes(22, 4) + int.to_bytes(key, 8) + bytes([0]) + int.to_bytes(version, 8))
+    elif at is not None:
+        s.sendall(bytes([200]) + int.to_bytes(22, 4) + int.to_bytes(key, 8) + bytes([1])
+                  + int.to_bytes(round(at * 1000), 8))
+    else:
+        s.sendall(bytes([200]) + int.to_bytes(13, 4) + int.to_bytes(key, 8))
 
     # receive the header of the message and get the message length
-    header = s.recv(5)
+    header = recv_exact(s, 5)
+    raise_if_error(s, header)
     if header[0] != 0:
         raise ValueError("unexpected response message type")
     msg_length = int.from_bytes(header[1:5])
 
-    # read the responded value
-    value = s.recv(msg_length - 5)
+    # read the found flag, the version and the responded value
+    payload = recv_exact(s, msg_length - 5)
     s.close()
 
-    return value
+    version = int.from_bytes(payload[1:9])
+    return (payload[9:] if payload[0] == 1 else None), version
 
-def write_value(key: int, new_value: bytes | None, ip_addr: str) -> None:
+def write_value(key: int, new_value: bytes | None, ip_addr: str, port: int = DEFAULT_PORT, tls_ca: str | None = None,
+                credentials: tuple[str, str] | None = None, ttl: float | None = None,
+                if_version: int | None = None, if_value: bytes | None = None, if_absent: bool = False,
+                verbose: bool = True) -> int:
     """
-    Writes a new value for the given key.
+    Writes a new value for the given key and returns the version of the new value.
+    With a condition, the value is written only if the key still matches it,
+    otherwise a conflict error is raised.
 
     :param key: The key that identifies the value.
     :param new_value: The new value bytes. If this is `None`, then new value is
         read from the stdin after receiving the old value.
     :param ip_addr: The IP address of any node in the datastore system.
+    :param port: The port of that node.
+    :param tls_ca: Path to the cluster CA certificate if the datastore uses TLS.
+    :param credentials: User name and secret if the datastore requires client authentication.
+    :param ttl: Seconds after which the new value expires, or `None` if it never expires.
+    :param if_version: Write only if the key has this version.
+    :param if_value: Write only if the key has this value.
+    :param if_absent: Write only if the key has no value.
+    :param verbose: Whether to print the old value.
     """
 
-    s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
-    s.connect((ip_addr, 52525))
+    s = open_connection(ip_addr, port, tls_ca, credentials)
 
     # send request
-    s.sendall(bytes([202]) + int.to_bytes(13, 4) + int.to_bytes(key, 8))
+    if ttl is None:
+        s.sendall(bytes([202]) + int.to_bytes(13, 4) + int.to_bytes(key, 8))
+    else:
+        s.sendall(bytes([202]) + int.to_bytes(21, 4) + int.to_bytes(key, 8) + int.to_bytes(round(ttl * 1000), 8))
 
     # wait for the permission
-    permission_msg_header = s.recv(5)
+    permission_msg_header = recv_exact(s, 5)
+    raise_if_error(s, permission_msg_header)
     if permission_msg_header[0] != 0:
         raise ValueError("malformed permission")
     old_value_length = int.from_bytes(permission_msg_header[1:5]) - 5
-    old_value = s.recv(old_value_length)
+    permission = recv_exact(s, old_value_length)
 
-    print('old value was', old_value)
+    if verbose and permission[0] == 1:
+        print('old value was', permission[9:], 'version', int.from_bytes(permission[1:9]))
+    elif verbose:
+        print('there was no old value')
 
     if new_value is None:
         new_value = input('new value: ').encode()
 
-    # send the new value
-    new_request_length = len(new_value) + 5
-    s.sendall(bytes([0]) + int.to_bytes(new_request_length, 4) + new_value)
+    # send the new value, as a compare-and-swap if there is a condition
+    if if_version is not None:
+        command = bytes([208]) + int.to_bytes(len(new_value) + 14, 4) + bytes([0]) + int.to_bytes(if_version, 8)
+    elif if_value is not None:
+        command = bytes([208]) + int.to_bytes(len(new_value) + len(if_value) + 10, 4) + bytes([1]) \
+            + int.to_bytes(len(if_value), 4) + if_value
+    elif if_absent:
+        command = bytes([208]) + int.to_bytes(len(new_value) + 6, 4) + bytes([2])
+    else:
+        command = bytes([0]) + int.to_bytes(len(new_value) + 5, 4)
+    s.sendall(command + new_value)
+
+    # check the acknowledgement
+    ack_header = recv_exact(s, 5)
+    raise_if_error(s, ack_header)
+    ack_message = ack_header + recv_exact(s, 10)
+    if ack_message[:7] != bytes([0]) + int.to_bytes(15, 4) + bytes([111, 107]):
+        raise ValueError("malformed ack response")
+
+    return int.from_bytes(ack_message[7:])
+
+def delete_value(key: int, ip_addr: str, port: int = DEFAULT_PORT, tls_ca: str | None = None,
+                 credentials: tuple[str, str] | None = None) -> None:
+    """
+    Delete the value of the given key from the datastore.
+
+    :param key: The key whose value to delete.
+    :param ip_addr: The IP address of any node in the datastore system.
+    :param port: The port of that node.
+    :param tls_ca: Path to the cluster CA certificate if the datastore uses TLS.
+    :param credentials: User name and secret if the datastore requires client authentication.
+    """
+
+    s = open_connection(ip_addr, port, tls_ca, credentials)
+    s.sendall(bytes([206]) + int.to_bytes(13, 4) + int.to_bytes(key, 8))
 
     # check the acknowledgement
-    ack_message = s.recv(7)
+    ack_header = recv_exact(s, 5)
+    raise_if_error(s, ack_header)
+    ack_message = ack_header + recv_exact(s, 2)
+    s.close()
     if ack_message != bytes([0]) + int.to_bytes(7, 4) + bytes([111, 107]):
         raise ValueError("malformed ack response")
 
+def usage(ip_addr: str, port: int = DEFAULT_PORT, tls_ca: str | None = None,
+          credentials: tuple[str, str] | None = None) -> dict[str, int]:
+    """
+    Read the memory usage of the given node.
+
+    :param ip_addr: The IP address of the node.
+    :param port: The port of that node.
+    :param tls_ca: Path to the cluster CA certificate if the datastore uses TLS.
+    :param credentials: User name and secret if the datastore requires client authentication.
+    """
+
+    s = open_connection(ip_addr, port, tls_ca, credentials)
+    s.sendall(bytes([204]) + int.to_bytes(5, 4))
+
+    header = recv_exact(s, 5)
+    raise_if_error(s, header)
+    if header != bytes([0]) + int.to_bytes(53, 4):
+        raise ValueError("malformed usage response")
+    payload = recv_exact(s, 48)
+    s.close()
+
+    names = ['used', 'budget', 'leader_keys', 'leader_bytes', 'backup_keys', 'backup_bytes']
+    return {name: int.from_bytes(payload[8 * i:8 * i + 8]) for i, name in enumerate(names)}
+
+def export_items(ip_addr: str, port: int = DEFAULT_PORT, tls_ca: str | None = None,
+                 credentials: tuple[str, str] | None = None, include_backups: bool = False) -> bytes:
+    """
+    Read the key-value pairs of the leader storage of the given node, and of its backup storage if asked.
+    Returns the exported items as sent by the node.
+
+    :param ip_addr: The IP address of the node.
+    :param port: The port of that node.
+    :param tls_ca: Path to the cluster CA certificate if the datastore uses TLS.
+    :param credentials: User name and secret if the datastore requires client authentication.
+    :param include_backups: Whether to export the backup storage as well.
+    """
+
+    s = open_connection(ip_addr, port, tls_ca, credentials)
+    s.sendall(bytes([210]) + int.to_bytes(6, 4) + bytes([int(include_backups)]))
+
+    # receive chunks until the empty end-of-export message
+    chunks = []
+    while True:
+        header = recv_exact(s, 5)
+        raise_if_error(s, header)
+        if header[0] != 0:
+            raise ValueError("unexpected export chunk message type")
+        chunk = recv_exact(s, int.from_bytes(header[1:5]) - 5)
+        if not chunk:
+            break
+        chunks.append(chunk)
+    s.close()
+    return b''.join(chunks)
+
+def parse_entry(data: bytes, offset: int) -> tuple[dict, int]:
+    """
+    Parse the fixed metadata fields of a value starting at the given offset.
+    Returns the fields and the offset after them.
+    """
+
+    expires_at = int.from_bytes(data[offset:offset + 8])
+    entry = {
+        'version': int.from_bytes(data[offset + 8:offset + 16]),
+        'written_at': int.from_bytes(data[offset + 16:offset + 24]),
+        'expires_at': expires_at if expires_at != 0 else None,
+        'deleted': data[offset + 24] & 1 == 1,
+    }
+    return entry, offset + 25
+
+def parse_value(data: bytes, offset: int) -> tuple[bytes, int]:
+    """
+    Parse a value preceded by its length starting at the given offset.
+    Returns the value and the offset after it.
+    """
+
+    length = int.from_bytes(data[offset:offset + 4])
+    return data[offset + 4:offset + 4 + length], offset + 4 + length
+
+def parse_export_items(items: bytes) -> list[dict]:
+    """
+    Parse the items exported by a node into records of the storage, the key, the value and its metadata.
+    """
+
+    records = []
+    offset = 0
+    while offset < len(items):
+        storage = STORAGES[items[offset]]
+        key = int.from_bytes(items[offset + 1:offset + 9])
+        record, offset = parse_entry(items, offset + 9)
+        history_length = int.from_bytes(items[offset:offset + 4])
+        offset += 4
+        history = []
+        for _ in range(history_length):
+            past, offset = parse_entry(items, offset)
+            past['value'], offset = parse_value(items, offset)
+            history.append(past)
+        record['value'], offset = parse_value(items, offset)
+        records.append({'storage': storage, 'key': key, **record, 'history': history})
+    return records
+
+def json_record(record: dict) -> dict:
+    """
+    Convert a record to a JSON object, encoding the values in base64.
+    """
+
+    converted = {**record, 'value': base64.b64encode(record['value']).decode()}
+    if 'history' in record:
+        converted['history'] = [json_record(past) for past in record['history']]
+    return converted
+
+def record_from_json(converted: dict) -> dict:
+    """
+    Convert a JSON object written by `json_record` back to a record.
+    """
+
+    record = {**converted, 'value': base64.b64decode(converted['value'])}
+    if 'history' in converted:
+        record['history'] = [record_from_json(past) for past in converted['history']]
+    return record
+
+def write_export_file(path: str, items: bytes, file_format: str) -> int:
+    """
+    Write the items exported by a node to a file, either as such after a versioned header (`binary`)
+    or as a header line followed by one JSON object per key-value pair (`jsonl`).
+    Returns the number of exported key-value pairs.
+    """
+
+    records = parse_export_items(items)
+    with open(path, 'wb') as file:
+        if file_format == 'binary':
+            file.write(EXPORT_MAGIC + int.to_bytes(EXPORT_FORMAT_VERSION, 2) + items)
+        else:
+            header = {'format': 'ds-export', 'version': EXPORT_FORMAT_VERSION}
+            lines = [header] + [json_record(record) for record in records]
+            file.write(''.join(json.dumps(line) + '\n' for line in lines).encode())
+    return len(records)
+
+def read_export_file(path: str) -> list[dict]:
+    """
+    Read the records of a file written by `write_export_file` in either format.
+    """
+
+    with open(path, 'rb') as file:
+        data = file.read()
+
+    if data.startswith(EXPORT_MAGIC):
+        version = int.from_bytes(data[4:6])
+        if version != EXPORT_FORMAT_VERSION:
+            raise ValueError(f'unsupported export format version {version}')
+        return parse_export_items(data[6:])
+
+    lines = data.decode().splitlines()
+    header = json.loads(lines[0]) if lines else {}
+    if header.get('format') != 'ds-export':
+        raise ValueError('not an export file')
+    if header.get('version') != EXPORT_FORMAT_VERSION:
+        raise ValueError(f'unsupported export format version {header.get("version")}')
+    return [record_from_json(json.loads(line)) for line in lines[1:] if line]
+
+def import_records(records: list[dict], ip_addr: str, port: int = DEFAULT_PORT, tls_ca: str | None = None,
+                   credentials: tuple[str, str] | None = None, include_backups: bool = False) -> int:
+    """
+    Write the current values of the given records through the normal write and delete requests,
+    so that the leaders of the keys replicate them to their backups.
+    The values keep their remaining time to live, and expired values are left out.
+    The keys get new versions, and the past versions are not imported.
+    Returns the number of imported key-value pairs.
+
+    :param records: The records read from an export file.
+    :param ip_addr: The IP address of any node in the datastore system.
+    :param port: The port of that node.
+    :param tls_ca: Path to the cluster CA certificate if the datastore uses TLS.
+    :param credentials: User name and secret if the datastore requires client authentication.
+    :param include_backups: Whether to import the records of backup storages as well.
+    """
+
+    imported = 0
+    for record in records:
+        if record['storage'] == 'backup' and not include_backups:
+            continue
+        now = time.time() * 1000
+        if record['expires_at'] is not None and record['expires_at'] <= now:
+            continue
+
+        if record['deleted']:
+            delete_value(record['key'], ip_addr, port, tls_ca, credentials)
+        else:
+            ttl = None if record['expires_at'] is None else (record['expires_at'] - now) / 1000
+            write_value(record['key'], record['value'], ip_addr, port, tls_ca, credentials, ttl, verbose=False)
+        imported += 1
+    return imported
+
 def parse_args():
     parser = argparse.ArgumentParser(description='A sample client for accessing the key-value store')
 
     parser.add_argument('nodeip', help='IP address of any node in the datastore')
+    parser.add_argument('--port', type=int, default=DEFAULT_PORT, help=f'port of that node (default: {DEFAULT_PORT})')
+    parser.add_argument('--tls-ca', help='path to the cluster CA certificate to connect with TLS')
+    parser.add_argument('--user', help='user name for client authentication')
+    parser.add_argument('--secret', help='secret of that user (default: DS_CLIENT_SECRET environment variable)')
 
     subparsers = parser.add_subparsers(dest='action', required=True, help='action to perform')
 
     parser_r = subparsers.add_parser('r', help='read the value of a given key')
     parser_r.add_argument('key', type=int, help='the key whose value to read')
+    read_point = parser_r.add_mutually_exclusive_group()
+    read_point.add_argument('--version', type=int, help='read this past version of the key')
+    read_point.add_argument('--ago', type=float, help='read the value that the key had this many seconds ago')
 
     parser_w = subparsers.add_parser('w', help='write the value for a given key')
     parser_w.add_argument('key', type=int, help='the key whose value to write')
     parser_w.add_argument('value', nargs='?', help='the value to write (default: stdin)')
+    parser_w.add_argument('--ttl', type=float, help='seconds after which the value expires (default: never)')
+    condition = parser_w.add_mutually_exclusive_group()
+    condition.add_argument('--if-version', type=int, help='write only if the key has this version')
+    condition.add_argument('--if-value', help='write only if the key has this value')
+    condition.add_argument('--if-absent', action='store_true', help='write only if the key has no value')
+
+    parser_d = subparsers.add_parser('d', help='delete the value of a given key')
+    parser_d.add_argument('key', type=int, help='the key whose value to delete')
+
+    subparsers.add_parser('u', help='show the memory usage of the node')
+
+    parser_e = subparsers.add_parser('e', help='export the key-value pairs of the node to a file')
+    parser_e.add_argument('file', help='the file to write')
+    parser_e.add_argument('--backups', action='store_true', help='export the backup storage of the node as well')
+    parser_e.add_argument('--format', choices=['binary', 'jsonl'], default='binary',
+                          help='format of the file (default: binary)')
+
+    parser_i = subparsers.add_parser('i', help='import the key-value pairs of an exported file')
+    parser_i.add_argument('file', help='the file to read')
+    parser_i.add_argument('--backups', action='store_true', help='import the exported backup storages as well')
 
     return parser.parse_args()
 
 def main():
     args = parse_args()
+    secret = args.secret if args.secret is not None else os.environ.get('DS_CLIENT_SECRET')
+    credentials = None if args.user is None else (args.user, secret or '')
+
+    try:
+        if args.action == 'r':
+            at = None if args.ago is None else time.time() - args.ago
+            value, version = read_value(args.key, args.nodeip, args.port, args.tls_ca, credentials,
+                                        args.version, at)
+            print(value if value is not None else 'not found', 'version', version)
+
+        elif args.action == 'w':
+            value = None if args.value is None else args.value.encode()
+            if_value = None if args.if_value is None else args.if_value.encode()
+            version = write_value(args.key, value, args.nodeip, args.port, args.tls_ca, credentials, args.ttl,
+                                  args.if_version, if_value, args.if_absent)
+            print('written version', version)
+
+        elif args.action == 'd':
+            delete_value(args.key, args.nodeip, args.port, args.tls_ca, credentials)
+
+        elif args.action == 'u':
+            for name, number in usage(args.nodeip, args.port, args.tls_ca, credentials).items():
+                print(f'{name}: {number}')
+
+        elif args.action == 'e':
+            items = export_items(args.nodeip, args.port, args.tls_ca, credentials, args.backups)
+            print('exported', write_export_file(args.file, items, args.format), 'key-value pairs')
 
-    if args.action == 'r':
-        value = read_value(args.key, args.nodeip)
-        print(value)
+        elif args.action == 'i':
+            records = read_export_file(args.file)
+            imported = import_records(records, args.nodeip, args.port, args.tls_ca, credentials, args.backups)
+            print('imported', imported, 'key-value pairs')
 
-    elif args.action == 'w':
-        value = None if args.value is None else args.value.encode()
-        write_value(args.key, value, args.nodeip)
+    except DatastoreError as error:
+        raise SystemExit(str(error))
 
 if __name__ == '__main__':
     main()
@@ -1,77 +1,250 @@
-use crate::helpers::communication::Connection;
-use std::collections::HashMap;
-use tokio::sync::mpsc;
+use crate::helpers::communication::{Connection, ErrorCode, KvPairsReceiver, Message};
+use crate::helpers::entry::Entry;
+use crate::helpers::storage::{
+    Durability, StorageEngine, StorageError, StorageStats, VersionedEngine,
+};
+use crate::helpers::stored_value::StoredValue;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+
+/// In-process access to the backup storage of this node.
+#[derive(Clone)]
+pub struct BackupHandle {
+    storage: Arc<Mutex<VersionedEngine>>,
+    durability: Option<Arc<Durability>>,
+}
+
+impl BackupHandle {
+    /// Wraps the given storage engine as the backup storage,
+    /// whose changes are logged to the given durability, if any.
+    pub fn new(
+        storage: Box<dyn StorageEngine>,
+        durability: Option<Arc<Durability>>,
+    ) -> BackupHandle {
+        BackupHandle {
+            storage: Arc::new(Mutex::new(VersionedEngine::new(storage))),
+            durability,
+        }
+    }
+
+    /// Inserts the given key-value pairs, replacing the existing values of the same keys,
+    /// and waits until they are on disk.
+    pub async fn insert_many(&self, kv_pairs: Vec<(u64, Entry)>) -> Result<(), StorageError> {
+        {
+            let mut storage_access = self.storage.lock().await;
+            for (key, entry) in kv_pairs {
+                storage_access.put(key, StoredValue::new(entry))?;
+            }
+        }
+        self.sync().await
+    }
+
+    /// Waits until the changes made to the backup storage so far are on disk, if they are logged.
+    pub async fn sync(&self) -> Result<(), StorageError> {
+        match &self.durability {
+            Some(durability) => durability.sync().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a copy of all key-value pairs, or an error if any of the values cannot be read.
+    pub async fn snapshot(&self) -> Result<Vec<(u64, Entry)>, StorageError> {
+        self.storage.lock().await.copy_range(0..=u64::MAX)
+    }
+
+    /// Returns the keys of the backup storage in ascending order.
+    pub async fn keys(&self) -> Vec<u64> {
+        self.storage.lock().await.keys(0..=u64::MAX)
+    }
+
+    /// Returns a copy of the key-value pairs whose keys are in the given range,
+    /// or an error if any of the values cannot be read.
+    pub async fn copy_range(
+        &self,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, Entry)>, StorageError> {
+        self.storage.lock().await.copy_range(key_range)
+    }
+
+    /// Removes the expired values and returns their keys.
+    pub async fn remove_expired(&self) -> Result<Vec<u64>, StorageError> {
+        self.storage.lock().await.remove_expired()
+    }
+
+    /// Returns the number of keys and the total length of the values.
+    pub async fn stats(&self) -> StorageStats {
+        self.storage.lock().await.stats()
+    }
+
+    /// Returns the greatest version of the values ever stored in the backup storage,
+    /// or of the leader storages of the neighbors that they were received from.
+    pub async fn version_high_water(&self) -> u64 {
+        self.storage.lock().await.version_high_water()
+    }
+
+    /// Raises the version high-water mark of the backup storage to the given version.
+    pub async fn raise_version_high_water(&self, version: u64) {
+        self.storage.lock().await.raise_version_high_water(version);
+    }
+
+    /// Removes and returns the key-value pairs whose keys are in the given range.
+    /// Nothing is removed if any of the values is corrupted.
+    pub async fn take_range(
+        &self,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, Entry)>, StorageError> {
+        self.storage.lock().await.take_range(key_range)
+    }
+}
 
 /// Handles incoming requests related to the backups kept by this node.
 pub async fn backup_block(
-    mut incoming_connection_stream: mpsc::UnboundedReceiver<(Connection, Vec<u8>)>,
-    initial_key_value_pairs: Vec<(u64, Vec<u8>)>,
+    mut incoming_connection_stream: mpsc::UnboundedReceiver<(Connection, Message)>,
+    backup: BackupHandle,
 ) {
-    let mut backup_storage: HashMap<u64, Vec<u8>> = HashMap::new();
-
-    for (key, value) in initial_key_value_pairs {
-        backup_storage.insert(key, value);
+    while let Some((mut connection, message)) = incoming_connection_stream.recv().await {
+        match message {
+            Message::BackupWrite { key, entry } => {
+                handle_write_request(connection, key, entry, &backup).await
+            }
+            Message::BackupArrayWrite { kv_pairs } => {
+                handle_array_write_request(connection, kv_pairs, &backup).await
+            }
+            _ => {
+                connection
+                    .send_error(ErrorCode::BadRequest, "unexpected message for backup block")
+                    .await;
+            }
+        };
     }
+}
 
-    while let Some((connection, message)) = incoming_connection_stream.recv().await {
-        match message.first() {
-            Some(20) => handle_write_request(connection, message, &mut backup_storage).await,
-            Some(21) => handle_array_write_request(connection, message, &mut backup_storage).await,
-            Some(32) => handle_transfer_request(connection, message, &mut backup_storage).await,
-            _ => {}
-        };
+/// Stores the entry unless the backup storage already has a newer version of the key.
+/// Updates of the same key from the leader may overtake each other on different connections,
+/// so an older one arriving last must not replace the newer value.
+/// A stored value that cannot be read is replaced.
+/// A value fails with `BudgetExceeded` if it does not fit the memory budget,
+/// while a tombstone is stored even over the budget, since it frees the value.
+fn put_unless_older(
+    storage: &mut VersionedEngine,
+    key: u64,
+    entry: Entry,
+) -> Result<(), StorageError> {
+    let stored_version = storage
+        .get(key)?
+        .and_then(|value| value.verified().ok().map(|stored| stored.version));
+    if stored_version.is_some_and(|version| version > entry.version) {
+        println!(
+            "ignoring backup key={} version={} older than the stored one",
+            key, entry.version
+        );
+        return Ok(());
+    }
+    if entry.deleted {
+        storage.put(key, StoredValue::new(entry))
+    } else {
+        storage.put_within_budget(key, StoredValue::new(entry))
     }
 }
 
 /// Handles an incoming request asking this node to write a single value to its backup.
 async fn handle_write_request(
     mut connection: Connection,
-    message: Vec<u8>,
-    backup_storage: &mut HashMap<u64, Vec<u8>>,
+    key: u64,
+    entry: Entry,
+    backup: &BackupHandle,
 ) {
-    // at this point, the first byte of message is 20
-    if message.len() < 13 {
-        println!("received invalid backup write request, dropping");
-        return;
-    }
-
-    let key = u64::from_be_bytes(message[5..13].try_into().unwrap());
-    let value = &message[13..];
-
     println!(
-        "updating backup key={} value={:?} from {}",
-        key, value, connection.address
+        "updating backup key={} value={:?} expires_at={:?} from {}",
+        key, entry.value, entry.expires_at, connection.address
     );
 
-    backup_storage.insert(key, value.to_vec());
+    let result = put_unless_older(&mut *backup.storage.lock().await, key, entry);
+    let result = match result {
+        Ok(()) => backup.sync().await,
+        Err(error) => Err(error),
+    };
 
-    connection.send_message(&[0, 0, 0, 0, 7, 111, 107]).await;
+    match result {
+        Ok(()) => {
+            connection.send_message(&Message::ok()).await;
+        }
+        Err(error) => {
+            println!("failed to update backup key={} ({})", key, error);
+            connection
+                .send_error(error.error_code(), &error.to_string())
+                .await;
+        }
+    }
 }
 
 /// Handles an incoming request asking this node to write multiple values to its backup.
+/// The pairs in the request may be followed by more chunks of pairs.
+/// Each chunk is stored as it arrives, so if the transfer fails midway the pairs written so far stay
+/// in the backup storage. The sender responds to the failure by sending the whole replica again.
 async fn handle_array_write_request(
     mut connection: Connection,
-    message: Vec<u8>,
-    backup_storage: &mut HashMap<u64, Vec<u8>>,
+    kv_pairs: Vec<(u64, Entry)>,
+    backup: &BackupHandle,
 ) {
-    // at this point, the first byte of message is 21
-    if message.len() < 5 {
-        println!("received invalid backup array write request, dropping");
-        return;
-    }
-
     let mut keys = Vec::new();
+    let mut receiver = KvPairsReceiver::following_request(&mut connection);
+    let mut chunk = kv_pairs;
 
-    let mut i = 5;
-    while i < message.len() {
-        let key = u64::from_be_bytes(message[i..i + 8].try_into().unwrap());
-        let value_length = u32::from_be_bytes(message[i + 8..i + 12].try_into().unwrap()) as usize;
-        let value = &message[i + 12..i + 12 + value_length];
+    loop {
+        {
+            let mut storage_access = backup.storage.lock().await;
+            for (key, entry) in chunk {
+                if let Err(error) = put_unless_older(&mut storage_access, key, entry) {
+                    println!(
+                        "failed to write backup array from {} ({}), wrote keys {:?}",
+                        connection.address, error, keys
+                    );
+                    drop(storage_access);
+                    connection
+                        .send_error(error.error_code(), &error.to_string())
+                        .await;
+                    return;
+                }
+                keys.push(key);
+            }
+        }
 
-        backup_storage.insert(key, value.to_vec());
-        keys.push(key);
+        chunk = match receiver.next_chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(error) => {
+                println!(
+                    "failed to receive backup array from {} ({}), wrote keys {:?}",
+                    connection.address, error, keys
+                );
+                connection
+                    .send_error(ErrorCode::BadRequest, &error.to_string())
+                    .await;
+                return;
+            }
+        };
+    }
 
-        i += value_length + 12;
+    // the versions of the keys that the leader no longer has are not reused when this node takes over
+    if let Some(version_high_water) = receiver.version_high_water() {
+        backup
+            .storage
+            .lock()
+            .await
+            .raise_version_high_water(version_high_water);
+    }
+
+    if let Err(error) = backup.sync().await {
+        println!(
+            "failed to log backup array from {} ({})",
+            connection.address, error
+        );
+        connection
+            .send_error(error.error_code(), &error.to_string())
+            .await;
+        return;
     }
 
     println!(
@@ -79,53 +252,89 @@ async fn handle_array_write_request(
         keys, connection.address
     );
 
-    connection.send_message(&[0, 0, 0, 0, 7, 111, 107]).await;
+    connection.send_message(&Message::ok()).await;
 }
 
-/// Handles an incoming request asking this node to remove
-/// and respond a range of key-value pairs from the backup.
-pub async fn handle_transfer_request(
-    mut connection: Connection,
-    message: Vec<u8>,
-    storage: &mut HashMap<u64, Vec<u8>>,
-) {
-    // at this point the first byte of message is 32
-    if message.len() != 21 {
-        println!("received invalid backup transfer request, dropping");
-        return;
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::helpers::storage::{BudgetPolicy, MemoryBudget, MemoryEngine};
+    use std::time::Duration;
+
+    fn entry(value: u8) -> Entry {
+        Entry::new(vec![value])
     }
 
-    let key_lower_bound = u64::from_be_bytes(message[5..13].try_into().unwrap());
-    let key_upper_bound = u64::from_be_bytes(message[13..21].try_into().unwrap());
+    #[tokio::test]
+    async fn older_versions_ignored() {
+        let backup = BackupHandle::new(Box::new(MemoryEngine::new()), None);
+        let version = |value, version| Entry {
+            version,
+            ..entry(value)
+        };
 
-    let keys_to_transfer: Vec<_> = storage
-        .keys()
-        .filter(|&&key| key_lower_bound <= key && key <= key_upper_bound)
-        .cloned()
-        .collect();
+        // version 6 arrives before version 5
+        let mut storage_access = backup.storage.lock().await;
+        put_unless_older(&mut storage_access, 1, version(6, 6)).unwrap();
+        put_unless_older(&mut storage_access, 1, version(5, 5)).unwrap();
+        put_unless_older(&mut storage_access, 2, version(2, 2)).unwrap();
+        put_unless_older(&mut storage_access, 2, version(3, 3)).unwrap();
+        drop(storage_access);
 
-    println!(
-        "transfering keys {:?} ({}..={}) out from backup",
-        keys_to_transfer, key_lower_bound, key_upper_bound
-    );
+        assert_eq!(
+            backup.snapshot().await.unwrap(),
+            vec![(1, version(6, 6)), (2, version(3, 3))]
+        );
+    }
 
-    let mut response_payload = Vec::new();
+    #[tokio::test]
+    async fn writes_within_budget() {
+        let budget = MemoryBudget::new(Some(2 * (8 + 10)), BudgetPolicy::Evict);
+        let backup = BackupHandle::new(budget.accounted(Box::new(MemoryEngine::new())), None);
 
-    for key in keys_to_transfer {
-        let value = storage.remove(&key).unwrap();
+        let mut storage_access = backup.storage.lock().await;
+        put_unless_older(&mut storage_access, 1, Entry::new(vec![1; 10])).unwrap();
+        put_unless_older(&mut storage_access, 2, Entry::new(vec![2; 10])).unwrap();
+        // backups are never evicted, so the policy does not matter
+        let error = put_unless_older(&mut storage_access, 3, Entry::new(vec![3; 10])).unwrap_err();
+        assert!(matches!(error, StorageError::BudgetExceeded));
+        assert_eq!(error.error_code(), ErrorCode::StorageFull);
 
-        response_payload.extend_from_slice(&key.to_be_bytes());
-        response_payload.extend_from_slice(&(value.len() as u32).to_be_bytes());
-        response_payload.extend_from_slice(&value);
+        // a tombstone is stored over the budget
+        put_unless_older(
+            &mut storage_access,
+            3,
+            Entry::tombstone(Duration::from_secs(60)),
+        )
+        .unwrap();
+        assert_eq!(budget.used(), 3 * 8 + 20);
     }
 
-    let response_length = response_payload.len() as u32 + 5;
-    let response = [
-        vec![0],
-        response_length.to_be_bytes().to_vec(),
-        response_payload,
-    ]
-    .concat();
+    #[tokio::test]
+    async fn range_taking() {
+        let backup = BackupHandle::new(Box::new(MemoryEngine::new()), None);
+        let expired = Entry {
+            expires_at: Some(1),
+            ..Entry::new(vec![7])
+        };
+        backup
+            .insert_many(vec![
+                (1, entry(1)),
+                (5, entry(5)),
+                (7, expired),
+                (9, entry(9)),
+            ])
+            .await
+            .unwrap();
+
+        // the expired value is removed but not returned
+        let taken = backup.take_range(2..=9).await.unwrap();
+        assert_eq!(taken, vec![(5, entry(5)), (9, entry(9))]);
 
-    connection.send_message(&response).await;
+        assert_eq!(
+            backup.take_range(0..=u64::MAX).await.unwrap(),
+            vec![(1, entry(1))]
+        );
+        assert!(backup.take_range(0..=u64::MAX).await.unwrap().is_empty());
+    }
 }
@@ -1,84 +1,135 @@
+use crate::blocks::backup::BackupHandle;
 use crate::blocks::fault_tolerance::send_node_down;
-use crate::helpers::communication::Connection;
+use crate::blocks::leader::LeaderHandle;
+use crate::helpers::communication::{
+    Connection, ConnectionError, ConnectionPool, ErrorCode, ExportSender, ExportedStorage, Message,
+};
+use crate::helpers::storage::{MemoryBudget, StorageError};
 use crate::PeerNode;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{mpsc, Mutex};
 
+/// Number of backup keys whose pairs are copied for an export under a single lock of the backup storage.
+const EXPORT_BATCH_KEYS: usize = 1024;
+
 /// Handles incoming requests from clients wanting to perform operations in the datastore.
 pub async fn client_block(
-    mut incoming_connection_stream: mpsc::UnboundedReceiver<(Connection, Vec<u8>)>,
+    mut incoming_connection_stream: mpsc::UnboundedReceiver<(Connection, Message)>,
     node_list: Arc<Mutex<Vec<PeerNode>>>,
+    connection_pool: Arc<ConnectionPool>,
+    leader: LeaderHandle,
+    backup: BackupHandle,
+    budget: Arc<MemoryBudget>,
 ) {
-    while let Some((client_connection, message)) = incoming_connection_stream.recv().await {
+    while let Some((mut client_connection, message)) = incoming_connection_stream.recv().await {
         let node_list_clone = Arc::clone(&node_list);
+        let connection_pool_clone = Arc::clone(&connection_pool);
+        let leader_clone = leader.clone();
+        let backup_clone = backup.clone();
+        let budget_clone = Arc::clone(&budget);
 
         tokio::task::spawn(async move {
-            match message.first() {
-                Some(200) => {
-                    forward_read_request(client_connection, message, node_list_clone).await
+            match message {
+                Message::ClientRead { key, at } => {
+                    forward_single_response_request(
+                        client_connection,
+                        key,
+                        Message::LeaderRead { key, at },
+                        node_list_clone,
+                        connection_pool_clone,
+                    )
+                    .await
+                }
+                Message::ClientDelete { key } => {
+                    forward_single_response_request(
+                        client_connection,
+                        key,
+                        Message::LeaderDelete { key },
+                        node_list_clone,
+                        connection_pool_clone,
+                    )
+                    .await
+                }
+                Message::ClientWrite { key, ttl } => {
+                    forward_write_request(
+                        client_connection,
+                        key,
+                        ttl,
+                        node_list_clone,
+                        connection_pool_clone,
+                    )
+                    .await
+                }
+                Message::UsageRequest => {
+                    handle_usage_request(
+                        client_connection,
+                        &leader_clone,
+                        &backup_clone,
+                        &budget_clone,
+                    )
+                    .await
+                }
+                Message::ExportRequest { include_backups } => {
+                    handle_export_request(
+                        client_connection,
+                        include_backups,
+                        &leader_clone,
+                        &backup_clone,
+                    )
+                    .await
                 }
-                Some(202) => {
-                    forward_write_request(client_connection, message, node_list_clone).await
+                _ => {
+                    client_connection
+                        .send_error(ErrorCode::BadRequest, "unexpected message for client block")
+                        .await;
                 }
-                _ => {}
             };
         });
     }
 }
 
-/// Handles an incoming read request from a client
-/// by forwarding the conversation between the client and a leader node.
-async fn forward_read_request(
+/// Handles an incoming read or delete request from a client by forwarding
+/// the given request to the leader node of the key and its single response back to the client.
+/// The response is forwarded as such, so a read response keeps its found flag.
+async fn forward_single_response_request(
     mut client_connection: Connection,
-    message: Vec<u8>,
+    key: u64,
+    forwarded_message: Message,
     node_list_arc: Arc<Mutex<Vec<PeerNode>>>,
+    connection_pool: Arc<ConnectionPool>,
 ) {
-    // at this point, the first byte of connection.message is `200`
-    if message.len() != 13 {
-        println!("received invalid request from a client, dropping");
-        return;
-    }
-    let key = u64::from_be_bytes(message[5..13].try_into().unwrap());
-
-    let forwarded_message = [vec![1, 0, 0, 0, 13], key.to_be_bytes().to_vec()].concat();
-
     // forward the request to the leader node
-    let node_list;
-    {
-        node_list = node_list_arc.lock().await.clone();
-    }
-
-    let leader_node = leader_node_for_key(&node_list, key);
-
     let mut leader_connection =
-        match Connection::new(leader_node.ip_address, &forwarded_message).await {
+        match connect_to_leader(key, &forwarded_message, &node_list_arc, &connection_pool).await {
             Ok(connection) => connection,
-            Err(_) => {
-                // leader node was down, handle fault and retry
-                send_node_down(leader_node.id, &node_list).await;
-
-                let node_list;
-                {
-                    node_list = node_list_arc.lock().await.clone();
-                }
-                let leader_node = leader_node_for_key(&node_list, key);
-
-                match Connection::new(leader_node.ip_address, &forwarded_message).await {
-                    Ok(connection) => connection,
-                    Err(_) => {
-                        println!("found two crashed nodes during forwarding, dropping");
-                        return;
-                    }
-                }
+            Err(error) => {
+                let (code, message) = leader_error_response(&error);
+                client_connection.send_error(code, message).await;
+                return;
             }
         };
 
     println!(
-        "forwarding read request {} -> {}",
-        client_connection.address, leader_connection.address
+        "forwarding request type {} {} -> {}",
+        forwarded_message.message_type(),
+        client_connection.address,
+        leader_connection.address
     );
 
-    let leader_response = leader_connection.read_message().await;
+    let leader_response = match leader_connection.read_message().await {
+        Ok(response) => response,
+        Err(error) => {
+            println!(
+                "received invalid response from leader ({}), dropping",
+                error
+            );
+            client_connection
+                .send_error(ErrorCode::Internal, "leader sent an invalid response")
+                .await;
+            return;
+        }
+    };
 
     // forward response to the client
     client_connection.send_message(&leader_response).await;
@@ -88,45 +139,21 @@ async fn forward_read_request(
 /// by forwarding the conversation between the client and a leader node.
 async fn forward_write_request(
     mut client_connection: Connection,
-    message: Vec<u8>,
+    key: u64,
+    ttl: Option<Duration>,
     node_list_arc: Arc<Mutex<Vec<PeerNode>>>,
+    connection_pool: Arc<ConnectionPool>,
 ) {
-    // at this point, the first byte of message is `202`
-    if message.len() != 13 {
-        println!("received invalid type=202 request from a client, dropping");
-        return;
-    }
-    let key = u64::from_be_bytes(message[5..13].try_into().unwrap());
-    let forwarded_message = [vec![2, 0, 0, 0, 13], key.to_be_bytes().to_vec()].concat();
+    let forwarded_message = Message::LeaderWrite { key, ttl };
 
     // forward request to the leader node
-    let node_list;
-    {
-        node_list = node_list_arc.lock().await.clone();
-    }
-
-    let leader_node = leader_node_for_key(&node_list, key);
-
     let mut leader_connection =
-        match Connection::new(leader_node.ip_address, &forwarded_message).await {
+        match connect_to_leader(key, &forwarded_message, &node_list_arc, &connection_pool).await {
             Ok(connection) => connection,
-            Err(_) => {
-                // leader node was down, handle fault and retry
-                send_node_down(leader_node.id, &node_list).await;
-
-                let node_list;
-                {
-                    node_list = node_list_arc.lock().await.clone();
-                }
-                let leader_node = leader_node_for_key(&node_list, key);
-
-                match Connection::new(leader_node.ip_address, &forwarded_message).await {
-                    Ok(connection) => connection,
-                    Err(_) => {
-                        println!("found two crashed nodes during forwarding, dropping");
-                        return;
-                    }
-                }
+            Err(error) => {
+                let (code, message) = leader_error_response(&error);
+                client_connection.send_error(code, message).await;
+                return;
             }
         };
 
@@ -135,21 +162,237 @@ async fn forward_write_request(
         client_connection.address, leader_connection.address
     );
 
-    // wait for and forward the write permission
-    let permission_msg = leader_connection.read_message().await;
+    // wait for and forward the write permission, including the found flag of the current value
+    let permission_msg = match leader_connection.read_message().await {
+        Ok(message) => message,
+        Err(error) => {
+            println!("received invalid write permission ({}), dropping", error);
+            client_connection
+                .send_error(
+                    ErrorCode::Internal,
+                    "leader sent an invalid write permission",
+                )
+                .await;
+            return;
+        }
+    };
     client_connection.send_message(&permission_msg).await;
+    if let Message::Error { .. } = permission_msg {
+        println!("leader refused the write request, ending forwarding");
+        return;
+    }
 
-    // wait for and forward the write command message
-    let write_command_message = client_connection.read_message().await;
+    // wait for and forward the write command message, a new value or a compare-and-swap
+    let write_command_message = match client_connection.read_message().await {
+        Ok(message @ (Message::Response(_) | Message::CompareAndSwap { .. })) => message,
+        Ok(_) => {
+            println!("received unexpected write command, dropping");
+            client_connection
+                .send_error(ErrorCode::BadRequest, "expected a write command")
+                .await;
+            return;
+        }
+        Err(error) => {
+            println!("received invalid write command ({}), dropping", error);
+            client_connection
+                .send_error(ErrorCode::BadRequest, &error.to_string())
+                .await;
+            return;
+        }
+    };
     leader_connection.send_message(&write_command_message).await;
 
-    // wait for and forward the acknowledgement
-    let ack_message = leader_connection.read_message().await;
+    // wait for and forward the acknowledgement, or the conflict error of a compare-and-swap
+    let ack_message = match leader_connection.read_message().await {
+        Ok(message) => message,
+        Err(error) => {
+            println!(
+                "received invalid write acknowledgement ({}), dropping",
+                error
+            );
+            client_connection
+                .send_error(
+                    ErrorCode::Internal,
+                    "leader sent an invalid acknowledgement",
+                )
+                .await;
+            return;
+        }
+    };
     client_connection.send_message(&ack_message).await;
 
+    if let Message::Error {
+        code: ErrorCode::Conflict,
+        ..
+    } = ack_message
+    {
+        println!("compare-and-swap conflicted, ending forwarding");
+        return;
+    }
     println!("write request forwarding ended");
 }
 
+/// Handles an incoming request from a client asking the memory usage of this node.
+async fn handle_usage_request(
+    mut client_connection: Connection,
+    leader: &LeaderHandle,
+    backup: &BackupHandle,
+    budget: &MemoryBudget,
+) {
+    let leader_stats = leader.stats().await;
+    let backup_stats = backup.stats().await;
+    println!(
+        "responding memory usage {} of {:?} bytes to {}",
+        budget.used(),
+        budget.limit(),
+        client_connection.address
+    );
+
+    let usage = [
+        budget.used(),
+        budget.limit().unwrap_or(0),
+        leader_stats.keys,
+        leader_stats.value_bytes,
+        backup_stats.keys,
+        backup_stats.value_bytes,
+    ];
+    let response: Vec<u8> = usage
+        .into_iter()
+        .flat_map(|number| (number as u64).to_be_bytes())
+        .collect();
+    client_connection
+        .send_message(&Message::Response(response))
+        .await;
+}
+
+/// Handles an incoming request from a client asking a copy of the key-value pairs
+/// in the leader storage of this node, and in its backup storage if asked.
+async fn handle_export_request(
+    mut client_connection: Connection,
+    include_backups: bool,
+    leader: &LeaderHandle,
+    backup: &BackupHandle,
+) {
+    let address = client_connection.address;
+    match send_export(&mut client_connection, include_backups, leader, backup).await {
+        Ok((leader_count, backup_count)) => {
+            println!(
+                "exported {} leader and {} backup kv-pairs to {}",
+                leader_count, backup_count, address
+            );
+        }
+        Err(ExportError::Storage(error)) => {
+            println!("failed to copy kv-pairs for export ({})", error);
+            client_connection
+                .send_error(error.error_code(), &error.to_string())
+                .await;
+        }
+        Err(ExportError::Connection(error)) => {
+            println!("failed to export kv-pairs ({}), dropping", error);
+        }
+    }
+}
+
+/// The error returned when the storages cannot be exported.
+enum ExportError {
+    Storage(StorageError),
+    Connection(ConnectionError),
+}
+
+/// Sends the key-value pairs of the leader storage, and of the backup storage if asked,
+/// as the response on the given connection.
+/// The pairs are copied one leader shard and one batch of backup keys at a time,
+/// so that neither storage is locked or held in memory as a whole.
+/// Returns the numbers of the sent leader and backup pairs.
+async fn send_export(
+    connection: &mut Connection,
+    include_backups: bool,
+    leader: &LeaderHandle,
+    backup: &BackupHandle,
+) -> Result<(usize, usize), ExportError> {
+    let mut counts = (0, 0);
+    let mut sender = ExportSender::new(connection);
+    for index in 0..leader.shard_count() {
+        let kv_pairs = leader
+            .copy_shard_range(index, 0..=u64::MAX)
+            .await
+            .map_err(ExportError::Storage)?;
+        counts.0 += kv_pairs.len();
+        sender
+            .send(ExportedStorage::Leader, &kv_pairs)
+            .await
+            .map_err(ExportError::Connection)?;
+    }
+
+    if include_backups {
+        for keys in backup.keys().await.chunks(EXPORT_BATCH_KEYS) {
+            let kv_pairs = backup
+                .copy_range(keys[0]..=keys[keys.len() - 1])
+                .await
+                .map_err(ExportError::Storage)?;
+            counts.1 += kv_pairs.len();
+            sender
+                .send(ExportedStorage::Backup, &kv_pairs)
+                .await
+                .map_err(ExportError::Connection)?;
+        }
+    }
+
+    sender.finish().await.map_err(ExportError::Connection)?;
+    Ok(counts)
+}
+
+/// Opens a connection to the leader of the given key and sends the given message to it.
+/// If the leader is down, handles the fault and retries once with the new leader.
+/// Fails if the new leader is down as well, or if the protocol version of the leader cannot carry the message.
+async fn connect_to_leader(
+    key: u64,
+    message: &Message,
+    node_list_arc: &Arc<Mutex<Vec<PeerNode>>>,
+    connection_pool: &ConnectionPool,
+) -> Result<Connection, ConnectionError> {
+    let node_list;
+    {
+        node_list = node_list_arc.lock().await.clone();
+    }
+
+    let leader_node = leader_node_for_key(&node_list, key);
+
+    match connection_pool.open(&leader_node, message).await {
+        Ok(connection) => Ok(connection),
+        // the leader is up but speaks an older protocol version
+        Err(error @ ConnectionError::IncompatibleVersion(_)) => Err(error),
+        Err(_) => {
+            // leader node was down, handle fault and retry
+            send_node_down(connection_pool, leader_node.id, &node_list).await;
+
+            let node_list;
+            {
+                node_list = node_list_arc.lock().await.clone();
+            }
+            let leader_node = leader_node_for_key(&node_list, key);
+
+            let result = connection_pool.open(&leader_node, message).await;
+            if let Err(error) = &result {
+                println!("failed to forward to the new leader ({}), dropping", error);
+            }
+            result
+        }
+    }
+}
+
+/// Returns the code and the message of the error response telling the client
+/// that the request could not be forwarded to the leader of the key for the given reason.
+fn leader_error_response(error: &ConnectionError) -> (ErrorCode, &'static str) {
+    match error {
+        ConnectionError::IncompatibleVersion(_) => (
+            ErrorCode::BadRequest,
+            "leader of the key does not support the request",
+        ),
+        _ => (ErrorCode::Unavailable, "leader of the key is unreachable"),
+    }
+}
+
 /// From the given node list, returns the node that is the leader for the given key.
 fn leader_node_for_key(node_list: &[PeerNode], key: u64) -> PeerNode {
     // node list always contains at least this node itself
@@ -180,15 +423,18 @@ mod test {
         let node_list = vec![
             PeerNode {
                 id: 5,
-                ip_address: "192.168.0.5".to_string(),
+                ip_address: "192.168.0.5".parse().unwrap(),
+                port: 52525,
             },
             PeerNode {
                 id: 12,
-                ip_address: "192.168.0.12".to_string(),
+                ip_address: "192.168.0.12".parse().unwrap(),
+                port: 52525,
             },
             PeerNode {
                 id: 25,
-                ip_address: "192.168.0.25".to_string(),
+                ip_address: "192.168.0.25".parse().unwrap(),
+                port: 52525,
             },
         ];
 
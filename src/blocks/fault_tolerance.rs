@@ -1,34 +1,81 @@
-use crate::helpers::communication::Connection;
+use super::backup::BackupHandle;
+use super::leader::LeaderHandle;
+use crate::helpers::communication::{
+    Connection, ConnectionError, ConnectionPool, ErrorCode, KvPairsSender, Message,
+};
 use crate::helpers::neighbors::find_neighbors_nonwrapping;
-use crate::helpers::neighbors::find_neighbors_wrapping;
+use crate::helpers::neighbors::{find_neighbors_wrapping, leader_key_range};
+use crate::helpers::storage::StorageError;
 use crate::PeerNode;
+use std::fmt;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{mpsc, Mutex};
 
+/// Number of times a new backup replica is sent before giving up.
+const REPLICA_ATTEMPTS: u32 = 3;
+
+/// Time to wait before sending a failed backup replica again.
+const REPLICA_RETRY_DELAY: Duration = Duration::from_secs(1);
+
 /// Handles incoming requests related to nodes crashing.
 pub async fn fault_tolerance_block(
-    mut incoming_connection_stream: mpsc::UnboundedReceiver<(Connection, Vec<u8>)>,
+    mut incoming_connection_stream: mpsc::UnboundedReceiver<(Connection, Message)>,
     node_list: Arc<Mutex<Vec<PeerNode>>>,
     this_node_id: u64,
+    connection_pool: Arc<ConnectionPool>,
+    leader: LeaderHandle,
+    backup: BackupHandle,
 ) {
-    while let Some((connection, message)) = incoming_connection_stream.recv().await {
+    while let Some((mut connection, message)) = incoming_connection_stream.recv().await {
         let node_list_clone = Arc::clone(&node_list);
+        let connection_pool_clone = Arc::clone(&connection_pool);
+        let leader_clone = leader.clone();
+        let backup_clone = backup.clone();
 
         tokio::task::spawn(async move {
-            match message.first() {
-                Some(30) => handle_neighbor_down(connection, message, node_list_clone).await,
-                Some(31) => {
-                    handle_peer_deannouncement(connection, message, node_list_clone, this_node_id)
-                        .await
+            match message {
+                Message::NeighborDown { node_id } => {
+                    handle_neighbor_down(
+                        connection,
+                        node_id,
+                        node_list_clone,
+                        connection_pool_clone,
+                        leader_clone,
+                        backup_clone,
+                    )
+                    .await
+                }
+                Message::PeerDown { node_id } => {
+                    handle_peer_deannouncement(
+                        connection,
+                        node_id,
+                        node_list_clone,
+                        this_node_id,
+                        connection_pool_clone,
+                        leader_clone,
+                    )
+                    .await
+                }
+                _ => {
+                    connection
+                        .send_error(
+                            ErrorCode::BadRequest,
+                            "unexpected message for fault tolerance block",
+                        )
+                        .await;
                 }
-                _ => {}
             };
         });
     }
 }
 
 /// Send a message to right recipient informing that a node with the given ID has crashed.
-pub async fn send_node_down(crashed_node_id: u64, node_list: &Vec<PeerNode>) {
+pub async fn send_node_down(
+    connection_pool: &ConnectionPool,
+    crashed_node_id: u64,
+    node_list: &[PeerNode],
+) {
     // the message must be sent to the greater neighbor of the crashed node
     // if the crashed node was greatest in the ring, send the message to its smaller neighbor
 
@@ -44,19 +91,24 @@ pub async fn send_node_down(crashed_node_id: u64, node_list: &Vec<PeerNode>) {
         crashed_node_id, recipient.ip_address
     );
 
-    let request = [
-        vec![30, 0, 0, 0, 13],
-        crashed_node_id.to_be_bytes().to_vec(),
-    ]
-    .concat();
+    let request = Message::NeighborDown {
+        node_id: crashed_node_id,
+    };
 
-    let mut connection = Connection::new(recipient.ip_address, &request)
-        .await
-        .unwrap();
+    let mut connection = match connection_pool.open(&recipient, &request).await {
+        Ok(connection) => connection,
+        Err(error) => {
+            println!(
+                "failed to inform about down peer ID={} ({}), dropping",
+                crashed_node_id, error
+            );
+            return;
+        }
+    };
 
     let response = connection.read_message().await;
 
-    if response != [0, 0, 0, 0, 7, 111, 107] {
+    if !matches!(response, Ok(message) if message.is_ok()) {
         println!("received invalid response to type=30 message, dropping");
     }
 }
@@ -64,21 +116,14 @@ pub async fn send_node_down(crashed_node_id: u64, node_list: &Vec<PeerNode>) {
 /// Handles an incoming request informing that the neighbor of this node has crashed.
 async fn handle_neighbor_down(
     mut connection: Connection,
-    message: Vec<u8>,
+    down_peer_id: u64,
     node_list_arc: Arc<Mutex<Vec<PeerNode>>>,
+    connection_pool: Arc<ConnectionPool>,
+    leader: LeaderHandle,
+    backup: BackupHandle,
 ) {
-    // at this point the first byte of message is 30
-    if message.len() != 13 {
-        println!(
-            "received invalid type=30 message from {}, dropping",
-            connection.address
-        );
-        return;
-    }
-
     // this crashed peer is expected to be the smaller neighbor
     // or greater neighbor if it was the greatest node in the ring
-    let down_peer_id = u64::from_be_bytes(message[5..13].try_into().unwrap());
 
     println!(
         "handling down peer ID={} detected by {}",
@@ -90,47 +135,47 @@ async fn handle_neighbor_down(
     {
         node_list = node_list_arc.lock().await.clone();
     }
-    deannounce_down_peer(down_peer_id, &node_list).await;
+    deannounce_down_peer(&connection_pool, down_peer_id, &node_list).await;
 
     // move values from backup storage to leader storage
-    transfer_from_backup_to_leader(down_peer_id, &node_list).await;
+    let transferred =
+        transfer_from_backup_to_leader(&backup, &leader, down_peer_id, &node_list).await;
 
     // create new backup replicas
-    let new_backup_node;
     // if the crashed node was the greatest in the ring
-    if find_neighbors_nonwrapping(down_peer_id, &node_list)
+    let new_backup_node = if find_neighbors_nonwrapping(down_peer_id, &node_list)
         .1
         .is_none()
     {
         // new backup node for this node is the smallest in the ring
-        new_backup_node = node_list.iter().min_by_key(|node| node.id).unwrap().clone();
+        node_list.iter().min_by_key(|node| node.id).unwrap().clone()
     } else {
         // the crashed node was the smaller neighbor of this node
         // new backup node for this node is the smaller neighbor of the crashed node (wrap if necessary)
-        new_backup_node = find_neighbors_wrapping(down_peer_id, &node_list)[0]
+        find_neighbors_wrapping(down_peer_id, &node_list)[0]
             .clone()
-            .unwrap();
-    }
-    create_new_backup_replica(new_backup_node).await;
+            .unwrap()
+    };
+    let replicated = create_new_backup_replica(&connection_pool, &leader, new_backup_node).await;
 
-    connection.send_message(&[0, 0, 0, 0, 7, 111, 107]).await;
+    if transferred && replicated {
+        connection.send_message(&Message::ok()).await;
+    } else {
+        connection
+            .send_error(ErrorCode::Internal, "failed to take over the crashed node")
+            .await;
+    }
 }
 
 /// Handles an incoming request informing that a node has leaved the ring.
 async fn handle_peer_deannouncement(
     mut connection: Connection,
-    message: Vec<u8>,
+    down_peer_id: u64,
     node_list_arc: Arc<Mutex<Vec<PeerNode>>>,
     this_node_id: u64,
+    connection_pool: Arc<ConnectionPool>,
+    leader: LeaderHandle,
 ) {
-    // at this point the first byte of message is 31
-    if message.len() != 13 {
-        println!("received invalid type=31 message, dropping");
-        return;
-    }
-
-    let down_peer_id = u64::from_be_bytes(message[5..13].try_into().unwrap());
-
     println!(
         "removing down peer ID={} detected by {}",
         down_peer_id, connection.address
@@ -145,17 +190,20 @@ async fn handle_peer_deannouncement(
     let [smaller_neighbor, greater_neighbor] = find_neighbors_wrapping(down_peer_id, &node_list);
     let greatest_in_ring = node_list.iter().max_by_key(|node| node.id).unwrap();
 
+    let mut replicated = true;
     if let Some(smaller_neighbor) = smaller_neighbor {
         if let Some(greater_neighbor) = greater_neighbor {
             // if the crashed node was our greater wrapping neighbor and not the greatest in the ring
             if smaller_neighbor.id == this_node_id && greatest_in_ring.id != down_peer_id {
                 // replicate the leader pairs of this node to the greater neighbor of the crashed node for backup
-                create_new_backup_replica(greater_neighbor).await;
+                replicated =
+                    create_new_backup_replica(&connection_pool, &leader, greater_neighbor).await;
             }
             // if the crashed node was our smaller wrapping neighbor and the greatest in the ring
             else if greater_neighbor.id == this_node_id && greatest_in_ring.id == down_peer_id {
                 // replicate the leader pairs of this node to the smaller neighbor of the crashed node for backup
-                create_new_backup_replica(smaller_neighbor).await;
+                replicated =
+                    create_new_backup_replica(&connection_pool, &leader, smaller_neighbor).await;
             }
         }
     }
@@ -165,13 +213,26 @@ async fn handle_peer_deannouncement(
         let mut node_list = node_list_arc.lock().await;
         node_list.retain(|node| node.id != down_peer_id);
     }
+    connection_pool.forget(down_peer_id);
 
-    connection.send_message(&[0, 0, 0, 0, 7, 111, 107]).await;
+    if replicated {
+        connection.send_message(&Message::ok()).await;
+    } else {
+        connection
+            .send_error(ErrorCode::Internal, "failed to create a new backup replica")
+            .await;
+    }
 }
 
 /// Sends a message to every node in the system informing that a node with the given ID has left the ring.
-async fn deannounce_down_peer(down_peer_id: u64, node_list: &Vec<PeerNode>) {
-    let message = [vec![31, 0, 0, 0, 13], down_peer_id.to_be_bytes().to_vec()].concat();
+async fn deannounce_down_peer(
+    connection_pool: &Arc<ConnectionPool>,
+    down_peer_id: u64,
+    node_list: &[PeerNode],
+) {
+    let message = Message::PeerDown {
+        node_id: down_peer_id,
+    };
 
     let mut handles = Vec::new();
 
@@ -180,15 +241,26 @@ async fn deannounce_down_peer(down_peer_id: u64, node_list: &Vec<PeerNode>) {
             continue;
         }
 
-        let peer_ip_address_clone = peer.ip_address.clone();
+        let peer_clone = peer.clone();
         let message_clone = message.clone();
+        let connection_pool_clone = Arc::clone(connection_pool);
 
         let handle = tokio::task::spawn(async move {
-            let mut connection = Connection::new(peer_ip_address_clone, &message_clone)
+            let mut connection = match connection_pool_clone
+                .open(&peer_clone, &message_clone)
                 .await
-                .unwrap();
+            {
+                Ok(connection) => connection,
+                Err(error) => {
+                    println!(
+                        "failed to deannounce peer ID={} ({}), skipping",
+                        down_peer_id, error
+                    );
+                    return;
+                }
+            };
 
-            if connection.read_message().await != [0, 0, 0, 0, 7, 111, 107] {
+            if !matches!(connection.read_message().await, Ok(message) if message.is_ok()) {
                 println!(
                     "failed to deannounce peer ID={} to {}",
                     down_peer_id, connection.address
@@ -207,81 +279,170 @@ async fn deannounce_down_peer(down_peer_id: u64, node_list: &Vec<PeerNode>) {
 /// Moves a range of key-value pairs internally from the backup to the primary storage of this node
 /// as a fault tolerance action after a node with the given node crashed.
 /// Node list should still contain the crashed node.
-async fn transfer_from_backup_to_leader(down_peer_id: u64, node_list: &Vec<PeerNode>) {
-    // find the neighbors of the crashed node
-    let (smaller_neighbor, greater_neighbor) = find_neighbors_nonwrapping(down_peer_id, node_list);
-
-    // find the inclusive bounds of the keys that will be transfered
-    let transfer_key_lower_bound = match smaller_neighbor {
-        Some(node) => node.id + 1,
-        None => 0,
-    };
-    let transfer_key_upper_bound = if greater_neighbor.is_none() {
-        u64::MAX
-    } else {
-        down_peer_id
-    };
+/// Returns `true` if the key-value pairs were moved successfully, `false` otherwise.
+async fn transfer_from_backup_to_leader(
+    backup: &BackupHandle,
+    leader: &LeaderHandle,
+    down_peer_id: u64,
+    node_list: &[PeerNode],
+) -> bool {
+    // the keys that the crashed node was the leader of
+    let key_range = leader_key_range(down_peer_id, node_list);
 
     println!(
-        "transfering keys {}..={} from backup to leader storage",
-        transfer_key_lower_bound, transfer_key_upper_bound
+        "transfering keys {:?} from backup to leader storage",
+        key_range
     );
 
-    // request backup key-value pairs in the range
-    let backup_request = [
-        vec![32, 0, 0, 0, 21],
-        transfer_key_lower_bound.to_be_bytes().to_vec(),
-        transfer_key_upper_bound.to_be_bytes().to_vec(),
-    ]
-    .concat();
-
-    let mut backup_connection = Connection::new("127.0.0.1".to_string(), &backup_request)
-        .await
-        .unwrap();
-
-    let backup_response = backup_connection.read_message().await;
+    let kv_pairs = match backup.take_range(key_range).await {
+        Ok(kv_pairs) => kv_pairs,
+        Err(error) => {
+            println!(
+                "failed to take keys from backup storage ({}), dropping",
+                error
+            );
+            return false;
+        }
+    };
 
-    if backup_response.len() < 5 || backup_response[0] != 0 {
-        println!("received invalid fault tolerance transfer response from backup, dropping");
-        return;
+    if let Err(error) = leader.insert_many(kv_pairs).await {
+        println!(
+            "failed to insert keys to leader storage ({}), dropping",
+            error
+        );
+        return false;
     }
 
-    // send request to add leader pairs
-    let leader_request = [vec![33], backup_response[1..].to_vec()].concat();
-
-    let mut leader_connection = Connection::new("127.0.0.1".to_string(), &leader_request)
-        .await
-        .unwrap();
+    // the versions that the crashed node gave out are not reused
+    leader
+        .raise_version_high_water(backup.version_high_water().await)
+        .await;
 
-    let leader_response = leader_connection.read_message().await;
+    let stats = leader.stats().await;
+    println!(
+        "leader storage now has {} keys ({} bytes)",
+        stats.keys, stats.value_bytes
+    );
 
-    if leader_response != [0, 0, 0, 0, 7, 111, 107] {
-        println!("received invalid fault tolerance transfer response from leader, dropping");
-        return;
-    }
+    true
 }
 
 /// Send all of the key-value pairs from the primary storage of this node to the given peer for backup.
-async fn create_new_backup_replica(new_backup_node: PeerNode) {
-    // request the leader key-value pairs from this node itself
-    let leader_request = [12, 0, 0, 0, 5];
-    let mut leader_connection = Connection::new("127.0.0.1".to_string(), &leader_request)
-        .await
-        .unwrap();
+/// A failed transfer may leave part of the replica in the backup storage of the peer,
+/// so the whole replica is sent again, up to `REPLICA_ATTEMPTS` times in total.
+/// Returns `true` if the new backup replica was created successfully, `false` otherwise.
+pub async fn create_new_backup_replica(
+    connection_pool: &ConnectionPool,
+    leader: &LeaderHandle,
+    new_backup_node: PeerNode,
+) -> bool {
+    for attempt in 1..=REPLICA_ATTEMPTS {
+        if attempt > 1 {
+            tokio::time::sleep(REPLICA_RETRY_DELAY).await;
+        }
+
+        if send_backup_replica(connection_pool, leader, &new_backup_node).await {
+            return true;
+        }
+
+        println!(
+            "attempt {} of {} to create a new backup replica to {} failed",
+            attempt, REPLICA_ATTEMPTS, new_backup_node.ip_address
+        );
+    }
 
-    let leader_response = leader_connection.read_message().await;
+    false
+}
 
+/// Sends the backup replica to the given peer once.
+/// Returns `true` if the peer stored all of it, `false` otherwise.
+async fn send_backup_replica(
+    connection_pool: &ConnectionPool,
+    leader: &LeaderHandle,
+    new_backup_node: &PeerNode,
+) -> bool {
     // send the leader pairs of this node to the new backup node
-    let backup_request = [vec![21], leader_response[1..].to_vec()].concat();
-    let mut backup_connection = Connection::new(new_backup_node.ip_address, &backup_request)
-        .await
-        .unwrap();
+    let mut backup_connection =
+        match send_leader_kv_pairs(connection_pool, leader, new_backup_node).await {
+            Ok(connection) => connection,
+            Err(error) => {
+                println!(
+                    "failed to create a new backup replica to {} ({})",
+                    new_backup_node.ip_address, error
+                );
+                return false;
+            }
+        };
     let backup_response = backup_connection.read_message().await;
 
-    if backup_response != [0, 0, 0, 0, 7, 111, 107] {
+    if !matches!(backup_response, Ok(message) if message.is_ok()) {
         println!(
-            "failed to create a new backup replica to {}, skipping",
+            "failed to create a new backup replica to {}",
             backup_connection.address
         );
+        return false;
+    }
+
+    true
+}
+
+/// Sends all of the key-value pairs of the primary storage of this node to the given node for backup.
+/// The pairs are copied and sent in chunks following the request one shard at a time if the node supports it,
+/// otherwise they are all sent in the request.
+/// Returns the connection on which the node responds.
+async fn send_leader_kv_pairs(
+    connection_pool: &ConnectionPool,
+    leader: &LeaderHandle,
+    node: &PeerNode,
+) -> Result<Connection, ReplicaError> {
+    if !connection_pool.supports_chunked_transfers(node).await {
+        let kv_pairs = leader.snapshot().await?;
+        let request = Message::BackupArrayWrite { kv_pairs };
+        return Ok(connection_pool.open(node, &request).await?);
+    }
+
+    // the chunks follow the request
+    let request = Message::BackupArrayWrite {
+        kv_pairs: Vec::new(),
+    };
+    let mut connection = connection_pool.open(node, &request).await?;
+    let mut sender = KvPairsSender::new(&mut connection);
+
+    for index in 0..leader.shard_count() {
+        for (key, entry) in leader.copy_shard_range(index, 0..=u64::MAX).await? {
+            sender.push(key, entry);
+            sender.send_full_chunk().await?;
+        }
+    }
+    sender.finish(leader.version_high_water().await).await?;
+
+    Ok(connection)
+}
+
+/// The error returned when a backup replica cannot be sent.
+#[derive(Debug)]
+enum ReplicaError {
+    Storage(StorageError),
+    Connection(ConnectionError),
+}
+
+impl fmt::Display for ReplicaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplicaError::Storage(error) => write!(f, "failed to read leader storage ({})", error),
+            ReplicaError::Connection(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<StorageError> for ReplicaError {
+    fn from(error: StorageError) -> ReplicaError {
+        ReplicaError::Storage(error)
+    }
+}
+
+impl From<ConnectionError> for ReplicaError {
+    fn from(error: ConnectionError) -> ReplicaError {
+        ReplicaError::Connection(error)
     }
 }
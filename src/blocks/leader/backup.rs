@@ -1,65 +1,87 @@
 use crate::blocks::fault_tolerance::send_node_down;
-use crate::helpers::communication::Connection;
+use crate::helpers::communication::{ConnectionPool, Message};
+use crate::helpers::entry::Entry;
 use crate::{helpers::neighbors::find_neighbors_wrapping, PeerNode};
 
+/// The outcome of asking a neighbor to write a backup.
+enum BackupOutcome {
+    Written,
+    /// The neighbor responded with an error, for example because the value exceeds its memory budget.
+    Refused,
+    /// The neighbor could not be reached or did not respond.
+    Unreachable,
+}
+
 /// Pushes the update to both backup neighbors and handles possible crashed nodes.
+/// A neighbor that refuses the update is not considered crashed.
 /// Returns `true` if the update was propagated to both backups, `false` otherwise.
 pub async fn push_update_to_backups(
-    node_list: &Vec<PeerNode>,
+    connection_pool: &ConnectionPool,
+    node_list: &[PeerNode],
     this_node_id: u64,
     key: u64,
-    value: Vec<u8>,
+    entry: Entry,
 ) -> bool {
+    let mut propagated = true;
     for neighbor_side in 0..2 {
         for retry_counter in 0..2 {
             let neighbor = &find_neighbors_wrapping(this_node_id, node_list)[neighbor_side];
 
             if let Some(neighbor) = neighbor {
-                let success =
-                    send_backup_message(neighbor.ip_address.clone(), key, value.clone()).await;
-
-                if !success && retry_counter == 0 {
-                    // neighbor is down
-                    send_node_down(neighbor.id, node_list).await;
-                } else if !success {
-                    // two neighbors on the same side were down, failing
-                    return false;
-                } else {
-                    // backup pushed successfully
-                    break;
+                match send_backup_message(connection_pool, neighbor, key, entry.clone()).await {
+                    BackupOutcome::Unreachable if retry_counter == 0 => {
+                        // neighbor is down
+                        send_node_down(connection_pool, neighbor.id, node_list).await;
+                    }
+                    BackupOutcome::Unreachable => {
+                        // two neighbors on the same side were down, failing
+                        return false;
+                    }
+                    BackupOutcome::Refused => {
+                        propagated = false;
+                        break;
+                    }
+                    BackupOutcome::Written => {
+                        // backup pushed successfully
+                        break;
+                    }
                 }
             }
         }
     }
 
-    true
+    propagated
 }
 
 /// Sends a message to the given node asking it to write the given key-value pair to its backup storage.
-async fn send_backup_message(ip_address: String, key: u64, value: Vec<u8>) -> bool {
-    let request_length = value.len() as u32 + 13;
-    let request = [
-        vec![20],
-        request_length.to_be_bytes().to_vec(),
-        key.to_be_bytes().to_vec(),
-        value,
-    ]
-    .concat();
+async fn send_backup_message(
+    connection_pool: &ConnectionPool,
+    node: &PeerNode,
+    key: u64,
+    entry: Entry,
+) -> BackupOutcome {
+    let request = Message::BackupWrite { key, entry };
 
-    let mut connection = match Connection::new(ip_address, &request).await {
+    let mut connection = match connection_pool.open(node, &request).await {
         Ok(conn) => conn,
-        Err(_) => return false,
+        Err(_) => return BackupOutcome::Unreachable,
     };
 
-    let response = connection.read_message().await;
-
-    if response != [0, 0, 0, 0, 7, 111, 107] {
-        println!(
-            "failed to update backup for key={} at {}",
-            key, connection.address
-        );
-        false
-    } else {
-        true
+    match connection.read_message().await {
+        Ok(message) if message.is_ok() => BackupOutcome::Written,
+        Ok(Message::Error { code, message }) => {
+            println!(
+                "backup for key={} refused by {} ({:?}: {})",
+                key, connection.address, code, message
+            );
+            BackupOutcome::Refused
+        }
+        _ => {
+            println!(
+                "failed to update backup for key={} at {}",
+                key, connection.address
+            );
+            BackupOutcome::Unreachable
+        }
     }
 }
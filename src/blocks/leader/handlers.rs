@@ -1,233 +1,640 @@
 use super::backup::push_update_to_backups;
-use crate::helpers::communication::Connection;
+use crate::helpers::communication::{
+    Connection, ConnectionError, ConnectionPool, ErrorCode, KvPairsSender, Message, ReadPoint,
+    WriteCondition,
+};
+use crate::helpers::entry::{now_millis, Entry};
+use crate::helpers::history::{find_version, HistoryRetention};
+use crate::helpers::storage::{ShardedStorage, StorageEngine, StorageError, VersionedEngine};
+use crate::helpers::stored_value::StoredValue;
 use crate::PeerNode;
-use std::collections::HashMap;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
-/// Handles an incoming request asking the value for a key for which this node is the leader.
+/// Handles an incoming request asking the value for a key for which this node is the leader,
+/// either the current value or the value at the given point of the history of the key.
 pub async fn handle_read_request(
     mut connection: Connection,
-    message: Vec<u8>,
-    storage: Arc<Mutex<HashMap<u64, Vec<u8>>>>,
+    key: u64,
+    at: Option<ReadPoint>,
+    storage: Arc<ShardedStorage>,
 ) {
-    // at this point, first byte of connection.message is `1`
-    if message.len() != 13 {
-        println!("received invalid type=1 message, dropping");
-        return;
-    }
-    let key = u64::from_be_bytes(message[5..13].try_into().unwrap());
-
-    println!("reading value key={} for {}", key, connection.address);
+    println!(
+        "reading value key={} at={:?} for {}",
+        key, at, connection.address
+    );
 
-    let default_value = Vec::new();
-    let value;
-    {
-        let storage_access = storage.lock().await;
-        value = storage_access.get(&key).unwrap_or(&default_value).clone();
-    }
+    let (value, version) = match read_value(&storage, key, at).await {
+        Ok(versioned_value) => versioned_value,
+        Err(error) => {
+            println!("failed to read value key={} ({})", key, error);
+            connection
+                .send_error(error.error_code(), &storage_error_message(key, &error))
+                .await;
+            return;
+        }
+    };
 
-    let response_length = 5 + value.len() as u32;
-    let response = [vec![0], response_length.to_be_bytes().to_vec(), value].concat();
-    connection.send_message(&response).await;
+    connection
+        .send_message(&Message::value_response(value, version))
+        .await;
 }
 
 /// Handles an incoming request asking to write the value for a key for which this node is the leader.
+/// The new value expires after the given time to live, if any.
+/// A compare-and-swap write command is applied only if the current value matches its condition.
+/// The replaced value is kept in the history of the key as far as the given retention allows.
+/// The values evicted to make room for the new one are replaced in the backups
+/// with tombstones of the given lifetime.
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_write_request(
     mut connection: Connection,
-    first_message: Vec<u8>,
-    storage: Arc<Mutex<HashMap<u64, Vec<u8>>>>,
+    key: u64,
+    ttl: Option<Duration>,
+    tombstone_lifetime: Duration,
+    history: HistoryRetention,
+    storage: Arc<ShardedStorage>,
     this_node_id: u64,
     node_list_arc: Arc<Mutex<Vec<PeerNode>>>,
+    connection_pool: Arc<ConnectionPool>,
 ) {
-    // at this point the first byte of first_message is `2`
-    let total_length_header = u32::from_be_bytes(first_message[1..5].try_into().unwrap());
-    if first_message.len() != 13 || total_length_header != 13 {
-        println!("received invalid type=2 message, dropping");
-        return;
-    }
-    let key = u64::from_be_bytes(first_message[5..13].try_into().unwrap());
-
     println!(
         "granting write permission for key={} for {}",
         key, connection.address
     );
 
     // send write permission with the current value to the client
-    let default_value = Vec::new();
-    let old_value;
+    let (old_value, old_version) = match read_value(&storage, key, None).await {
+        Ok(versioned_value) => versioned_value,
+        Err(error) => {
+            println!("failed to read old value key={} ({})", key, error);
+            connection
+                .send_error(error.error_code(), &storage_error_message(key, &error))
+                .await;
+            return;
+        }
+    };
+
+    connection
+        .send_message(&Message::value_response(old_value, old_version))
+        .await;
+
+    // read the new value and the condition of a compare-and-swap
+    let (new_value, condition) = match connection.read_message().await {
+        Ok(Message::Response(new_value)) => (new_value, None),
+        Ok(Message::CompareAndSwap { condition, value }) => (value, Some(condition)),
+        _ => {
+            println!("received invalid write command message, dropping");
+            connection
+                .send_error(ErrorCode::BadRequest, "expected a write command")
+                .await;
+            return;
+        }
+    };
+
+    println!(
+        "writing new value={:?} for key={} ttl={:?} condition={:?}",
+        new_value, key, ttl, condition
+    );
+    let new_entry = Entry::with_ttl(new_value, ttl);
+
+    // write the new value to the storage first, so that a write over the budget is not backed up;
+    // every attempt starts from the new entry, as a write that had to evict values is run again
+    let (result, evicted) = storage
+        .write_within_budget(key, |shard| {
+            let mut entry = new_entry.clone();
+            let written = write_entry(shard, key, &mut entry, condition.as_ref(), &history)?;
+            Ok(written.then_some(entry))
+        })
+        .await;
+    // the write is acknowledged and backed up only once it is on disk
+    let result = match result {
+        Ok(Some(entry)) => storage.sync().await.map(|_| Some(entry)),
+        result => result,
+    };
+
+    // the evicted values are removed from the backups as well, even if the write failed after evicting them,
+    // so that they cannot return when a backup takes over the keys
+    let node_list;
     {
-        let storage_access = storage.lock().await;
-        old_value = storage_access.get(&key).unwrap_or(&default_value).clone();
-    }
-
-    let permission_msg_length = 5 + old_value.len() as u32;
-    let permission_message = [
-        vec![0],
-        permission_msg_length.to_be_bytes().to_vec(),
-        old_value,
-    ]
-    .concat();
-    connection.send_message(&permission_message).await;
-
-    // read the new value
-    let write_command_message = connection.read_message().await;
-    if write_command_message.len() < 5 || write_command_message[0] != 0 {
-        println!("received invalid write command message (header), dropping");
-        return;
+        node_list = node_list_arc.lock().await.clone();
     }
-    let new_value_length = u32::from_be_bytes(write_command_message[1..5].try_into().unwrap()) - 5;
-    if write_command_message.len() as u32 != new_value_length + 5 {
-        println!("received invalid write command message (length), dropping");
-        return;
+    for (evicted_key, version) in evicted {
+        println!("removing evicted key={} from backups", evicted_key);
+        let tombstone = Entry {
+            version,
+            ..Entry::tombstone(tombstone_lifetime)
+        };
+        push_update_to_backups(
+            &connection_pool,
+            &node_list,
+            this_node_id,
+            evicted_key,
+            tombstone,
+        )
+        .await;
     }
-    let new_value = &write_command_message[5..new_value_length as usize + 5];
 
-    println!("writing new value={:?} for key={}", new_value, key);
+    let entry = match result {
+        Ok(Some(entry)) => entry,
+        Ok(None) => {
+            println!("compare-and-swap of key={} conflicted, not writing", key);
+            connection
+                .send_error(
+                    ErrorCode::Conflict,
+                    &format!("value of key {} does not match the condition", key),
+                )
+                .await;
+            return;
+        }
+        Err(error) => {
+            println!("failed to write value key={} ({})", key, error);
+            connection
+                .send_error(error.error_code(), &storage_error_message(key, &error))
+                .await;
+            return;
+        }
+    };
 
     // push the update to backups
-    let node_list;
-    {
-        node_list = node_list_arc.lock().await.clone();
+    let version = entry.version;
+    push_update_to_backups(&connection_pool, &node_list, this_node_id, key, entry).await;
+
+    // respond acknowledgement with the new version
+    connection.send_message(&Message::write_ok(version)).await;
+}
+
+/// Handles an incoming request asking to delete the value of a key for which this node is the leader.
+/// The value is replaced with a tombstone of the given lifetime, which is pushed to the backups as well.
+/// The deleted value is kept in the history of the key as far as the given retention allows.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_delete_request(
+    mut connection: Connection,
+    key: u64,
+    tombstone_lifetime: Duration,
+    history: HistoryRetention,
+    storage: Arc<ShardedStorage>,
+    this_node_id: u64,
+    node_list_arc: Arc<Mutex<Vec<PeerNode>>>,
+    connection_pool: Arc<ConnectionPool>,
+) {
+    println!("deleting key={} for {}", key, connection.address);
+    let mut tombstone = Entry::tombstone(tombstone_lifetime);
+
+    // a tombstone is written even over the budget, since it frees the value
+    let result = {
+        let mut storage_access = storage.shard(key).lock().await;
+        current_entry(&*storage_access, key).and_then(|current| {
+            let version = storage_access.next_version();
+            supersede(&mut tombstone, current, version, &history, now_millis());
+            storage_access.put(key, StoredValue::new(tombstone.clone()))
+        })
+    };
+    let result = match result {
+        Ok(()) => storage.sync().await,
+        Err(error) => Err(error),
+    };
+    if let Err(error) = result {
+        println!("failed to delete key={} ({})", key, error);
+        connection
+            .send_error(error.error_code(), &storage_error_message(key, &error))
+            .await;
+        return;
     }
-    push_update_to_backups(&node_list, this_node_id, key, new_value.to_vec()).await;
 
-    // write the new value to the storage
+    // push the tombstone to backups
+    let node_list;
     {
-        let mut storage_access = storage.lock().await;
-        storage_access.insert(key, new_value.to_vec());
+        node_list = node_list_arc.lock().await.clone();
     }
+    push_update_to_backups(&connection_pool, &node_list, this_node_id, key, tombstone).await;
 
     // respond acknowledgement
-    connection.send_message(&[0, 0, 0, 0, 7, 111, 107]).await;
+    connection.send_message(&Message::ok()).await;
 }
 
 /// Handles an incoming request that asks this node to remove
 /// and respond a range of keys from the primary storage.
 pub async fn handle_transfer_request(
     mut connection: Connection,
-    message: Vec<u8>,
-    storage: Arc<Mutex<HashMap<u64, Vec<u8>>>>,
+    key_range: RangeInclusive<u64>,
+    storage: Arc<ShardedStorage>,
 ) {
-    // at this point, the first byte of message is 11
-    if u32::from_be_bytes(message[1..5].try_into().unwrap()) != 21 || message.len() != 21 {
-        println!("receiving invalid transfer request, dropping");
-        return;
-    }
-
-    let key_lower_bound = u64::from_be_bytes(message[5..13].try_into().unwrap());
-    let key_upper_bound = u64::from_be_bytes(message[13..21].try_into().unwrap());
-
-    let mut response_payload = Vec::new();
-
-    let keys_to_transfer: Vec<_>;
-    {
-        let storage_access = storage.lock().await;
-        keys_to_transfer = storage_access
-            .keys()
-            .filter(|&&k| key_lower_bound <= k && k <= key_upper_bound)
-            .cloned()
-            .collect();
-    }
-
     println!(
-        "transfering leader keys {:?} ({}..={}) to {}",
-        keys_to_transfer, key_lower_bound, key_upper_bound, connection.address
+        "transfering leader keys {:?} to {}",
+        key_range, connection.address
     );
 
-    {
-        let mut storage_access = storage.lock().await;
-        for key in keys_to_transfer {
-            if let Some(value) = storage_access.remove(&key) {
-                response_payload.extend_from_slice(&key.to_be_bytes());
-                response_payload.extend_from_slice(&(value.len() as u32).to_be_bytes());
-                response_payload.extend_from_slice(&value);
+    // the range is sent one shard at a time and only removed once the joining node has stored all of it,
+    // so that a failed transfer leaves the keys in place
+    let sent_versions = match send_range(&mut connection, &storage, key_range.clone()).await {
+        Ok(sent_versions) => sent_versions,
+        Err(RangeError::Storage(error)) => {
+            println!("failed to read leader keys {:?} ({})", key_range, error);
+            connection
+                .send_error(error.error_code(), &error.to_string())
+                .await;
+            return;
+        }
+        Err(RangeError::Connection(error)) => {
+            println!("failed to transfer leader keys ({}), keeping them", error);
+            return;
+        }
+    };
+
+    // a joining node of an older protocol version does not acknowledge the transfer
+    if connection.acknowledges_transfers() {
+        match connection.read_message().await {
+            Ok(message) if message.is_ok() => {}
+            Ok(Message::Error { code, message }) => {
+                println!(
+                    "joining node failed to store leader keys ({:?}: {}), keeping them",
+                    code, message
+                );
+                return;
+            }
+            Ok(message) => {
+                println!(
+                    "unexpected acknowledgement of leader transfer (type {}), keeping keys",
+                    message.message_type()
+                );
+                return;
+            }
+            Err(error) => {
+                println!(
+                    "leader transfer was not acknowledged ({}), keeping keys",
+                    error
+                );
+                return;
             }
         }
     }
 
-    let response_length_bytes = (5 + response_payload.len() as u32).to_be_bytes();
-    let response = [vec![0], response_length_bytes.to_vec(), response_payload].concat();
+    // values written during the transfer are newer than the transferred ones and are kept
+    for (key, version) in &sent_versions {
+        let mut storage_access = storage.shard(*key).lock().await;
+        let result = match current_entry(&*storage_access, *key) {
+            Ok(Some(entry)) if entry.version != *version => continue,
+            Ok(_) => storage_access.delete(*key).map(|_| ()),
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            println!(
+                "failed to remove transferred leader key={} ({})",
+                key, error
+            );
+        }
+    }
 
-    connection.send_message(&response).await;
+    println!("transferred {} leader keys", sent_versions.len());
 }
 
 /// Handles an incoming request asking a copy of
 /// all the key-value pairs stored in the primary storage of this node.
-pub async fn handle_backup_request(
-    mut connection: Connection,
-    storage: Arc<Mutex<HashMap<u64, Vec<u8>>>>,
-) {
-    // message was [12, 0, 0, 0, 5]
-    let storage_keys: Vec<_>;
-    {
-        let storage_access = storage.lock().await;
-        storage_keys = storage_access.keys().cloned().collect();
-    }
+pub async fn handle_backup_request(mut connection: Connection, storage: Arc<ShardedStorage>) {
     println!(
-        "responding leader kv-pairs (keys {:?}) to {} for backup",
-        storage_keys, connection.address
+        "responding leader kv-pairs to {} for backup",
+        connection.address
     );
 
-    let mut response_payload = Vec::new();
+    match send_range(&mut connection, &storage, 0..=u64::MAX).await {
+        Ok(sent_versions) => println!("backup transfer of {} keys done", sent_versions.len()),
+        Err(RangeError::Storage(error)) => {
+            println!("failed to copy leader kv-pairs for backup ({})", error);
+            connection
+                .send_error(error.error_code(), &error.to_string())
+                .await;
+        }
+        Err(RangeError::Connection(error)) => {
+            println!("failed to respond leader kv-pairs ({}), dropping", error);
+        }
+    }
+}
 
-    {
-        let storage_access = storage.lock().await;
+/// The error returned when a range of keys cannot be sent.
+enum RangeError {
+    Storage(StorageError),
+    Connection(ConnectionError),
+}
 
-        for (key, value) in storage_access.iter() {
-            response_payload.extend_from_slice(&key.to_be_bytes());
-            response_payload.extend_from_slice(&(value.len() as u32).to_be_bytes());
-            response_payload.extend_from_slice(value);
+/// Sends the key-value pairs of the given range as the response on the given connection,
+/// copying one shard at a time so that only the pairs of a single shard are held in memory
+/// and requests to the other shards are not blocked.
+/// Returns the keys and the versions of the sent pairs.
+async fn send_range(
+    connection: &mut Connection,
+    storage: &ShardedStorage,
+    key_range: RangeInclusive<u64>,
+) -> Result<Vec<(u64, u64)>, RangeError> {
+    let mut sent_versions = Vec::new();
+    let mut sender = KvPairsSender::new(connection);
+    for index in 0..storage.shard_count() {
+        let kv_pairs = storage
+            .copy_shard_range(index, key_range.clone())
+            .await
+            .map_err(RangeError::Storage)?;
+        for (key, entry) in kv_pairs {
+            sent_versions.push((key, entry.version));
+            sender.push(key, entry);
+            sender
+                .send_full_chunk()
+                .await
+                .map_err(RangeError::Connection)?;
         }
     }
+    let version_high_water = storage.version_high_water().await;
+    sender
+        .finish(version_high_water)
+        .await
+        .map_err(RangeError::Connection)?;
+    Ok(sent_versions)
+}
 
-    let response_length = response_payload.len() as u32 + 5;
-
-    let response = [
-        vec![0],
-        response_length.to_be_bytes().to_vec(),
-        response_payload,
-    ]
-    .concat();
+/// Returns the verified value of the given key and its version,
+/// either the current ones or the ones at the given point of the history of the key.
+/// The value is `None` if the key has no value, it has expired or it has been deleted,
+/// and the version is `0` if the key has never been written, its tombstone has been removed
+/// or the requested version is not kept.
+async fn read_value(
+    storage: &ShardedStorage,
+    key: u64,
+    at: Option<ReadPoint>,
+) -> Result<(Option<Vec<u8>>, u64), StorageError> {
+    let current = current_entry(&*storage.shard(key).lock().await, key)?;
+    Ok(value_at(current, at, now_millis()))
+}
 
-    connection.send_message(&response).await;
+/// Returns the verified entry of the given key with its history, even if it has expired.
+fn current_entry(storage: &dyn StorageEngine, key: u64) -> Result<Option<Entry>, StorageError> {
+    Ok(storage
+        .get(key)?
+        .map(StoredValue::into_verified)
+        .transpose()?)
+}
 
-    println!("backup transfer done");
+/// Returns the value and the version of the given entry like `read_value`.
+fn value_at(current: Option<Entry>, at: Option<ReadPoint>, now: u64) -> (Option<Vec<u8>>, u64) {
+    let found = match (current, at) {
+        (None, _) => None,
+        (Some(current), None) => Some((current, now)),
+        (Some(current), Some(at)) => find_version(current, at, now),
+    };
+    match found {
+        Some((entry, read_at)) => {
+            let version = entry.version;
+            if entry.is_expired_at(read_at) {
+                (None, version)
+            } else {
+                (entry.into_value(), version)
+            }
+        }
+        None => (None, 0),
+    }
 }
 
-/// Handles an incoming request asking an array of
-/// key-value pairs to be inserted into the primary storage of this node.
-pub async fn handle_fault_tolerance_insertion(
-    mut connection: Connection,
-    message: Vec<u8>,
-    storage: Arc<Mutex<HashMap<u64, Vec<u8>>>>,
+/// Gives the new entry of a key the given version and write time,
+/// and keeps the replaced entry, if any, in its history as far as the given retention allows.
+/// The version comes from the shard of the key, so it is greater than every version the key had before,
+/// even if its previous value or tombstone has been removed since.
+fn supersede(
+    entry: &mut Entry,
+    current: Option<Entry>,
+    version: u64,
+    history: &HistoryRetention,
+    now: u64,
 ) {
-    // at this point the first byte of message is 33
-    if message.len() < 5 {
-        println!("received invalid fault tolerance insertion request, dropping");
-        return;
+    entry.version = version;
+    entry.written_at = now;
+    if let Some(current) = current {
+        history.supersede(entry, current, now);
     }
+}
 
-    let mut keys = Vec::new();
+/// Stores the new entry of the given key within the memory budget like `supersede` describes,
+/// unless the current value of the key does not match the given condition.
+/// Returns `false` without storing the entry if it does not match.
+fn write_entry(
+    storage: &mut VersionedEngine,
+    key: u64,
+    entry: &mut Entry,
+    condition: Option<&WriteCondition>,
+    history: &HistoryRetention,
+) -> Result<bool, StorageError> {
+    let now = now_millis();
+    let current = current_entry(&*storage, key)?;
+    if let Some(condition) = condition {
+        let (value, version) = value_at(current.clone(), None, now);
+        let matches = match condition {
+            WriteCondition::Version(expected) => version == *expected,
+            WriteCondition::Value(expected) => value.as_ref() == Some(expected),
+            WriteCondition::Absent => value.is_none(),
+        };
+        if !matches {
+            return Ok(false);
+        }
+    }
 
-    {
-        let mut storage_access = storage.lock().await;
+    // the version is only given out once the value is stored, as a write that has to evict values is run again
+    let version = storage.upcoming_version();
+    supersede(entry, current, version, history, now);
+    storage.put_within_budget(key, StoredValue::new(entry.clone()))?;
+    Ok(true)
+}
+
+/// Returns the message of the error response telling that the value of the given key could not be read or written.
+fn storage_error_message(key: u64, error: &StorageError) -> String {
+    match error {
+        StorageError::Corrupted => format!("stored value of key {} is corrupted", key),
+        StorageError::Io(_) => format!("failed to access the stored value of key {}", key),
+        StorageError::BudgetExceeded | StorageError::EvictionNeeded => {
+            format!("value of key {} does not fit the memory budget", key)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::helpers::communication::listen_messages;
+    use crate::helpers::storage::{BudgetPolicy, MemoryBudget, MemoryEngine};
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio_stream::StreamExt;
 
-        let mut i = 5;
-        while i < message.len() {
-            let key = u64::from_be_bytes(message[i..i + 8].try_into().unwrap());
-            let value_length =
-                u32::from_be_bytes(message[i + 8..i + 12].try_into().unwrap()) as usize;
-            let value = &message[i + 12..i + 12 + value_length];
+    fn versioned() -> VersionedEngine {
+        VersionedEngine::new(Box::new(MemoryEngine::new()))
+    }
+
+    fn read(storage: &VersionedEngine, at: Option<ReadPoint>) -> (Option<Vec<u8>>, u64) {
+        value_at(current_entry(storage, 1).unwrap(), at, now_millis())
+    }
 
-            storage_access.insert(key, value.to_vec());
-            keys.push(key);
+    #[test]
+    fn compare_and_swap() {
+        let mut storage = versioned();
+        let write = |storage: &mut VersionedEngine, value: u8, condition| {
+            let mut entry = Entry::new(vec![value]);
+            let history = HistoryRetention::default();
+            write_entry(storage, 1, &mut entry, Some(&condition), &history).unwrap()
+        };
+
+        assert!(!write(&mut storage, 1, WriteCondition::Version(1)));
+        assert!(write(&mut storage, 1, WriteCondition::Absent));
+        assert!(!write(&mut storage, 2, WriteCondition::Absent));
+        assert!(!write(&mut storage, 2, WriteCondition::Value(vec![2])));
+        assert!(write(&mut storage, 2, WriteCondition::Value(vec![1])));
+        assert!(!write(&mut storage, 3, WriteCondition::Version(1)));
+        assert!(write(&mut storage, 3, WriteCondition::Version(2)));
+        assert_eq!(read(&storage, None), (Some(vec![3]), 3));
+
+        // a deleted key has no value but keeps its version
+        let mut tombstone = Entry::tombstone(Duration::from_secs(60));
+        let version = storage.next_version();
+        supersede(
+            &mut tombstone,
+            current_entry(&storage, 1).unwrap(),
+            version,
+            &HistoryRetention::default(),
+            now_millis(),
+        );
+        storage.put(1, StoredValue::new(tombstone)).unwrap();
+        assert_eq!(read(&storage, None), (None, 4));
+        assert!(!write(&mut storage, 4, WriteCondition::Version(3)));
+        assert!(write(&mut storage, 4, WriteCondition::Absent));
+    }
+
+    #[test]
+    fn versions_after_expiry() {
+        let mut storage = versioned();
+        let history = HistoryRetention::default();
+
+        let mut entry = Entry::with_ttl(vec![1], Some(Duration::ZERO));
+        write_entry(&mut storage, 1, &mut entry, None, &history).unwrap();
+        let stale_version = entry.version;
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(storage.remove_expired().unwrap(), vec![1]);
+
+        // the rewritten key does not get the version of the removed value again
+        let mut entry = Entry::new(vec![2]);
+        write_entry(&mut storage, 1, &mut entry, None, &history).unwrap();
+        assert!(entry.version > stale_version);
+
+        let mut entry = Entry::new(vec![3]);
+        let condition = WriteCondition::Version(stale_version);
+        assert!(!write_entry(&mut storage, 1, &mut entry, Some(&condition), &history).unwrap());
+        assert_eq!(read(&storage, None).0, Some(vec![2]));
+    }
 
-            i += value_length + 12;
+    #[test]
+    fn versions_after_eviction() {
+        let budget = MemoryBudget::new(Some(9), BudgetPolicy::Evict);
+        let mut storage =
+            VersionedEngine::new(budget.accounted_evictable(Box::new(MemoryEngine::new())));
+        let history = HistoryRetention::default();
+
+        let mut entry = Entry::new(vec![1]);
+        write_entry(&mut storage, 1, &mut entry, None, &history).unwrap();
+
+        // the write that has to evict the other value first does not use up a version
+        let mut entry = Entry::new(vec![2]);
+        assert!(matches!(
+            write_entry(&mut storage, 2, &mut entry, None, &history),
+            Err(StorageError::EvictionNeeded)
+        ));
+        storage.delete(1).unwrap();
+        write_entry(&mut storage, 2, &mut entry, None, &history).unwrap();
+        assert_eq!(entry.version, 2);
+    }
+
+    #[test]
+    fn history_reading() {
+        let mut storage = versioned();
+        let history = HistoryRetention {
+            versions: Some(2),
+            window: None,
+        };
+        for value in 1..=4 {
+            let mut entry = Entry::new(vec![value]);
+            write_entry(&mut storage, 1, &mut entry, None, &history).unwrap();
         }
+
+        assert_eq!(read(&storage, None), (Some(vec![4]), 4));
+        assert_eq!(
+            read(&storage, Some(ReadPoint::Version(3))),
+            (Some(vec![3]), 3)
+        );
+        assert_eq!(
+            read(&storage, Some(ReadPoint::Version(2))),
+            (Some(vec![2]), 2)
+        );
+        assert_eq!(read(&storage, Some(ReadPoint::Version(1))), (None, 0));
+        assert_eq!(
+            read(&storage, Some(ReadPoint::Time(now_millis()))),
+            (Some(vec![4]), 4)
+        );
+        assert_eq!(read(&storage, Some(ReadPoint::Time(0))), (None, 0));
     }
 
-    println!("inserted fault tolerance keys {:?} to leader storage", keys);
+    /// Sends a leader transfer request of all keys to a handler of the given storage.
+    /// Returns the connection of the joining node and the task of the handler.
+    async fn request_transfer(
+        storage: &Arc<ShardedStorage>,
+    ) -> (Connection, tokio::task::JoinHandle<()>) {
+        let port = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut incoming = Box::pin(listen_messages(IpAddr::V4(Ipv4Addr::LOCALHOST), port).await);
+
+        let request = Message::LeaderTransferRequest {
+            key_range: 0..=u64::MAX,
+        };
+        let joining = Connection::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port, &request)
+            .await
+            .unwrap();
+        let mut leader = incoming.next().await.unwrap();
+        leader.read_message().await.unwrap();
+
+        let handler = tokio::task::spawn(handle_transfer_request(
+            leader,
+            0..=u64::MAX,
+            Arc::clone(storage),
+        ));
+        (joining, handler)
+    }
 
-    connection.send_message(&[0, 0, 0, 0, 7, 111, 107]).await;
+    #[tokio::test]
+    async fn transfer_acknowledgement() {
+        let budget = MemoryBudget::new(None, BudgetPolicy::Reject);
+        let storage = Arc::new(ShardedStorage::new(
+            vec![budget.accounted(Box::new(MemoryEngine::new()))],
+            budget,
+            None,
+        ));
+        storage
+            .insert_many(vec![(1, Entry::new(vec![1])), (2, Entry::new(vec![2]))])
+            .await
+            .unwrap();
+
+        // the keys are kept if the joining node goes away before acknowledging the transfer
+        let (mut joining, handler) = request_transfer(&storage).await;
+        assert!(matches!(
+            joining.read_message().await,
+            Ok(Message::Response(_))
+        ));
+        drop(joining);
+        handler.await.unwrap();
+        assert_eq!(storage.copy_range(0..=u64::MAX).await.unwrap().len(), 2);
+
+        let (mut joining, handler) = request_transfer(&storage).await;
+        assert!(matches!(
+            joining.read_message().await,
+            Ok(Message::Response(_))
+        ));
+        joining.send_message(&Message::ok()).await;
+        handler.await.unwrap();
+        assert!(storage.copy_range(0..=u64::MAX).await.unwrap().is_empty());
+    }
 }
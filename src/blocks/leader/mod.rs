@@ -1,69 +1,141 @@
-use crate::helpers::communication::Connection;
+use crate::helpers::communication::{Connection, ConnectionPool, ErrorCode, Message};
+use crate::helpers::entry::Entry;
+use crate::helpers::history::HistoryRetention;
+use crate::helpers::storage::{ShardedStorage, StorageError, StorageStats};
 use crate::PeerNode;
 use handlers::{
-    handle_backup_request, handle_fault_tolerance_insertion, handle_read_request,
-    handle_transfer_request, handle_write_request,
+    handle_backup_request, handle_delete_request, handle_read_request, handle_transfer_request,
+    handle_write_request,
 };
-use std::collections::HashMap;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{mpsc, Mutex};
 
 mod backup;
 mod handlers;
 
+/// In-process access to the primary storage of this node.
+#[derive(Clone)]
+pub struct LeaderHandle {
+    storage: Arc<ShardedStorage>,
+}
+
+impl LeaderHandle {
+    /// Wraps the given sharded storage as the primary storage.
+    pub fn new(storage: ShardedStorage) -> LeaderHandle {
+        LeaderHandle {
+            storage: Arc::new(storage),
+        }
+    }
+
+    /// Inserts the given key-value pairs, replacing the existing values of the same keys.
+    pub async fn insert_many(&self, kv_pairs: Vec<(u64, Entry)>) -> Result<(), StorageError> {
+        self.storage.insert_many(kv_pairs).await
+    }
+
+    /// Returns a copy of all key-value pairs, or an error if any of the values cannot be read.
+    pub async fn snapshot(&self) -> Result<Vec<(u64, Entry)>, StorageError> {
+        self.storage.copy_range(0..=u64::MAX).await
+    }
+
+    /// Returns the number of shards of the primary storage.
+    pub fn shard_count(&self) -> usize {
+        self.storage.shard_count()
+    }
+
+    /// Returns a copy of the key-value pairs of the shard of the given index in the given range,
+    /// or an error if any of the values cannot be read.
+    pub async fn copy_shard_range(
+        &self,
+        index: usize,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, Entry)>, StorageError> {
+        self.storage.copy_shard_range(index, key_range).await
+    }
+
+    /// Returns the greatest version stored or given out by the primary storage.
+    pub async fn version_high_water(&self) -> u64 {
+        self.storage.version_high_water().await
+    }
+
+    /// Raises the version high-water mark of the primary storage to the given version,
+    /// so that the versions given out from now on are greater than it.
+    pub async fn raise_version_high_water(&self, version: u64) {
+        self.storage.raise_version_high_water(version).await
+    }
+
+    /// Removes the expired values and returns their keys.
+    pub async fn remove_expired(&self) -> Result<Vec<u64>, StorageError> {
+        self.storage.remove_expired().await
+    }
+
+    /// Returns the size of the primary storage.
+    pub async fn stats(&self) -> StorageStats {
+        self.storage.stats().await
+    }
+}
+
 /// Handles incoming requests related to the primary key-value pairs stored by this node.
+/// Deleted keys are kept as tombstones for the given lifetime,
+/// and replaced values are kept in the histories of the keys as far as the given retention allows.
 pub async fn leader_block(
-    mut incoming_connection_stream: mpsc::UnboundedReceiver<(Connection, Vec<u8>)>,
-    initial_kv_pairs: Vec<(u64, Vec<u8>)>,
+    mut incoming_connection_stream: mpsc::UnboundedReceiver<(Connection, Message)>,
+    leader: LeaderHandle,
     node_list_arc: Arc<Mutex<Vec<PeerNode>>>,
     this_node_id: u64,
+    connection_pool: Arc<ConnectionPool>,
+    tombstone_lifetime: Duration,
+    history: HistoryRetention,
 ) {
-    let leader_storage: Arc<Mutex<HashMap<u64, Vec<u8>>>> = Arc::new(Mutex::new(HashMap::new()));
-
-    println!(
-        "leader block starting with initial kv-pairs {:?}",
-        initial_kv_pairs
-    );
-
-    {
-        let mut storage_access = leader_storage.lock().await;
-        for (key, value) in initial_kv_pairs {
-            storage_access.insert(key, value);
-        }
-    }
-
-    while let Some((connection, first_message)) = incoming_connection_stream.recv().await {
-        let leader_storage_clone = Arc::clone(&leader_storage);
+    while let Some((mut connection, first_message)) = incoming_connection_stream.recv().await {
+        let leader_storage_clone = Arc::clone(&leader.storage);
         let node_list_clone = Arc::clone(&node_list_arc);
+        let connection_pool_clone = Arc::clone(&connection_pool);
 
         tokio::task::spawn(async move {
-            match first_message.first() {
-                Some(1) => {
-                    handle_read_request(connection, first_message, leader_storage_clone).await
+            match first_message {
+                Message::LeaderRead { key, at } => {
+                    handle_read_request(connection, key, at, leader_storage_clone).await
                 }
-                Some(2) => {
+                Message::LeaderWrite { key, ttl } => {
                     handle_write_request(
                         connection,
-                        first_message,
+                        key,
+                        ttl,
+                        tombstone_lifetime,
+                        history,
                         leader_storage_clone,
                         this_node_id,
                         node_list_clone,
+                        connection_pool_clone,
                     )
                     .await
                 }
-                Some(11) => {
-                    handle_transfer_request(connection, first_message, leader_storage_clone).await
-                }
-                Some(12) => handle_backup_request(connection, leader_storage_clone).await,
-                Some(33) => {
-                    handle_fault_tolerance_insertion(
+                Message::LeaderDelete { key } => {
+                    handle_delete_request(
                         connection,
-                        first_message,
+                        key,
+                        tombstone_lifetime,
+                        history,
                         leader_storage_clone,
+                        this_node_id,
+                        node_list_clone,
+                        connection_pool_clone,
                     )
                     .await
                 }
-                _ => panic!(),
+                Message::LeaderTransferRequest { key_range } => {
+                    handle_transfer_request(connection, key_range, leader_storage_clone).await
+                }
+                Message::BackupRequest => {
+                    handle_backup_request(connection, leader_storage_clone).await
+                }
+                _ => {
+                    connection
+                        .send_error(ErrorCode::BadRequest, "unexpected message for leader block")
+                        .await;
+                }
             };
         });
     }
@@ -1,20 +1,35 @@
-use crate::helpers::communication::Connection;
+use crate::helpers::communication::{encode_node_list, Connection, ErrorCode, Message};
 use crate::PeerNode;
 use std::sync::Arc;
 use tokio::sync::{mpsc, Mutex};
 
 /// Handles incoming requests related to new peer nodes joining the ring.
 pub async fn peer_block(
-    mut incoming_connection_stream: mpsc::UnboundedReceiver<(Connection, Vec<u8>)>,
+    mut incoming_connection_stream: mpsc::UnboundedReceiver<(Connection, Message)>,
     node_list: Arc<Mutex<Vec<PeerNode>>>,
 ) {
-    while let Some((connection, message)) = incoming_connection_stream.recv().await {
+    while let Some((mut connection, message)) = incoming_connection_stream.recv().await {
         let node_list_clone = Arc::clone(&node_list);
 
-        match message.first() {
-            Some(10) => handle_node_list_request(connection, node_list_clone).await,
-            Some(13) => handle_join_announcement(connection, message, node_list_clone).await,
-            _ => {}
+        match message {
+            Message::NodeListRequest => handle_node_list_request(connection, node_list_clone).await,
+            Message::JoinAnnouncement {
+                node_id,
+                ip_address,
+                port,
+            } => {
+                let joining_node = PeerNode {
+                    id: node_id,
+                    ip_address,
+                    port,
+                };
+                handle_join_announcement(connection, joining_node, node_list_clone).await
+            }
+            _ => {
+                connection
+                    .send_error(ErrorCode::BadRequest, "unexpected message for peer block")
+                    .await;
+            }
         };
     }
 }
@@ -24,74 +39,34 @@ async fn handle_node_list_request(
     mut connection: Connection,
     node_list_arc: Arc<Mutex<Vec<PeerNode>>>,
 ) {
-    // at this point the first byte of message is 10
-
     println!("serving node list to {}", connection.address);
 
-    let mut encoded_node_list = Vec::new();
-
+    let encoded_node_list;
     {
         let node_list = node_list_arc.lock().await;
-
-        for node in node_list.iter() {
-            let node_id_bytes = node.id.to_be_bytes();
-            let node_ip_bytes = ip_address_bytes(&node.ip_address);
-            encoded_node_list.extend_from_slice(&node_id_bytes);
-            encoded_node_list.extend_from_slice(&node_ip_bytes);
-        }
+        encoded_node_list = encode_node_list(&node_list);
     }
 
-    let response_length = (5 + encoded_node_list.len() as u32).to_be_bytes();
     connection
-        .send_message(&[vec![0], response_length.to_vec(), encoded_node_list].concat())
+        .send_message(&Message::Response(encoded_node_list))
         .await;
 }
 
 /// Handles an incoming request that announces a new node has joined the ring.
 async fn handle_join_announcement(
     mut connection: Connection,
-    message: Vec<u8>,
+    joining_node: PeerNode,
     node_list_arc: Arc<Mutex<Vec<PeerNode>>>,
 ) {
-    // at this point the first byte of message is 13
-    if message.len() != 13 || u32::from_be_bytes(message[1..5].try_into().unwrap()) != 13 {
-        println!(
-            "received invalid join announcement from {}, dropping",
-            connection.address
-        );
-        return;
-    }
-
-    let joining_node_id = u64::from_be_bytes(message[5..13].try_into().unwrap());
-    let joining_node_ip = connection.address.ip().to_canonical();
-
-    if !joining_node_ip.is_ipv4() {
-        println!("received join announcement from non-IPv4 address, dropping");
-        return;
-    }
+    println!(
+        "registered new peer ID={} at {} port {}",
+        joining_node.id, joining_node.ip_address, joining_node.port
+    );
 
     {
         let mut node_list = node_list_arc.lock().await;
-        node_list.push(PeerNode {
-            id: joining_node_id,
-            ip_address: joining_node_ip.to_string(),
-        });
+        node_list.push(joining_node);
     }
 
-    println!(
-        "registered new peer ID={} at {}",
-        joining_node_id, joining_node_ip
-    );
-
-    connection.send_message(&[0, 0, 0, 0, 7, 111, 107]).await;
-}
-
-/// Returns a list of exactly four integers encoding the given IPv4 address.
-/// Panics if the address is invalid.
-fn ip_address_bytes(address: &str) -> [u8; 4] {
-    let parts: Vec<u8> = address.split('.').map(|s| s.parse().unwrap()).collect();
-    if parts.len() != 4 {
-        panic!("invalid IPv4 address");
-    }
-    parts.try_into().unwrap()
+    connection.send_message(&Message::ok()).await;
 }
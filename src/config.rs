@@ -0,0 +1,221 @@
+use crate::helpers::communication::{
+    AuthenticationSettings, ConnectionSettings, TlsSettings, DEFAULT_PORT,
+};
+use crate::helpers::history::HistoryRetention;
+use crate::helpers::storage::{BudgetPolicy, StorageSettings};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::net::{IpAddr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Configuration of a node, read from the environment variables.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Hostname of a single known node in the system, `None` to start a new system.
+    /// May be followed by a colon and the port of that node.
+    pub known_node_host: Option<String>,
+    /// The address on which this node listens for incoming connections.
+    pub bind_address: IpAddr,
+    /// The port on which this node listens for incoming connections.
+    pub port: u16,
+    /// The address, optionally with a port, that other nodes use to contact this node.
+    /// Determined automatically if `None`.
+    pub advertised_address: Option<String>,
+    /// Limits applied to every connection of this node.
+    pub connection: ConnectionSettings,
+    /// The storage engine of the leader and backup storages of this node.
+    pub storage: StorageSettings,
+    /// The number of shards the leader storage is split into, each with a lock of its own.
+    pub leader_shards: NonZeroUsize,
+    /// The directory of the write-ahead log and snapshots of the storages,
+    /// `None` to keep the storages only until the node stops.
+    pub data_directory: Option<PathBuf>,
+    /// How often the write-ahead log is replaced with a snapshot of the storages.
+    pub snapshot_interval: Duration,
+    /// The number of bytes the leader and backup storages may use together, `None` for no limit.
+    pub memory_budget: Option<usize>,
+    /// What happens to a client write that would exceed the memory budget.
+    pub budget_policy: BudgetPolicy,
+    /// How often the expired values are removed from the storages.
+    pub expiry_sweep_interval: Duration,
+    /// How long the tombstone of a deleted key is kept before it is removed like an expired value.
+    pub tombstone_lifetime: Duration,
+    /// How many past versions of each key the leader keeps for reads of the history.
+    pub history: HistoryRetention,
+}
+
+impl Config {
+    /// Reads the configuration from the environment variables, using defaults for missing ones.
+    /// Panics if a variable is present but invalid.
+    pub fn from_env() -> Config {
+        let defaults = Config::default();
+
+        Config {
+            known_node_host: env::var("DS_KNOWN_NODE").ok(),
+            bind_address: parse_env("DS_BIND_ADDRESS").unwrap_or(defaults.bind_address),
+            port: parse_env("DS_PORT").unwrap_or(defaults.port),
+            advertised_address: env::var("DS_ADVERTISED_ADDRESS").ok(),
+            connection: ConnectionSettings {
+                max_message_length: parse_env("DS_MAX_MESSAGE_LENGTH")
+                    .unwrap_or(defaults.connection.max_message_length),
+                read_timeout: parse_env("DS_READ_TIMEOUT")
+                    .map(Duration::from_secs)
+                    .unwrap_or(defaults.connection.read_timeout),
+                connection_timeout: parse_env("DS_CONNECTION_TIMEOUT")
+                    .map(Duration::from_secs)
+                    .unwrap_or(defaults.connection.connection_timeout),
+                tls: tls_settings_from_env(),
+                authentication: authentication_settings_from_env(),
+            },
+            storage: storage_settings_from_env(),
+            leader_shards: parse_env("DS_LEADER_SHARDS").unwrap_or(defaults.leader_shards),
+            data_directory: env::var_os("DS_DATA_DIRECTORY").map(PathBuf::from),
+            snapshot_interval: parse_env("DS_SNAPSHOT_INTERVAL")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.snapshot_interval),
+            memory_budget: parse_env("DS_MEMORY_BUDGET"),
+            budget_policy: budget_policy_from_env(),
+            expiry_sweep_interval: parse_env("DS_EXPIRY_SWEEP_INTERVAL")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.expiry_sweep_interval),
+            tombstone_lifetime: parse_env("DS_TOMBSTONE_LIFETIME")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.tombstone_lifetime),
+            history: HistoryRetention {
+                versions: parse_env("DS_HISTORY_VERSIONS"),
+                window: parse_env("DS_HISTORY_WINDOW").map(Duration::from_secs),
+            },
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            known_node_host: None,
+            bind_address: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+            advertised_address: None,
+            connection: ConnectionSettings::default(),
+            storage: StorageSettings::default(),
+            leader_shards: NonZeroUsize::new(16).unwrap(),
+            data_directory: None,
+            snapshot_interval: Duration::from_secs(300),
+            memory_budget: None,
+            budget_policy: BudgetPolicy::default(),
+            expiry_sweep_interval: Duration::from_secs(10),
+            tombstone_lifetime: Duration::from_secs(24 * 60 * 60),
+            history: HistoryRetention::default(),
+        }
+    }
+}
+
+/// Reads the paths of the TLS files, if TLS is enabled.
+/// Panics if only some of the paths are set.
+fn tls_settings_from_env() -> Option<TlsSettings> {
+    let paths = (
+        env::var_os("DS_TLS_CERT"),
+        env::var_os("DS_TLS_KEY"),
+        env::var_os("DS_TLS_CA"),
+    );
+    match paths {
+        (Some(certificate), Some(private_key), Some(ca_certificate)) => Some(TlsSettings {
+            certificate_path: certificate.into(),
+            private_key_path: private_key.into(),
+            ca_certificate_path: ca_certificate.into(),
+        }),
+        (None, None, None) => None,
+        _ => panic!("DS_TLS_CERT, DS_TLS_KEY and DS_TLS_CA must be set together"),
+    }
+}
+
+/// Reads the storage engine and its directory.
+/// Panics if the engine is unknown.
+fn storage_settings_from_env() -> StorageSettings {
+    match env::var("DS_STORAGE_ENGINE").as_deref() {
+        Err(_) | Ok("memory") => StorageSettings::Memory,
+        Ok("disk") => StorageSettings::Disk {
+            directory: env::var_os("DS_STORAGE_DIRECTORY")
+                .unwrap_or_else(|| "data".into())
+                .into(),
+        },
+        Ok(engine) => panic!("invalid value {:?} for DS_STORAGE_ENGINE", engine),
+    }
+}
+
+/// Reads the policy applied when the memory budget is exceeded.
+/// Panics if the policy is unknown.
+fn budget_policy_from_env() -> BudgetPolicy {
+    match env::var("DS_BUDGET_POLICY").as_deref() {
+        Err(_) | Ok("reject") => BudgetPolicy::Reject,
+        Ok("evict") => BudgetPolicy::Evict,
+        Ok(policy) => panic!("invalid value {:?} for DS_BUDGET_POLICY", policy),
+    }
+}
+
+/// Reads the secrets of the nodes and the clients and the names of the administrator clients.
+/// Panics if an administrator client is not one of the clients in `DS_CLIENT_CREDENTIALS`.
+fn authentication_settings_from_env() -> AuthenticationSettings {
+    let client_secrets = client_secrets_from_env();
+    let admin_clients: HashSet<String> = env::var("DS_ADMIN_CLIENTS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    if let Some(name) = admin_clients
+        .iter()
+        .find(|name| !client_secrets.contains_key(*name))
+    {
+        panic!(
+            "administrator client {:?} in DS_ADMIN_CLIENTS is not in DS_CLIENT_CREDENTIALS",
+            name
+        );
+    }
+
+    AuthenticationSettings {
+        cluster_secret: env::var("DS_CLUSTER_SECRET").ok().map(String::into_bytes),
+        client_secrets,
+        admin_clients,
+    }
+}
+
+/// Reads the client names and secrets from the file given in `DS_CLIENT_CREDENTIALS`.
+/// Every non-empty line of the file is `name:secret`.
+/// Panics if the file cannot be read or a line is invalid.
+fn client_secrets_from_env() -> HashMap<String, Vec<u8>> {
+    let Some(path) = env::var_os("DS_CLIENT_CREDENTIALS") else {
+        return HashMap::new();
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(error) => panic!("failed to read DS_CLIENT_CREDENTIALS ({})", error),
+    };
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.split_once(':') {
+            Some((name, secret)) if !name.is_empty() && !secret.is_empty() => {
+                (name.to_string(), secret.as_bytes().to_vec())
+            }
+            _ => panic!("invalid line {:?} in DS_CLIENT_CREDENTIALS", line),
+        })
+        .collect()
+}
+
+/// Parses the value of the given environment variable, if it is set.
+/// Panics if the value cannot be parsed.
+fn parse_env<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => panic!("invalid value {:?} for {}", value, name),
+    }
+}
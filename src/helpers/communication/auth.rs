@@ -0,0 +1,275 @@
+use super::message::Message;
+use super::tls;
+use ring::hmac;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many seconds the timestamp of an authentication message may differ from the clock of this node.
+const AUTHENTICATION_WINDOW: u64 = 300;
+
+/// Secrets used to authenticate the other nodes and the clients.
+#[derive(Clone, Default)]
+pub struct AuthenticationSettings {
+    /// Secret shared by all nodes of the system, `None` to accept internal requests from anyone.
+    pub cluster_secret: Option<Vec<u8>>,
+    /// Secrets of the clients by their names, empty to accept client requests from anyone.
+    pub client_secrets: HashMap<String, Vec<u8>>,
+    /// Names of the clients that may also send administrative requests, such as exports.
+    pub admin_clients: HashSet<String>,
+}
+
+impl fmt::Debug for AuthenticationSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthenticationSettings")
+            .field(
+                "cluster_secret",
+                &self.cluster_secret.as_ref().map(|_| "<hidden>"),
+            )
+            .field("clients", &self.client_secrets.keys().collect::<Vec<_>>())
+            .field("admin_clients", &self.admin_clients)
+            .finish()
+    }
+}
+
+/// The authenticated sender of the messages of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    /// Another node of the system.
+    Node,
+    /// The client with the given name.
+    Client(String),
+}
+
+static AUTHENTICATION: OnceLock<AuthenticationSettings> = OnceLock::new();
+
+/// Nonces of the accepted authentication messages with their timestamps, to detect replays.
+static SEEN_NONCES: Mutex<Option<HashMap<u64, u64>>> = Mutex::new(None);
+
+/// Sets the secrets used by all connections of this node.
+/// Has no effect if called more than once.
+pub(super) fn configure_authentication(settings: AuthenticationSettings) {
+    let _ = AUTHENTICATION.set(settings);
+}
+
+fn settings() -> &'static AuthenticationSettings {
+    AUTHENTICATION.get_or_init(AuthenticationSettings::default)
+}
+
+/// Returns `true` if internal requests are accepted only from authenticated nodes.
+pub(super) fn node_authentication_required() -> bool {
+    settings().node_authentication_required()
+}
+
+/// Returns the message proving that this node knows the cluster secret, if there is one.
+pub(super) fn node_authentication() -> Option<Message> {
+    let secret = settings().cluster_secret.as_ref()?;
+    Some(authentication_message(secret, None, current_timestamp()))
+}
+
+/// Verifies the given authentication message and returns the principal it proves to be.
+/// Returns `None` if the proof is invalid, too old or has been used before.
+pub(super) fn verify(message: &Message) -> Option<Principal> {
+    settings().verify(message, current_timestamp())
+}
+
+/// Checks whether a sender authenticated as the given principal may send the given request.
+/// Returns the reason if it may not.
+pub(super) fn authorize(
+    principal: Option<&Principal>,
+    message: &Message,
+) -> Result<(), &'static str> {
+    settings().authorize(principal, message)
+}
+
+impl AuthenticationSettings {
+    fn node_authentication_required(&self) -> bool {
+        self.cluster_secret.is_some() || tls::is_enabled()
+    }
+
+    fn verify(&self, message: &Message, now: u64) -> Option<Principal> {
+        let Message::Authenticate {
+            client,
+            timestamp,
+            nonce,
+            mac,
+        } = message
+        else {
+            return None;
+        };
+
+        let secret = match client {
+            Some(client) => self.client_secrets.get(client)?,
+            None => self.cluster_secret.as_ref()?,
+        };
+
+        if now.abs_diff(*timestamp) > AUTHENTICATION_WINDOW {
+            return None;
+        }
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+        let signed = signed_bytes(client.as_deref(), *timestamp, *nonce);
+        hmac::verify(&key, &signed, mac).ok()?;
+
+        if !remember_nonce(*nonce, *timestamp, now) {
+            return None;
+        }
+
+        Some(match client {
+            Some(client) => Principal::Client(client.clone()),
+            None => Principal::Node,
+        })
+    }
+
+    fn authorize(
+        &self,
+        principal: Option<&Principal>,
+        message: &Message,
+    ) -> Result<(), &'static str> {
+        if message.is_internal()
+            && self.node_authentication_required()
+            && principal != Some(&Principal::Node)
+        {
+            return Err("internal requests require node authentication");
+        }
+
+        if message.is_client_request()
+            && !self.client_secrets.is_empty()
+            && !matches!(principal, Some(Principal::Client(_)))
+        {
+            return Err("client requests require client authentication");
+        }
+
+        // administrative requests expose the whole storages, so they always require authentication
+        if message.is_admin_request() {
+            let authorized = match principal {
+                Some(Principal::Node) => true,
+                Some(Principal::Client(client)) => self.admin_clients.contains(client),
+                None => false,
+            };
+            if !authorized {
+                return Err("administrative requests require node or administrator authentication");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns an authentication message for the given client, or for a node if no client is given.
+fn authentication_message(secret: &[u8], client: Option<String>, timestamp: u64) -> Message {
+    let nonce = rand::random();
+    let mac = sign(secret, client.as_deref(), timestamp, nonce);
+    Message::Authenticate {
+        client,
+        timestamp,
+        nonce,
+        mac: mac.as_ref().to_vec(),
+    }
+}
+
+/// Records the nonce as used. Returns `false` if it has already been used within the window.
+fn remember_nonce(nonce: u64, timestamp: u64, now: u64) -> bool {
+    let mut seen_nonces = SEEN_NONCES.lock().unwrap();
+    let seen_nonces = seen_nonces.get_or_insert_with(HashMap::new);
+
+    seen_nonces.retain(|_, seen_timestamp| now.abs_diff(*seen_timestamp) <= AUTHENTICATION_WINDOW);
+    seen_nonces.insert(nonce, timestamp).is_none()
+}
+
+fn sign(secret: &[u8], client: Option<&str>, timestamp: u64, nonce: u64) -> hmac::Tag {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hmac::sign(&key, &signed_bytes(client, timestamp, nonce))
+}
+
+/// Returns the bytes covered by the authentication code: the principal kind, timestamp, nonce and client name.
+fn signed_bytes(client: Option<&str>, timestamp: u64, nonce: u64) -> Vec<u8> {
+    let mut bytes = vec![u8::from(client.is_some())];
+    bytes.extend_from_slice(&timestamp.to_be_bytes());
+    bytes.extend_from_slice(&nonce.to_be_bytes());
+    bytes.extend_from_slice(client.unwrap_or_default().as_bytes());
+    bytes
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn authentication_message_verification() {
+        let settings = AuthenticationSettings {
+            cluster_secret: Some(b"cluster".to_vec()),
+            client_secrets: HashMap::from([("alice".to_string(), b"wonderland".to_vec())]),
+            admin_clients: HashSet::new(),
+        };
+        let now = current_timestamp();
+
+        let message = authentication_message(b"cluster", None, now);
+        assert_eq!(settings.verify(&message, now), Some(Principal::Node));
+        // the same message cannot be replayed
+        assert_eq!(settings.verify(&message, now), None);
+
+        let message = authentication_message(b"wonderland", Some("alice".to_string()), now);
+        assert_eq!(
+            settings.verify(&message, now),
+            Some(Principal::Client("alice".to_string()))
+        );
+
+        let message = authentication_message(b"guess", Some("alice".to_string()), now);
+        assert_eq!(settings.verify(&message, now), None);
+
+        let message = authentication_message(b"cluster", Some("alice".to_string()), now);
+        assert_eq!(settings.verify(&message, now), None);
+
+        let message = authentication_message(b"wonderland", Some("bob".to_string()), now);
+        assert_eq!(settings.verify(&message, now), None);
+
+        let message = authentication_message(b"cluster", None, now - 2 * AUTHENTICATION_WINDOW);
+        assert_eq!(settings.verify(&message, now), None);
+    }
+
+    #[test]
+    fn request_authorization() {
+        let settings = AuthenticationSettings {
+            cluster_secret: Some(b"cluster".to_vec()),
+            client_secrets: HashMap::from([
+                ("alice".to_string(), b"wonderland".to_vec()),
+                ("root".to_string(), b"admin".to_vec()),
+            ]),
+            admin_clients: HashSet::from(["root".to_string()]),
+        };
+        let internal = Message::PeerDown { node_id: 1 };
+        let client = Message::ClientRead { key: 1, at: None };
+        let export = Message::ExportRequest {
+            include_backups: true,
+        };
+        let alice = Principal::Client("alice".to_string());
+        let root = Principal::Client("root".to_string());
+
+        assert!(settings
+            .authorize(Some(&Principal::Node), &internal)
+            .is_ok());
+        assert!(settings.authorize(Some(&alice), &internal).is_err());
+        assert!(settings.authorize(None, &internal).is_err());
+        assert!(settings.authorize(Some(&alice), &client).is_ok());
+        assert!(settings.authorize(None, &client).is_err());
+        assert!(settings.authorize(Some(&Principal::Node), &export).is_ok());
+        assert!(settings.authorize(Some(&root), &export).is_ok());
+        assert!(settings.authorize(Some(&alice), &export).is_err());
+        assert!(settings.authorize(None, &export).is_err());
+
+        let open = AuthenticationSettings::default();
+        assert!(open.authorize(None, &internal).is_ok());
+        assert!(open.authorize(None, &client).is_ok());
+        assert!(open.authorize(None, &export).is_err());
+    }
+}
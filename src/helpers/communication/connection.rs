@@ -0,0 +1,570 @@
+use super::auth::{self, AuthenticationSettings, Principal};
+use super::link::{LinkRequest, Protocol, CHECKSUM_LENGTH, CHUNKED_TRANSFERS, FRAME_CHECKSUMS};
+use super::message::{
+    DecodeError, ErrorCode, Message, HEADER_LENGTH, PROTOCOL_VERSION, TRANSFER_ACK_PROTOCOL_VERSION,
+};
+use super::tls::{configure_tls, secure_outgoing, Stream, TlsSettings};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpSocket;
+use tokio::time::{timeout_at, Instant};
+
+/// The message type of the request to enable checksums on a plain connection.
+const ENABLE_CHECKSUMS_TYPE: u8 = 16;
+
+/// Limits applied to every connection of this node.
+#[derive(Debug, Clone)]
+pub struct ConnectionSettings {
+    /// Maximum total length of a single message, header included.
+    pub max_message_length: usize,
+    /// Maximum time to wait for a single message to arrive completely.
+    pub read_timeout: Duration,
+    /// Maximum lifetime of a connection, counted from when it was opened or accepted.
+    pub connection_timeout: Duration,
+    /// Certificates for securing the connections with TLS, `None` for plain TCP.
+    pub tls: Option<TlsSettings>,
+    /// Secrets for authenticating the other nodes and the clients.
+    pub authentication: AuthenticationSettings,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        ConnectionSettings {
+            max_message_length: 64 * 1024 * 1024,
+            read_timeout: Duration::from_secs(60),
+            connection_timeout: Duration::from_secs(600),
+            tls: None,
+            authentication: AuthenticationSettings::default(),
+        }
+    }
+}
+
+static SETTINGS: OnceLock<ConnectionSettings> = OnceLock::new();
+
+/// Sets the limits, the TLS certificates and the secrets used by all connections of this node.
+/// Has no effect if called more than once. Panics if the TLS certificates cannot be loaded.
+pub fn configure(settings: ConnectionSettings) {
+    configure_tls(settings.tls.as_ref());
+    auth::configure_authentication(settings.authentication.clone());
+    let _ = SETTINGS.set(settings);
+}
+
+/// Returns the limits used by all connections of this node.
+pub(super) fn settings() -> &'static ConnectionSettings {
+    SETTINGS.get_or_init(ConnectionSettings::default)
+}
+
+/// Reasons why communicating over a connection failed.
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// The other end closed the connection before a complete message was received.
+    Closed,
+    /// The underlying socket failed.
+    Io(io::Error),
+    /// The message or the connection did not complete in time.
+    Timeout,
+    /// The header announced a message longer than the configured maximum.
+    MessageTooLong { length: usize, max: usize },
+    /// The received message was malformed.
+    Decode(DecodeError),
+    /// The other node speaks a protocol version this node cannot communicate with,
+    /// the other node refused the given protocol version of this node,
+    /// or the message cannot be expressed in the given protocol version of the other node.
+    IncompatibleVersion(u16),
+    /// The other node refused to communicate with this node for the given reason.
+    Unauthorized(String),
+    /// The other end sent a message of the given type where it was not expected.
+    UnexpectedMessage(u8),
+    /// The other end responded with an error.
+    ErrorResponse { code: ErrorCode, message: String },
+    /// The received message did not match the checksum that followed it.
+    ChecksumMismatch,
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::Closed => write!(f, "connection closed"),
+            ConnectionError::Io(error) => write!(f, "connection failed: {}", error),
+            ConnectionError::Timeout => write!(f, "connection timed out"),
+            ConnectionError::MessageTooLong { length, max } => write!(
+                f,
+                "message length {} exceeds the maximum of {}",
+                length, max
+            ),
+            ConnectionError::Decode(error) => write!(f, "{}", error),
+            ConnectionError::IncompatibleVersion(version) => {
+                write!(f, "incompatible protocol version {}", version)
+            }
+            ConnectionError::Unauthorized(reason) => write!(f, "not authorized ({})", reason),
+            ConnectionError::UnexpectedMessage(message_type) => {
+                write!(f, "unexpected message type {}", message_type)
+            }
+            ConnectionError::ErrorResponse { code, message } => {
+                write!(f, "error response {:?} ({})", code, message)
+            }
+            ConnectionError::ChecksumMismatch => write!(f, "message checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+impl From<io::Error> for ConnectionError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            ConnectionError::Closed
+        } else {
+            ConnectionError::Io(error)
+        }
+    }
+}
+
+impl From<DecodeError> for ConnectionError {
+    fn from(error: DecodeError) -> Self {
+        ConnectionError::Decode(error)
+    }
+}
+
+/// A conversation of messages with another process.
+pub struct Connection {
+    pub address: SocketAddr,
+    local_address: SocketAddr,
+    transport: Transport,
+    deadline: Instant,
+    /// The authenticated sender of the received messages, `None` if not authenticated.
+    principal: Option<Principal>,
+    /// The protocol version and the optional protocol features that both ends of the connection support.
+    protocol: Protocol,
+    /// The result of reading the first message, if it was read before handing out the connection.
+    pending_message: Option<Result<Message, ConnectionError>>,
+}
+
+/// The way the messages of a connection are carried.
+enum Transport {
+    /// A dedicated stream for this conversation only.
+    Stream(Stream),
+    /// A single request multiplexed over a persistent link with a peer node.
+    Link(LinkRequest),
+}
+
+impl Connection {
+    /// Wraps an accepted stream into a connection.
+    /// The given result of reading the first message, if any, is returned by the first `read_message`.
+    pub(super) fn accepted(
+        stream: Stream,
+        address: SocketAddr,
+        local_address: SocketAddr,
+        principal: Option<Principal>,
+        first_message: Option<Result<Message, ConnectionError>>,
+    ) -> Connection {
+        Connection {
+            address,
+            local_address,
+            transport: Transport::Stream(stream),
+            deadline: Instant::now() + settings().connection_timeout,
+            principal,
+            // versions and features are only negotiated on links
+            protocol: Protocol {
+                version: PROTOCOL_VERSION,
+                capabilities: 0,
+            },
+            pending_message: first_message,
+        }
+    }
+
+    /// Wraps a request over a link into a connection.
+    pub(super) fn over_link(
+        request: LinkRequest,
+        address: SocketAddr,
+        local_address: SocketAddr,
+        principal: Option<Principal>,
+        protocol: Protocol,
+    ) -> Connection {
+        Connection {
+            address,
+            local_address,
+            transport: Transport::Link(request),
+            deadline: Instant::now() + settings().connection_timeout,
+            principal,
+            protocol,
+            pending_message: None,
+        }
+    }
+
+    /// Open and return a new connection with another process and send the given message.
+    /// Every message of the connection carries its checksum unless the other process is a node
+    /// too old to support checksums on plain connections.
+    /// Connections to other nodes of the system should be opened with `ConnectionPool` instead.
+    pub async fn new(
+        peer_ip_address: IpAddr,
+        peer_port: u16,
+        message: &Message,
+    ) -> Result<Connection, ConnectionError> {
+        let peer_address = SocketAddr::new(peer_ip_address, peer_port);
+
+        let mut connection = match Connection::open_with_checksums(peer_address).await? {
+            Some(connection) => connection,
+            None => {
+                let (stream, local_address) = connect(peer_address).await?;
+                Connection::accepted(stream, peer_address, local_address, None, None)
+            }
+        };
+        connection.write(message).await?;
+
+        Ok(connection)
+    }
+
+    /// Opens a connection whose messages carry their checksums.
+    /// Returns `None` if the other process does not know the request to enable checksums.
+    async fn open_with_checksums(
+        peer_address: SocketAddr,
+    ) -> Result<Option<Connection>, ConnectionError> {
+        let (stream, local_address) = connect(peer_address).await?;
+        let mut connection = Connection::accepted(stream, peer_address, local_address, None, None);
+        connection.write(&Message::EnableChecksums).await?;
+
+        match connection.read_message().await {
+            Ok(message) if message.is_ok() => {
+                connection.enable_frame_checksums();
+                Ok(Some(connection))
+            }
+            // an older node answers the unknown message type with an error or closes
+            Ok(Message::Error {
+                code: ErrorCode::BadRequest,
+                message,
+            }) if message == DecodeError::UnknownType(ENABLE_CHECKSUMS_TYPE).to_string() => {
+                Ok(None)
+            }
+            Err(ConnectionError::Closed) => Ok(None),
+            Ok(message) => Err(ConnectionError::UnexpectedMessage(message.message_type())),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Returns the IP address of this end of the connection.
+    pub fn local_ip_address(&self) -> IpAddr {
+        self.local_address.ip()
+    }
+
+    /// Returns `true` if the other end sends and accepts bulk transfers split into chunks.
+    pub fn supports_chunked_transfers(&self) -> bool {
+        self.protocol.capabilities & CHUNKED_TRANSFERS != 0
+    }
+
+    /// Returns `true` if the other end acknowledges a leader transfer once it has stored the transferred pairs.
+    pub fn acknowledges_transfers(&self) -> bool {
+        self.protocol.version >= TRANSFER_ACK_PROTOCOL_VERSION
+    }
+
+    /// Returns the protocol version whose formats the messages of this connection use.
+    pub(super) fn protocol_version(&self) -> u16 {
+        self.protocol.version
+    }
+
+    /// Checks whether the sender of this connection may send the given request.
+    /// Returns the reason if it may not.
+    pub fn authorize(&self, message: &Message) -> Result<(), &'static str> {
+        auth::authorize(self.principal.as_ref(), message)
+    }
+
+    /// Reads and decodes the next message from the stream.
+    /// Fails if the message is malformed, too long or does not arrive in time.
+    pub async fn read_message(&mut self) -> Result<Message, ConnectionError> {
+        if let Some(first_message) = self.pending_message.take() {
+            return first_message;
+        }
+
+        let read_deadline = self.deadline.min(Instant::now() + settings().read_timeout);
+
+        timeout_at(read_deadline, self.read_frame())
+            .await
+            .map_err(|_| ConnectionError::Timeout)?
+    }
+
+    async fn read_frame(&mut self) -> Result<Message, ConnectionError> {
+        let has_checksums = self.has_frame_checksums();
+        let frame = match &mut self.transport {
+            Transport::Stream(stream) => read_stream_frame(stream, has_checksums).await?,
+            Transport::Link(request) => {
+                let mut frame = request.receive().await?;
+                if has_checksums {
+                    verify_checksum(&mut frame)?;
+                }
+                frame
+            }
+        };
+
+        Ok(Message::decode_for(&frame, self.protocol.version)?)
+    }
+
+    /// Returns `true` if every message of this connection is followed by its checksum.
+    fn has_frame_checksums(&self) -> bool {
+        self.protocol.capabilities & FRAME_CHECKSUMS != 0
+    }
+
+    /// Follows every message of this connection with its checksum from now on,
+    /// once both ends of a plain connection have agreed to.
+    pub(super) fn enable_frame_checksums(&mut self) {
+        self.protocol.capabilities |= FRAME_CHECKSUMS;
+    }
+
+    /// Sends an error response with the given code and message to the connection stream.
+    pub async fn send_error(&mut self, code: ErrorCode, message: &str) -> bool {
+        self.send_message(&Message::error(code, message)).await
+    }
+
+    /// Sends the given message to the connection stream.
+    /// Returns `true` if the message was sent, `false` otherwise.
+    pub async fn send_message(&mut self, message: &Message) -> bool {
+        match self.write(message).await {
+            Ok(()) => true,
+            Err(error) => {
+                println!("failed to send message to {} ({})", self.address, error);
+                false
+            }
+        }
+    }
+
+    /// Fails without sending anything if the message cannot be expressed in the protocol version of the connection.
+    pub(super) async fn write(&mut self, message: &Message) -> Result<(), ConnectionError> {
+        if message.min_protocol_version() > self.protocol.version {
+            return Err(ConnectionError::IncompatibleVersion(self.protocol.version));
+        }
+
+        let mut frame = message.encode_for(self.protocol.version);
+        if self.has_frame_checksums() {
+            let checksum = crc32c::crc32c(&frame);
+            frame.extend_from_slice(&checksum.to_be_bytes());
+        }
+
+        match &mut self.transport {
+            Transport::Stream(stream) => {
+                timeout_at(self.deadline, async {
+                    stream.write_all(&frame).await?;
+                    stream.flush().await
+                })
+                .await
+                .map_err(|_| ConnectionError::Timeout)??;
+            }
+            Transport::Link(request) => {
+                // with flow control, waits until the other end has read the earlier messages
+                timeout_at(self.deadline, request.send(frame))
+                    .await
+                    .map_err(|_| ConnectionError::Timeout)??;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Opens a connection to the given address within the read timeout, secured with TLS if enabled
+/// and authenticated with the cluster secret if there is one. Returns the stream and the local address of the connection.
+pub(super) async fn connect(address: SocketAddr) -> Result<(Stream, SocketAddr), ConnectionError> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    let connect_deadline = Instant::now() + settings().read_timeout;
+    let stream = timeout_at(connect_deadline, socket.connect(address))
+        .await
+        .map_err(|_| ConnectionError::Timeout)??;
+    let local_address = stream.local_addr()?;
+
+    let mut stream = timeout_at(connect_deadline, secure_outgoing(stream, address.ip()))
+        .await
+        .map_err(|_| ConnectionError::Timeout)??;
+
+    if let Some(authentication) = auth::node_authentication() {
+        timeout_at(connect_deadline, async {
+            stream.write_all(&authentication.encode()).await?;
+            stream.flush().await
+        })
+        .await
+        .map_err(|_| ConnectionError::Timeout)??;
+    }
+    Ok((stream, local_address))
+}
+
+/// Removes the checksum trailer from the given frame and checks that it matches the rest of the frame.
+fn verify_checksum(frame: &mut Vec<u8>) -> Result<(), ConnectionError> {
+    if frame.len() < CHECKSUM_LENGTH {
+        return Err(DecodeError::TooShort.into());
+    }
+    let trailer = frame.split_off(frame.len() - CHECKSUM_LENGTH);
+    let checksum = u32::from_be_bytes(trailer.try_into().unwrap());
+
+    if crc32c::crc32c(frame) != checksum {
+        return Err(ConnectionError::ChecksumMismatch);
+    }
+    Ok(())
+}
+
+/// Reads the bytes of the next complete message on a plain stream like `read_frame`,
+/// followed by its checksum if asked, which is checked and removed.
+pub(super) async fn read_stream_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    frame_checksums: bool,
+) -> Result<Vec<u8>, ConnectionError> {
+    let mut frame = read_frame(reader).await?;
+    if frame_checksums {
+        let message_length = frame.len();
+        frame.resize(message_length + CHECKSUM_LENGTH, 0);
+        reader.read_exact(&mut frame[message_length..]).await?;
+        verify_checksum(&mut frame)?;
+    }
+    Ok(frame)
+}
+
+/// Reads the bytes of the next complete message, header included, from the given reader.
+/// Fails if the header announces a length shorter than the header or longer than the maximum.
+pub(super) async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Vec<u8>, ConnectionError> {
+    let mut header = [0u8; HEADER_LENGTH];
+
+    reader.read_exact(&mut header).await?;
+
+    let message_length = u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize;
+    if message_length < HEADER_LENGTH {
+        return Err(DecodeError::TooShort.into());
+    }
+    let max = settings().max_message_length;
+    if message_length > max {
+        return Err(ConnectionError::MessageTooLong {
+            length: message_length,
+            max,
+        });
+    }
+
+    let mut frame = vec![0; message_length];
+    frame[..HEADER_LENGTH].copy_from_slice(&header);
+
+    reader.read_exact(&mut frame[HEADER_LENGTH..]).await?;
+
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Returns a connection whose other end has written the given bytes and closed.
+    async fn connection_receiving(bytes: &[u8]) -> Connection {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let mut sender = TcpStream::connect(address).await.unwrap();
+        sender.write_all(bytes).await.unwrap();
+        drop(sender);
+
+        let (stream, address) = listener.accept().await.unwrap();
+        let local_address = stream.local_addr().unwrap();
+        Connection::accepted(Box::new(stream), address, local_address, None, None)
+    }
+
+    #[tokio::test]
+    async fn valid_message_reading() {
+        let mut connection = connection_receiving(&[0, 0, 0, 0, 7, 111, 107]).await;
+        assert!(connection.read_message().await.unwrap().is_ok());
+        assert!(matches!(
+            connection.read_message().await,
+            Err(ConnectionError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_message_reading() {
+        let mut connection = connection_receiving(&[0, 0, 0, 0, 9, 1]).await;
+        assert!(matches!(
+            connection.read_message().await,
+            Err(ConnectionError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn invalid_length_reading() {
+        let mut connection = connection_receiving(&[0, 0, 0, 0, 3]).await;
+        assert!(matches!(
+            connection.read_message().await,
+            Err(ConnectionError::Decode(DecodeError::TooShort))
+        ));
+
+        let mut connection = connection_receiving(&[0, 255, 255, 255, 255]).await;
+        assert!(matches!(
+            connection.read_message().await,
+            Err(ConnectionError::MessageTooLong { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn plain_connection_checksums() {
+        let mut frame = Message::ok().encode();
+        frame.extend_from_slice(&crc32c::crc32c(&frame).to_be_bytes());
+
+        let mut connection = connection_receiving(&frame).await;
+        connection.enable_frame_checksums();
+        assert!(connection.read_message().await.unwrap().is_ok());
+
+        frame[5] ^= 1;
+        let mut connection = connection_receiving(&frame).await;
+        connection.enable_frame_checksums();
+        assert!(matches!(
+            connection.read_message().await,
+            Err(ConnectionError::ChecksumMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn plain_connection_without_checksum_support() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // answers the request to enable checksums like a node predating it, then reads a request without checksum
+        tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_frame(&mut stream).await.unwrap();
+            let error = DecodeError::UnknownType(ENABLE_CHECKSUMS_TYPE);
+            let refusal = Message::error(ErrorCode::BadRequest, &error.to_string());
+            stream.write_all(&refusal.encode()).await.unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let frame = read_frame(&mut stream).await.unwrap();
+            assert_eq!(Message::decode(&frame).unwrap(), Message::NodeListRequest);
+            stream.write_all(&Message::ok().encode()).await.unwrap();
+        });
+
+        let mut connection =
+            Connection::new(address.ip(), address.port(), &Message::NodeListRequest)
+                .await
+                .unwrap();
+        assert!(!connection.has_frame_checksums());
+        assert!(connection.read_message().await.unwrap().is_ok());
+    }
+
+    #[test]
+    fn checksum_verification() {
+        let mut frame = Message::ok().encode();
+        let checksum = crc32c::crc32c(&frame);
+        frame.extend_from_slice(&checksum.to_be_bytes());
+
+        let mut valid = frame.clone();
+        assert!(verify_checksum(&mut valid).is_ok());
+        assert_eq!(valid, Message::ok().encode());
+
+        let mut corrupted = frame.clone();
+        corrupted[5] ^= 1;
+        assert!(matches!(
+            verify_checksum(&mut corrupted),
+            Err(ConnectionError::ChecksumMismatch)
+        ));
+
+        assert!(verify_checksum(&mut vec![0, 1]).is_err());
+    }
+}
@@ -0,0 +1,858 @@
+use super::auth::{self, Principal};
+use super::connection::{connect, read_frame, settings, Connection, ConnectionError};
+use super::message::{DecodeError, ErrorCode, Message, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use super::tls::Stream;
+use crate::PeerNode;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::timeout;
+
+/// The protocol version of the nodes predating links, whose formats the first version kept.
+const LEGACY_PROTOCOL_VERSION: u16 = 1;
+/// The message type of the request to open a link.
+const OPEN_LINK_TYPE: u8 = 14;
+/// Capability of sending the key-value pairs of bulk transfers in bounded chunks.
+pub(super) const CHUNKED_TRANSFERS: u64 = 1 << 0;
+/// Capability of following every message on a link with its CRC32C checksum.
+pub(super) const FRAME_CHECKSUMS: u64 = 1 << 1;
+/// Capability of limiting the messages of a request sent ahead of the other end reading them.
+pub(super) const FLOW_CONTROL: u64 = 1 << 2;
+/// Bitmask of the optional protocol features that this node supports.
+const CAPABILITIES: u64 = CHUNKED_TRANSFERS | FRAME_CHECKSUMS | FLOW_CONTROL;
+
+/// Number of messages of a request that may be sent on a link with flow control
+/// before the other end has read them, so that a bulk transfer holds at most this many chunks in memory.
+const REQUEST_WINDOW: usize = 4;
+
+/// Length of the checksum that follows a message on links with frame checksums.
+pub(super) const CHECKSUM_LENGTH: usize = 4;
+
+/// Kind of a link frame that carries a message of a request.
+const DATA_FRAME: u8 = 0;
+/// Kind of a link frame telling that the sender has finished with a request.
+const CLOSE_FRAME: u8 = 1;
+/// Kind of a link frame telling that the sender has read a message of a request,
+/// so that the other end may send one more.
+const CREDIT_FRAME: u8 = 2;
+
+/// Requests open on a link by their ID, or `None` after the link has closed.
+type Requests = std::sync::Mutex<Option<HashMap<u64, OpenRequest>>>;
+
+/// The channels of a request open on a link.
+struct OpenRequest {
+    /// Passes the received message frames to the request.
+    incoming: mpsc::UnboundedSender<Vec<u8>>,
+    /// The messages that the request may still send before the other end reads more of them.
+    credits: Arc<Semaphore>,
+}
+
+/// A frame received on a link, without the ID of its request.
+enum LinkFrame {
+    Data(Vec<u8>),
+    Close,
+    Credit,
+}
+
+/// The way of communicating with one node. Locked while connecting.
+type LinkSlot = Arc<tokio::sync::Mutex<PeerLink>>;
+
+/// The state of the communication with one node in the pool.
+enum PeerLink {
+    /// No link has been opened yet, or the previous one has closed.
+    NotConnected,
+    Connected(Arc<Link>),
+    /// The node predates links, so its protocol version is too old to communicate with.
+    Unsupported,
+}
+
+/// The protocol version and optional capabilities that both ends of a link support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Protocol {
+    pub version: u16,
+    pub capabilities: u64,
+}
+
+impl Protocol {
+    /// Returns `true` if the messages of a request wait for the other end to read the earlier ones.
+    fn has_flow_control(&self) -> bool {
+        self.capabilities & FLOW_CONTROL != 0
+    }
+
+    /// Returns the protocol to use with another node announcing the given version and capabilities,
+    /// or `None` if the other node is too old to communicate with.
+    fn negotiate(version: u16, capabilities: u64) -> Option<Protocol> {
+        if version < MIN_PROTOCOL_VERSION {
+            return None;
+        }
+        Some(Protocol {
+            version: version.min(PROTOCOL_VERSION),
+            capabilities: capabilities & CAPABILITIES,
+        })
+    }
+
+    /// Returns the length of the trailer that follows every message on the link.
+    fn trailer_length(&self) -> usize {
+        if self.capabilities & FRAME_CHECKSUMS != 0 {
+            CHECKSUM_LENGTH
+        } else {
+            0
+        }
+    }
+}
+
+/// Returns the message that a node sends when opening a link or accepting one.
+fn open_link_message() -> Message {
+    Message::OpenLink {
+        version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES,
+    }
+}
+
+/// A persistent TCP connection with another node that carries many concurrent requests.
+/// Every frame on a link is prefixed with the ID of the request it belongs to.
+pub(super) struct Link {
+    address: SocketAddr,
+    local_address: SocketAddr,
+    protocol: Protocol,
+    requests: Arc<Requests>,
+    writer: mpsc::UnboundedSender<Vec<u8>>,
+    next_request_id: AtomicU64,
+}
+
+/// One request multiplexed over a link, seen from either end of the link.
+pub(super) struct LinkRequest {
+    id: u64,
+    incoming: mpsc::UnboundedReceiver<Vec<u8>>,
+    requests: Arc<Requests>,
+    writer: mpsc::UnboundedSender<Vec<u8>>,
+    /// The credits of sending messages, `None` if the link has no flow control.
+    credits: Option<Arc<Semaphore>>,
+}
+
+impl Link {
+    /// Opens a new link to the node at the given address.
+    /// Returns `None` if the node is too old to support links,
+    /// and an incompatible version error if either node refuses the protocol version of the other.
+    async fn open(address: SocketAddr) -> Result<Option<Link>, ConnectionError> {
+        let (mut stream, local_address) = connect(address).await?;
+        write_and_flush(&mut stream, &open_link_message().encode()).await?;
+
+        let reply = match timeout(settings().read_timeout, read_frame(&mut stream)).await {
+            Ok(Ok(frame)) => Message::decode(&frame)?,
+            Ok(Err(ConnectionError::Closed)) => return Ok(None),
+            Ok(Err(error)) => return Err(error),
+            Err(_) => return Err(ConnectionError::Timeout),
+        };
+        let protocol = match reply {
+            Message::OpenLink {
+                version,
+                capabilities,
+            } => match Protocol::negotiate(version, capabilities) {
+                Some(protocol) => protocol,
+                None => return Err(ConnectionError::IncompatibleVersion(version)),
+            },
+            Message::Error {
+                code: ErrorCode::Unauthorized,
+                message,
+            } => return Err(ConnectionError::Unauthorized(message)),
+            // a node without links answers the unknown message type with an error or closes
+            Message::Error {
+                code: ErrorCode::BadRequest,
+                message,
+            } if message == DecodeError::UnknownType(OPEN_LINK_TYPE).to_string() => {
+                return Ok(None)
+            }
+            // a node with links refuses a version it no longer supports
+            Message::Error {
+                code: ErrorCode::BadRequest,
+                ..
+            } => return Err(ConnectionError::IncompatibleVersion(PROTOCOL_VERSION)),
+            message => return Err(ConnectionError::UnexpectedMessage(message.message_type())),
+        };
+
+        println!(
+            "opened link to {} using protocol version {} capabilities {:#x}",
+            address, protocol.version, protocol.capabilities
+        );
+
+        let (mut read_half, write_half) = tokio::io::split(stream);
+
+        let requests = Arc::new(std::sync::Mutex::new(Some(HashMap::new())));
+        let writer = spawn_writer(write_half, Arc::clone(&requests));
+
+        let reader_requests = Arc::clone(&requests);
+        tokio::task::spawn(async move {
+            loop {
+                let (request_id, frame) =
+                    match read_link_frame(&mut read_half, protocol.trailer_length()).await {
+                        Ok(link_frame) => link_frame,
+                        Err(ConnectionError::Closed) => break,
+                        Err(error) => {
+                            println!("link to {} closed ({})", address, error);
+                            break;
+                        }
+                    };
+                match frame {
+                    LinkFrame::Data(frame) => deliver(&reader_requests, request_id, frame),
+                    LinkFrame::Close => remove_request(&reader_requests, request_id),
+                    LinkFrame::Credit => grant_credit(&reader_requests, request_id),
+                }
+            }
+            close(&reader_requests);
+        });
+
+        Ok(Some(Link {
+            address,
+            local_address,
+            protocol,
+            requests,
+            writer,
+            next_request_id: AtomicU64::new(1),
+        }))
+    }
+
+    /// Returns `true` if the link can no longer carry requests.
+    fn is_closed(&self) -> bool {
+        self.requests.lock().unwrap().is_none()
+    }
+
+    /// Starts a new request over this link and sends the given message as its first message.
+    /// Fails without starting the request if the message cannot be expressed in the protocol version of the link.
+    async fn request(&self, message: &Message) -> Result<Connection, ConnectionError> {
+        if message.min_protocol_version() > self.protocol.version {
+            return Err(ConnectionError::IncompatibleVersion(self.protocol.version));
+        }
+
+        let id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let request = register_request(id, &self.requests, &self.writer, self.protocol)?;
+
+        let mut connection = Connection::over_link(
+            request,
+            self.address,
+            self.local_address,
+            None,
+            self.protocol,
+        );
+        connection.write(message).await?;
+
+        Ok(connection)
+    }
+}
+
+impl LinkRequest {
+    /// Waits for the next message frame of this request.
+    /// With flow control, lets the other end send one more message in its place.
+    pub(super) async fn receive(&mut self) -> Result<Vec<u8>, ConnectionError> {
+        let frame = self.incoming.recv().await.ok_or(ConnectionError::Closed)?;
+        if self.credits.is_some() {
+            let mut link_frame = self.id.to_be_bytes().to_vec();
+            link_frame.push(CREDIT_FRAME);
+            let _ = self.writer.send(link_frame);
+        }
+        Ok(frame)
+    }
+
+    /// Sends the given message frame as a part of this request.
+    /// With flow control, first waits until the other end has read enough of the earlier messages,
+    /// and fails if the other end finishes the request meanwhile.
+    pub(super) async fn send(&self, frame: Vec<u8>) -> Result<(), ConnectionError> {
+        if let Some(credits) = &self.credits {
+            credits
+                .acquire()
+                .await
+                .map_err(|_| ConnectionError::Closed)?
+                .forget();
+        }
+
+        let mut link_frame = Vec::with_capacity(9 + frame.len());
+        link_frame.extend_from_slice(&self.id.to_be_bytes());
+        link_frame.push(DATA_FRAME);
+        link_frame.extend_from_slice(&frame);
+
+        self.writer
+            .send(link_frame)
+            .map_err(|_| ConnectionError::Closed)
+    }
+}
+
+impl Drop for LinkRequest {
+    fn drop(&mut self) {
+        remove_request(&self.requests, self.id);
+
+        let mut link_frame = self.id.to_be_bytes().to_vec();
+        link_frame.push(CLOSE_FRAME);
+        let _ = self.writer.send(link_frame);
+    }
+}
+
+/// A request from another node to open a link.
+pub(super) struct IncomingLink {
+    pub address: SocketAddr,
+    pub local_address: SocketAddr,
+    /// The protocol version announced by the other node.
+    pub version: u16,
+    /// The capabilities announced by the other node.
+    pub capabilities: u64,
+    /// The authenticated sender of the link, inherited by all requests on it.
+    pub principal: Option<Principal>,
+}
+
+/// Serves a link opened by another node over the given stream.
+/// Sends a `Connection` to the given channel for every new request on the link.
+pub(super) async fn serve_link(
+    mut stream: Stream,
+    incoming_link: IncomingLink,
+    incoming_connections: mpsc::UnboundedSender<Connection>,
+) {
+    let IncomingLink {
+        address,
+        local_address,
+        version,
+        capabilities,
+        principal,
+    } = incoming_link;
+
+    if auth::node_authentication_required() && principal != Some(Principal::Node) {
+        println!("refusing link from {} without node authentication", address);
+        let refusal = Message::error(ErrorCode::Unauthorized, "links require node authentication");
+        let _ = write_and_flush(&mut stream, &refusal.encode()).await;
+        return;
+    }
+
+    let protocol = match Protocol::negotiate(version, capabilities) {
+        Some(protocol) => protocol,
+        None => {
+            println!(
+                "refusing link from {} with protocol version {}",
+                address, version
+            );
+            let refusal = Message::error(
+                ErrorCode::BadRequest,
+                &format!("protocol version {} is not supported", version),
+            );
+            let _ = write_and_flush(&mut stream, &refusal.encode()).await;
+            return;
+        }
+    };
+    if let Err(error) = write_and_flush(&mut stream, &open_link_message().encode()).await {
+        println!("failed to accept link from {} ({})", address, error);
+        return;
+    }
+
+    println!(
+        "accepted link from {} using protocol version {} capabilities {:#x}",
+        address, protocol.version, protocol.capabilities
+    );
+
+    let (mut read_half, write_half) = tokio::io::split(stream);
+
+    let requests = Arc::new(std::sync::Mutex::new(Some(HashMap::new())));
+    let writer = spawn_writer(write_half, Arc::clone(&requests));
+
+    // request IDs grow on the opening side, so frames of finished requests are never mistaken for new ones
+    let mut greatest_request_id = 0;
+
+    loop {
+        let (request_id, frame) =
+            match read_link_frame(&mut read_half, protocol.trailer_length()).await {
+                Ok(link_frame) => link_frame,
+                Err(ConnectionError::Closed) => break,
+                Err(error) => {
+                    println!("link from {} closed ({})", address, error);
+                    break;
+                }
+            };
+
+        match frame {
+            LinkFrame::Data(frame) if request_id > greatest_request_id => {
+                greatest_request_id = request_id;
+                let request = match register_request(request_id, &requests, &writer, protocol) {
+                    Ok(request) => request,
+                    Err(_) => break,
+                };
+                deliver(&requests, request_id, frame);
+                let connection = Connection::over_link(
+                    request,
+                    address,
+                    local_address,
+                    principal.clone(),
+                    protocol,
+                );
+                if incoming_connections.send(connection).is_err() {
+                    break;
+                }
+            }
+            LinkFrame::Data(frame) => deliver(&requests, request_id, frame),
+            LinkFrame::Close => remove_request(&requests, request_id),
+            LinkFrame::Credit => grant_credit(&requests, request_id),
+        }
+    }
+
+    close(&requests);
+}
+
+/// Spawns a task that writes the frames sent to the returned channel to the given stream.
+/// With flow control, the channel holds at most the window of messages of each open request
+/// besides the small close and credit frames.
+/// The link is closed if writing fails.
+/// The stream is shut down once all senders of the channel have been dropped.
+fn spawn_writer(
+    mut write_half: WriteHalf<Stream>,
+    requests: Arc<Requests>,
+) -> mpsc::UnboundedSender<Vec<u8>> {
+    let (writer, mut outgoing_frames) = mpsc::unbounded_channel::<Vec<u8>>();
+
+    tokio::task::spawn(async move {
+        while let Some(link_frame) = outgoing_frames.recv().await {
+            if write_and_flush(&mut write_half, &link_frame).await.is_err() {
+                close(&requests);
+                return;
+            }
+        }
+        let _ = write_half.shutdown().await;
+    });
+
+    writer
+}
+
+async fn write_and_flush<W: AsyncWrite + Unpin>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(bytes).await?;
+    writer.flush().await
+}
+
+/// Reads the next link frame, including the given length of trailer after a message.
+/// Returns the request ID and the frame, a data frame with its trailer.
+async fn read_link_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    trailer_length: usize,
+) -> Result<(u64, LinkFrame), ConnectionError> {
+    let request_id = reader.read_u64().await?;
+
+    match reader.read_u8().await? {
+        DATA_FRAME => {
+            let mut frame = read_frame(reader).await?;
+            let message_length = frame.len();
+            frame.resize(message_length + trailer_length, 0);
+            reader.read_exact(&mut frame[message_length..]).await?;
+            Ok((request_id, LinkFrame::Data(frame)))
+        }
+        CLOSE_FRAME => Ok((request_id, LinkFrame::Close)),
+        CREDIT_FRAME => Ok((request_id, LinkFrame::Credit)),
+        kind => Err(DecodeError::UnknownType(kind).into()),
+    }
+}
+
+/// Registers a new request with the given ID to the link of the given protocol.
+fn register_request(
+    id: u64,
+    requests: &Arc<Requests>,
+    writer: &mpsc::UnboundedSender<Vec<u8>>,
+    protocol: Protocol,
+) -> Result<LinkRequest, ConnectionError> {
+    let (sender, incoming) = mpsc::unbounded_channel();
+    let credits = Arc::new(Semaphore::new(REQUEST_WINDOW));
+
+    match requests.lock().unwrap().as_mut() {
+        Some(open_requests) => open_requests.insert(
+            id,
+            OpenRequest {
+                incoming: sender,
+                credits: Arc::clone(&credits),
+            },
+        ),
+        None => return Err(ConnectionError::Closed),
+    };
+
+    Ok(LinkRequest {
+        id,
+        incoming,
+        requests: Arc::clone(requests),
+        writer: writer.clone(),
+        credits: protocol.has_flow_control().then_some(credits),
+    })
+}
+
+/// Passes the given message frame to the request with the given ID, if it is still open.
+fn deliver(requests: &Requests, request_id: u64, frame: Vec<u8>) {
+    if let Some(open_requests) = requests.lock().unwrap().as_ref() {
+        if let Some(request) = open_requests.get(&request_id) {
+            let _ = request.incoming.send(frame);
+        }
+    }
+}
+
+/// Lets the request with the given ID send one more message, if it is still open.
+fn grant_credit(requests: &Requests, request_id: u64) {
+    if let Some(open_requests) = requests.lock().unwrap().as_ref() {
+        if let Some(request) = open_requests.get(&request_id) {
+            request.credits.add_permits(1);
+        }
+    }
+}
+
+/// Removes the request with the given ID, failing its sends that wait for credits.
+fn remove_request(requests: &Requests, request_id: u64) {
+    if let Some(open_requests) = requests.lock().unwrap().as_mut() {
+        if let Some(request) = open_requests.remove(&request_id) {
+            request.credits.close();
+        }
+    }
+}
+
+/// Marks the link closed, which ends all of its open requests.
+fn close(requests: &Requests) {
+    if let Some(open_requests) = requests.lock().unwrap().take() {
+        for request in open_requests.into_values() {
+            request.credits.close();
+        }
+    }
+}
+
+/// Persistent links to other nodes, shared by all blocks of this node.
+pub struct ConnectionPool {
+    links: std::sync::Mutex<HashMap<PeerNode, LinkSlot>>,
+}
+
+impl ConnectionPool {
+    pub fn new() -> ConnectionPool {
+        ConnectionPool {
+            links: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts a new conversation with the given node and sends the given message to it.
+    /// Reuses the link to the node if there is one, and opens a new one otherwise.
+    /// Fails with an incompatible version error if the node does not support links,
+    /// since it predates the formats that this node can convert its messages to.
+    pub async fn open(
+        &self,
+        node: &PeerNode,
+        message: &Message,
+    ) -> Result<Connection, ConnectionError> {
+        match self.link(node).await? {
+            Some(link) => link.request(message).await,
+            None => Err(ConnectionError::IncompatibleVersion(
+                LEGACY_PROTOCOL_VERSION,
+            )),
+        }
+    }
+
+    /// Returns `true` if the given node accepts bulk transfers split into chunks.
+    /// Opens a link to the node if there is none.
+    pub async fn supports_chunked_transfers(&self, node: &PeerNode) -> bool {
+        match self.link(node).await {
+            Ok(Some(link)) => link.protocol.capabilities & CHUNKED_TRANSFERS != 0,
+            _ => false,
+        }
+    }
+
+    /// Returns the open link to the given node, opening one if necessary,
+    /// or `None` if the node does not support links.
+    async fn link(&self, node: &PeerNode) -> Result<Option<Arc<Link>>, ConnectionError> {
+        let slot = {
+            let mut links = self.links.lock().unwrap();
+            let slot = links
+                .entry(node.clone())
+                .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(PeerLink::NotConnected)));
+            Arc::clone(slot)
+        };
+
+        let mut slot = slot.lock().await;
+        match &*slot {
+            PeerLink::Connected(link) if !link.is_closed() => Ok(Some(Arc::clone(link))),
+            PeerLink::Unsupported => Ok(None),
+            _ => {
+                let address = SocketAddr::new(node.ip_address, node.port);
+                match Link::open(address).await? {
+                    Some(link) => {
+                        let link = Arc::new(link);
+                        *slot = PeerLink::Connected(Arc::clone(&link));
+                        Ok(Some(link))
+                    }
+                    None => {
+                        println!(
+                            "node ID={} does not support links, refusing its protocol version {}",
+                            node.id, LEGACY_PROTOCOL_VERSION
+                        );
+                        *slot = PeerLink::Unsupported;
+                        Ok(None)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Closes the link to the node with the given ID, if there is one.
+    pub fn forget(&self, node_id: u64) {
+        let mut links = self.links.lock().unwrap();
+        links.retain(|node, _| node.id != node_id);
+    }
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        ConnectionPool::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// How a test node answers a request to open a link.
+    #[derive(Clone, Copy, PartialEq)]
+    enum LinkSupport {
+        /// Accepts the link.
+        Supported,
+        /// Refuses the link like the nodes predating links.
+        Legacy,
+        /// Refuses the protocol version of the opening node like a node that no longer supports it.
+        NewerOnly,
+    }
+
+    /// Starts a listener that answers every request with the same message.
+    async fn start_echo_node(link_support: LinkSupport) -> PeerNode {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::task::spawn(async move {
+            while let Ok((mut stream, address)) = listener.accept().await {
+                let local_address = stream.local_addr().unwrap();
+                let (tx, mut rx) = mpsc::unbounded_channel::<Connection>();
+                tokio::task::spawn(async move {
+                    while let Some(mut connection) = rx.recv().await {
+                        tokio::task::spawn(async move {
+                            while let Ok(message) = connection.read_message().await {
+                                connection.send_message(&message).await;
+                            }
+                        });
+                    }
+                });
+
+                let first_message = Message::decode(&read_frame(&mut stream).await.unwrap());
+                match first_message.unwrap() {
+                    Message::OpenLink {
+                        version,
+                        capabilities,
+                    } if link_support == LinkSupport::Supported => {
+                        let incoming_link = IncomingLink {
+                            address,
+                            local_address,
+                            version,
+                            capabilities,
+                            principal: None,
+                        };
+                        tokio::task::spawn(serve_link(Box::new(stream), incoming_link, tx));
+                    }
+                    Message::OpenLink { version, .. } => {
+                        let reason = match link_support {
+                            LinkSupport::Legacy => DecodeError::UnknownType(14).to_string(),
+                            _ => format!("protocol version {} is not supported", version),
+                        };
+                        let refusal = Message::error(ErrorCode::BadRequest, &reason);
+                        stream.write_all(&refusal.encode()).await.unwrap();
+                    }
+                    message => {
+                        let connection = Connection::accepted(
+                            Box::new(stream),
+                            address,
+                            local_address,
+                            None,
+                            Some(Ok(message)),
+                        );
+                        tx.send(connection).unwrap();
+                    }
+                }
+            }
+        });
+
+        PeerNode {
+            id: 1,
+            ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+        }
+    }
+
+    /// Starts a node that accepts links and passes the requests on them to the returned channel unread.
+    async fn start_idle_node() -> (PeerNode, mpsc::UnboundedReceiver<Connection>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::unbounded_channel::<Connection>();
+
+        tokio::task::spawn(async move {
+            let (mut stream, address) = listener.accept().await.unwrap();
+            let local_address = stream.local_addr().unwrap();
+            let Message::OpenLink {
+                version,
+                capabilities,
+            } = Message::decode(&read_frame(&mut stream).await.unwrap()).unwrap()
+            else {
+                panic!("expected a request to open a link");
+            };
+            let incoming_link = IncomingLink {
+                address,
+                local_address,
+                version,
+                capabilities,
+                principal: None,
+            };
+            serve_link(Box::new(stream), incoming_link, tx).await;
+        });
+
+        let node = PeerNode {
+            id: 1,
+            ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+        };
+        (node, rx)
+    }
+
+    #[tokio::test]
+    async fn flow_control_over_link() {
+        let (node, mut incoming) = start_idle_node().await;
+        let pool = ConnectionPool::new();
+
+        let mut sending = pool.open(&node, &Message::BackupRequest).await.unwrap();
+        for _ in 1..REQUEST_WINDOW {
+            sending.write(&Message::ok()).await.unwrap();
+        }
+
+        // the window is full until the other end reads a message
+        let blocked = timeout(Duration::from_millis(100), sending.write(&Message::ok())).await;
+        assert!(blocked.is_err());
+
+        let mut receiving = incoming.recv().await.unwrap();
+        assert_eq!(
+            receiving.read_message().await.unwrap(),
+            Message::BackupRequest
+        );
+        timeout(Duration::from_secs(5), sending.write(&Message::ok()))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // a send waiting for credits fails once the other end finishes the request
+        let waiting = tokio::task::spawn(async move { sending.write(&Message::ok()).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(receiving);
+        assert!(matches!(
+            waiting.await.unwrap(),
+            Err(ConnectionError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_over_link() {
+        let node = start_echo_node(LinkSupport::Supported).await;
+        let pool = Arc::new(ConnectionPool::new());
+
+        let mut handles = Vec::new();
+        for key in 0..20 {
+            let pool = Arc::clone(&pool);
+            let node = node.clone();
+            handles.push(tokio::task::spawn(async move {
+                let mut connection = pool
+                    .open(&node, &Message::LeaderRead { key, at: None })
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    connection.read_message().await.unwrap(),
+                    Message::LeaderRead { key, at: None }
+                );
+                connection.send_message(&Message::ok()).await;
+                assert!(connection.read_message().await.unwrap().is_ok());
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(pool.links.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_forget() {
+        let node = start_echo_node(LinkSupport::Supported).await;
+        let pool = ConnectionPool::new();
+
+        let mut first = pool.open(&node, &Message::BackupRequest).await.unwrap();
+        assert_eq!(first.read_message().await.unwrap(), Message::BackupRequest);
+
+        pool.forget(node.id);
+        assert!(pool.links.lock().unwrap().is_empty());
+
+        // requests already open keep working on the forgotten link
+        first.send_message(&Message::ok()).await;
+        assert!(first.read_message().await.unwrap().is_ok());
+
+        let mut second = pool.open(&node, &Message::NodeListRequest).await.unwrap();
+        assert_eq!(
+            second.read_message().await.unwrap(),
+            Message::NodeListRequest
+        );
+    }
+
+    #[tokio::test]
+    async fn refusal_without_link_support() {
+        let node = start_echo_node(LinkSupport::Legacy).await;
+        let pool = ConnectionPool::new();
+
+        for key in 0..3 {
+            let result = pool
+                .open(&node, &Message::LeaderRead { key, at: None })
+                .await;
+            assert!(matches!(
+                result,
+                Err(ConnectionError::IncompatibleVersion(
+                    LEGACY_PROTOCOL_VERSION
+                ))
+            ));
+        }
+
+        let slot = Arc::clone(&pool.links.lock().unwrap()[&node]);
+        assert!(matches!(*slot.lock().await, PeerLink::Unsupported));
+    }
+
+    #[tokio::test]
+    async fn refused_protocol_version() {
+        let node = start_echo_node(LinkSupport::NewerOnly).await;
+        let pool = ConnectionPool::new();
+
+        let result = pool.open(&node, &Message::BackupRequest).await;
+        assert!(matches!(
+            result,
+            Err(ConnectionError::IncompatibleVersion(PROTOCOL_VERSION))
+        ));
+
+        // the refusal is not mistaken for a node predating links
+        let slot = Arc::clone(&pool.links.lock().unwrap()[&node]);
+        assert!(matches!(*slot.lock().await, PeerLink::NotConnected));
+    }
+
+    #[test]
+    fn protocol_negotiation() {
+        assert_eq!(
+            Protocol::negotiate(PROTOCOL_VERSION + 1, u64::MAX),
+            Some(Protocol {
+                version: PROTOCOL_VERSION,
+                capabilities: CAPABILITIES,
+            })
+        );
+        assert_eq!(
+            Protocol::negotiate(PROTOCOL_VERSION, 0),
+            Some(Protocol {
+                version: PROTOCOL_VERSION,
+                capabilities: 0,
+            })
+        );
+        assert_eq!(Protocol::negotiate(MIN_PROTOCOL_VERSION - 1, 0), None);
+    }
+}
@@ -0,0 +1,996 @@
+use crate::helpers::entry::{Entry, DELETED_FLAG};
+use crate::PeerNode;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+/// The length of the header (one message type + four total length) of every message.
+pub const HEADER_LENGTH: usize = 5;
+
+/// The length of the message authentication code in an authentication message.
+pub const MAC_LENGTH: usize = 32;
+
+/// The version of the formats described in `docs/messages.md` that this node speaks.
+pub(super) const PROTOCOL_VERSION: u16 = 9;
+/// The oldest protocol version of another node that this node can still communicate with,
+/// converting the messages to the formats of that version.
+pub(super) const MIN_PROTOCOL_VERSION: u16 = 6;
+/// The first protocol version with the write time and the history in value metadata
+/// and the read point in read requests.
+pub(super) const HISTORY_PROTOCOL_VERSION: u16 = 7;
+/// The first protocol version in which the end of a chunked transfer carries the version high-water mark.
+pub(super) const HIGH_WATER_PROTOCOL_VERSION: u16 = 8;
+/// The first protocol version in which the joining node acknowledges a leader transfer once it has stored it.
+pub(super) const TRANSFER_ACK_PROTOCOL_VERSION: u16 = 9;
+
+/// A message of the wire protocol described in `docs/messages.md`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Type `0`, a response whose meaning can be deduced from the previous messages.
+    Response(Vec<u8>),
+    /// Type `1`, read request from the communicating node to the leader,
+    /// with the point of the history of the key to read if not the current value.
+    LeaderRead { key: u64, at: Option<ReadPoint> },
+    /// Type `2`, write request from the communicating node to the leader,
+    /// with the time to live of the new value if it expires.
+    LeaderWrite { key: u64, ttl: Option<Duration> },
+    /// Type `3`, delete request from the communicating node to the leader.
+    LeaderDelete { key: u64 },
+    /// Type `10`, request for the list of nodes in the system.
+    NodeListRequest,
+    /// Type `11`, request to remove and respond a range of primary key-value pairs.
+    LeaderTransferRequest { key_range: RangeInclusive<u64> },
+    /// Type `12`, request for a copy of all primary key-value pairs.
+    BackupRequest,
+    /// Type `13`, announcement that a new node reachable at the given address has joined the ring.
+    JoinAnnouncement {
+        node_id: u64,
+        ip_address: IpAddr,
+        port: u16,
+    },
+    /// Type `14`, request to turn the connection into a persistent link between nodes,
+    /// and its response, with the protocol version and optional capabilities of the sender.
+    OpenLink { version: u16, capabilities: u64 },
+    /// Type `15`, proof that the sender knows the secret of the given client,
+    /// or the cluster secret if no client is given.
+    Authenticate {
+        client: Option<String>,
+        timestamp: u64,
+        nonce: u64,
+        mac: Vec<u8>,
+    },
+    /// Type `16`, request to follow every later message on a plain connection with its CRC32C checksum.
+    EnableChecksums,
+    /// Type `20`, request to write a single key-value pair to the backup storage.
+    BackupWrite { key: u64, entry: Entry },
+    /// Type `21`, request to write an array of key-value pairs to the backup storage.
+    BackupArrayWrite { kv_pairs: Vec<(u64, Entry)> },
+    /// Type `30`, information that the smaller neighbor of the receiver is down.
+    NeighborDown { node_id: u64 },
+    /// Type `31`, announcement that a node has left the ring.
+    PeerDown { node_id: u64 },
+    /// Type `200`, read request from a client,
+    /// with the point of the history of the key to read if not the current value.
+    ClientRead { key: u64, at: Option<ReadPoint> },
+    /// Type `202`, write request from a client, with the time to live of the new value if it expires.
+    ClientWrite { key: u64, ttl: Option<Duration> },
+    /// Type `204`, request for the memory usage of the node.
+    UsageRequest,
+    /// Type `206`, delete request from a client.
+    ClientDelete { key: u64 },
+    /// Type `208`, write command of a client sent in place of the new value,
+    /// writing the new value only if the current value of the key matches the condition.
+    CompareAndSwap {
+        condition: WriteCondition,
+        value: Vec<u8>,
+    },
+    /// Type `210`, request to export the leader storage of the node, and its backup storage if asked.
+    ExportRequest { include_backups: bool },
+    /// Type `255`, response telling that the request could not be served.
+    Error { code: ErrorCode, message: String },
+}
+
+/// The condition on the current value of a key under which a compare-and-swap writes the new value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteCondition {
+    /// The key has the given version.
+    Version(u64),
+    /// The key has the given value.
+    Value(Vec<u8>),
+    /// The key has no value.
+    Absent,
+}
+
+/// The point of the history of a key at which a read reads the value of the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadPoint {
+    /// The value written with the given version.
+    Version(u64),
+    /// The value at the given time in milliseconds since the Unix epoch.
+    Time(u64),
+}
+
+/// The reason carried by an error response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request was malformed or not expected at this point of the conversation.
+    BadRequest,
+    /// The node responsible for the requested key range could not be reached.
+    Unavailable,
+    /// The node failed to serve an otherwise valid request.
+    Internal,
+    /// The sender is not authenticated for the request.
+    Unauthorized,
+    /// A value or a message no longer matches its checksum.
+    Corrupted,
+    /// The write would exceed the memory budget of the node.
+    StorageFull,
+    /// The current value of the key does not match the condition of a compare-and-swap.
+    Conflict,
+}
+
+impl ErrorCode {
+    fn to_byte(self) -> u8 {
+        match self {
+            ErrorCode::BadRequest => 1,
+            ErrorCode::Unavailable => 2,
+            ErrorCode::Internal => 3,
+            ErrorCode::Unauthorized => 4,
+            ErrorCode::Corrupted => 5,
+            ErrorCode::StorageFull => 6,
+            ErrorCode::Conflict => 7,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<ErrorCode, DecodeError> {
+        match byte {
+            1 => Ok(ErrorCode::BadRequest),
+            2 => Ok(ErrorCode::Unavailable),
+            3 => Ok(ErrorCode::Internal),
+            4 => Ok(ErrorCode::Unauthorized),
+            5 => Ok(ErrorCode::Corrupted),
+            6 => Ok(ErrorCode::StorageFull),
+            7 => Ok(ErrorCode::Conflict),
+            other => Err(DecodeError::UnknownErrorCode(other)),
+        }
+    }
+}
+
+/// Reasons why a frame could not be decoded into a `Message`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The frame ended before all of the expected fields were read.
+    TooShort,
+    /// The total length in the header does not match the length of the frame.
+    LengthMismatch { header: usize, actual: usize },
+    /// The frame contained bytes after all of the expected fields.
+    TrailingBytes,
+    /// The message type is not part of the protocol.
+    UnknownType(u8),
+    /// The error code of an error response is not part of the protocol.
+    UnknownErrorCode(u8),
+    /// The address family of an encoded IP address is neither `4` nor `6`.
+    UnknownAddressFamily(u8),
+    /// The principal kind of an authentication message is neither node nor client.
+    UnknownPrincipal(u8),
+    /// The condition kind of a compare-and-swap is not part of the protocol.
+    UnknownCondition(u8),
+    /// The kind of the read point of a read request is not part of the protocol.
+    UnknownReadPoint(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort => write!(f, "message is too short"),
+            DecodeError::LengthMismatch { header, actual } => write!(
+                f,
+                "header length {} does not match message length {}",
+                header, actual
+            ),
+            DecodeError::TrailingBytes => write!(f, "message has trailing bytes"),
+            DecodeError::UnknownType(message_type) => {
+                write!(f, "unknown message type {}", message_type)
+            }
+            DecodeError::UnknownErrorCode(code) => write!(f, "unknown error code {}", code),
+            DecodeError::UnknownAddressFamily(family) => {
+                write!(f, "unknown address family {}", family)
+            }
+            DecodeError::UnknownPrincipal(kind) => write!(f, "unknown principal kind {}", kind),
+            DecodeError::UnknownCondition(kind) => write!(f, "unknown condition kind {}", kind),
+            DecodeError::UnknownReadPoint(kind) => write!(f, "unknown read point kind {}", kind),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Message {
+    /// Returns the acknowledgement response, `[0, 0, 0, 0, 7, 111, 107]` on the wire.
+    pub fn ok() -> Message {
+        Message::Response(b"ok".to_vec())
+    }
+
+    /// Returns the response to a read request or the write permission:
+    /// one byte, `1` if the key has a value and `0` if not,
+    /// the version of the key, 8 big-endian bytes, and the value.
+    pub fn value_response(value: Option<Vec<u8>>, version: u64) -> Message {
+        let mut payload = vec![value.is_some() as u8];
+        payload.extend_from_slice(&version.to_be_bytes());
+        payload.extend(value.unwrap_or_default());
+        Message::Response(payload)
+    }
+
+    /// Returns the acknowledgement of a write, the acknowledgement response
+    /// followed by the version of the written value, 8 big-endian bytes.
+    pub fn write_ok(version: u64) -> Message {
+        let mut payload = b"ok".to_vec();
+        payload.extend_from_slice(&version.to_be_bytes());
+        Message::Response(payload)
+    }
+
+    /// Returns `true` if this message is the acknowledgement response.
+    pub fn is_ok(&self) -> bool {
+        *self == Message::ok()
+    }
+
+    /// Returns an error response with the given code and human-readable message.
+    pub fn error(code: ErrorCode, message: &str) -> Message {
+        Message::Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    /// Returns `true` if this is a request that only the other nodes of the system may send.
+    pub fn is_internal(&self) -> bool {
+        matches!(self.message_type(), 1..=3 | 10..=14 | 20..=21 | 30..=31)
+    }
+
+    /// Returns `true` if this is a request that clients send to access the key-value pairs.
+    pub fn is_client_request(&self) -> bool {
+        matches!(
+            self,
+            Message::ClientRead { .. }
+                | Message::ClientWrite { .. }
+                | Message::UsageRequest
+                | Message::ClientDelete { .. }
+        )
+    }
+
+    /// Returns `true` if this is a request that only the other nodes and administrator clients may send.
+    pub fn is_admin_request(&self) -> bool {
+        matches!(self, Message::ExportRequest { .. })
+    }
+
+    /// Returns the message type byte of this message.
+    pub fn message_type(&self) -> u8 {
+        match self {
+            Message::Response(_) => 0,
+            Message::LeaderRead { .. } => 1,
+            Message::LeaderWrite { .. } => 2,
+            Message::LeaderDelete { .. } => 3,
+            Message::NodeListRequest => 10,
+            Message::LeaderTransferRequest { .. } => 11,
+            Message::BackupRequest => 12,
+            Message::JoinAnnouncement { .. } => 13,
+            Message::OpenLink { .. } => 14,
+            Message::Authenticate { .. } => 15,
+            Message::EnableChecksums => 16,
+            Message::BackupWrite { .. } => 20,
+            Message::BackupArrayWrite { .. } => 21,
+            Message::NeighborDown { .. } => 30,
+            Message::PeerDown { .. } => 31,
+            Message::ClientRead { .. } => 200,
+            Message::ClientWrite { .. } => 202,
+            Message::UsageRequest => 204,
+            Message::ClientDelete { .. } => 206,
+            Message::CompareAndSwap { .. } => 208,
+            Message::ExportRequest { .. } => 210,
+            Message::Error { .. } => 255,
+        }
+    }
+
+    /// Returns the oldest protocol version whose formats can carry this message.
+    pub fn min_protocol_version(&self) -> u16 {
+        match self {
+            Message::LeaderRead { at: Some(_), .. } => HISTORY_PROTOCOL_VERSION,
+            _ => MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    /// Encodes this message into a frame that can be sent over a connection.
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_for(PROTOCOL_VERSION)
+    }
+
+    /// Encodes this message into a frame in the formats of the given protocol version,
+    /// which must be at least `min_protocol_version`.
+    pub fn encode_for(&self, version: u16) -> Vec<u8> {
+        let mut payload = Vec::new();
+
+        match self {
+            Message::Response(bytes) => payload.extend_from_slice(bytes),
+            Message::NodeListRequest
+            | Message::BackupRequest
+            | Message::UsageRequest
+            | Message::EnableChecksums => {}
+            Message::LeaderDelete { key } | Message::ClientDelete { key } => {
+                payload.extend_from_slice(&key.to_be_bytes())
+            }
+            Message::LeaderRead { key, at } | Message::ClientRead { key, at } => {
+                payload.extend_from_slice(&key.to_be_bytes());
+                match at {
+                    Some(ReadPoint::Version(version)) => {
+                        payload.push(0);
+                        payload.extend_from_slice(&version.to_be_bytes());
+                    }
+                    Some(ReadPoint::Time(time)) => {
+                        payload.push(1);
+                        payload.extend_from_slice(&time.to_be_bytes());
+                    }
+                    None => {}
+                }
+            }
+            Message::LeaderWrite { key, ttl } | Message::ClientWrite { key, ttl } => {
+                payload.extend_from_slice(&key.to_be_bytes());
+                if let Some(ttl) = ttl {
+                    payload.extend_from_slice(&(ttl.as_millis() as u64).to_be_bytes());
+                }
+            }
+            Message::NeighborDown { node_id } | Message::PeerDown { node_id } => {
+                payload.extend_from_slice(&node_id.to_be_bytes())
+            }
+            Message::OpenLink {
+                version,
+                capabilities,
+            } => {
+                payload.extend_from_slice(&version.to_be_bytes());
+                payload.extend_from_slice(&capabilities.to_be_bytes());
+            }
+            Message::Authenticate {
+                client,
+                timestamp,
+                nonce,
+                mac,
+            } => {
+                payload.push(u8::from(client.is_some()));
+                payload.extend_from_slice(&timestamp.to_be_bytes());
+                payload.extend_from_slice(&nonce.to_be_bytes());
+                payload.extend_from_slice(mac);
+                if let Some(client) = client {
+                    payload.extend_from_slice(client.as_bytes());
+                }
+            }
+            Message::JoinAnnouncement {
+                node_id,
+                ip_address,
+                port,
+            } => {
+                payload.extend_from_slice(&node_id.to_be_bytes());
+                payload.extend_from_slice(&encode_ip_address(*ip_address));
+                payload.extend_from_slice(&port.to_be_bytes());
+            }
+            Message::LeaderTransferRequest { key_range } => {
+                payload.extend_from_slice(&key_range.start().to_be_bytes());
+                payload.extend_from_slice(&key_range.end().to_be_bytes());
+            }
+            Message::BackupWrite { key, entry } => {
+                payload.extend_from_slice(&key.to_be_bytes());
+                payload.extend_from_slice(&encode_entry_metadata(entry, version));
+                payload.extend_from_slice(&entry.value);
+            }
+            Message::BackupArrayWrite { kv_pairs } => {
+                payload = encode_kv_pairs(kv_pairs, version);
+            }
+            Message::CompareAndSwap { condition, value } => {
+                match condition {
+                    WriteCondition::Version(version) => {
+                        payload.push(0);
+                        payload.extend_from_slice(&version.to_be_bytes());
+                    }
+                    WriteCondition::Value(expected) => {
+                        payload.push(1);
+                        payload.extend_from_slice(&(expected.len() as u32).to_be_bytes());
+                        payload.extend_from_slice(expected);
+                    }
+                    WriteCondition::Absent => payload.push(2),
+                }
+                payload.extend_from_slice(value);
+            }
+            Message::ExportRequest { include_backups } => payload.push(u8::from(*include_backups)),
+            Message::Error { code, message } => {
+                payload.push(code.to_byte());
+                payload.extend_from_slice(message.as_bytes());
+            }
+        }
+
+        let total_length = (HEADER_LENGTH + payload.len()) as u32;
+        [
+            vec![self.message_type()],
+            total_length.to_be_bytes().to_vec(),
+            payload,
+        ]
+        .concat()
+    }
+
+    /// Decodes a complete frame, header included, into a message.
+    pub fn decode(frame: &[u8]) -> Result<Message, DecodeError> {
+        Message::decode_for(frame, PROTOCOL_VERSION)
+    }
+
+    /// Decodes a complete frame in the formats of the given protocol version into a message.
+    pub fn decode_for(frame: &[u8], version: u16) -> Result<Message, DecodeError> {
+        if frame.len() < HEADER_LENGTH {
+            return Err(DecodeError::TooShort);
+        }
+
+        let total_length = u32::from_be_bytes(frame[1..5].try_into().unwrap()) as usize;
+        if total_length != frame.len() {
+            return Err(DecodeError::LengthMismatch {
+                header: total_length,
+                actual: frame.len(),
+            });
+        }
+
+        let mut reader = PayloadReader::new(&frame[HEADER_LENGTH..]);
+
+        let message = match frame[0] {
+            0 => Message::Response(reader.rest().to_vec()),
+            1 => Message::LeaderRead {
+                key: reader.u64()?,
+                at: decode_read_point(&mut reader)?,
+            },
+            2 => Message::LeaderWrite {
+                key: reader.u64()?,
+                ttl: decode_ttl(&mut reader)?,
+            },
+            3 => Message::LeaderDelete { key: reader.u64()? },
+            10 => Message::NodeListRequest,
+            11 => Message::LeaderTransferRequest {
+                key_range: reader.u64()?..=reader.u64()?,
+            },
+            12 => Message::BackupRequest,
+            13 => Message::JoinAnnouncement {
+                node_id: reader.u64()?,
+                ip_address: decode_ip_address(&mut reader)?,
+                port: reader.u16()?,
+            },
+            14 => Message::OpenLink {
+                version: reader.u16()?,
+                capabilities: reader.u64()?,
+            },
+            15 => {
+                let has_client = match reader.bytes(1)?[0] {
+                    0 => false,
+                    1 => true,
+                    other => return Err(DecodeError::UnknownPrincipal(other)),
+                };
+                let timestamp = reader.u64()?;
+                let nonce = reader.u64()?;
+                let mac = reader.bytes(MAC_LENGTH)?.to_vec();
+                let client =
+                    has_client.then(|| String::from_utf8_lossy(reader.rest()).into_owned());
+                Message::Authenticate {
+                    client,
+                    timestamp,
+                    nonce,
+                    mac,
+                }
+            }
+            16 => Message::EnableChecksums,
+            20 => {
+                let key = reader.u64()?;
+                let mut entry = reader.entry_metadata(version)?;
+                entry.value = reader.rest().to_vec();
+                Message::BackupWrite { key, entry }
+            }
+            21 => Message::BackupArrayWrite {
+                kv_pairs: decode_kv_pairs(reader.rest(), version)?,
+            },
+            30 => Message::NeighborDown {
+                node_id: reader.u64()?,
+            },
+            31 => Message::PeerDown {
+                node_id: reader.u64()?,
+            },
+            200 => Message::ClientRead {
+                key: reader.u64()?,
+                at: decode_read_point(&mut reader)?,
+            },
+            202 => Message::ClientWrite {
+                key: reader.u64()?,
+                ttl: decode_ttl(&mut reader)?,
+            },
+            204 => Message::UsageRequest,
+            206 => Message::ClientDelete { key: reader.u64()? },
+            208 => Message::CompareAndSwap {
+                condition: match reader.bytes(1)?[0] {
+                    0 => WriteCondition::Version(reader.u64()?),
+                    1 => {
+                        let length = reader.u32()? as usize;
+                        WriteCondition::Value(reader.bytes(length)?.to_vec())
+                    }
+                    2 => WriteCondition::Absent,
+                    other => return Err(DecodeError::UnknownCondition(other)),
+                },
+                value: reader.rest().to_vec(),
+            },
+            210 => Message::ExportRequest {
+                include_backups: reader.bytes(1)?[0] != 0,
+            },
+            255 => Message::Error {
+                code: ErrorCode::from_byte(reader.bytes(1)?[0])?,
+                message: String::from_utf8_lossy(reader.rest()).into_owned(),
+            },
+            other => return Err(DecodeError::UnknownType(other)),
+        };
+
+        reader.finish()?;
+
+        Ok(message)
+    }
+}
+
+/// Encodes key-value pairs as concatenated items of key, entry metadata, value length and value
+/// in the formats of the given protocol version.
+pub fn encode_kv_pairs(kv_pairs: &[(u64, Entry)], version: u16) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (key, entry) in kv_pairs {
+        bytes.extend_from_slice(&key.to_be_bytes());
+        bytes.extend_from_slice(&encode_entry_metadata(entry, version));
+        bytes.extend_from_slice(&(entry.value.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&entry.value);
+    }
+    bytes
+}
+
+/// Decodes key-value pairs encoded by `encode_kv_pairs` for the given protocol version.
+pub fn decode_kv_pairs(bytes: &[u8], version: u16) -> Result<Vec<(u64, Entry)>, DecodeError> {
+    let mut reader = PayloadReader::new(bytes);
+    let mut kv_pairs = Vec::new();
+
+    while !reader.is_empty() {
+        let key = reader.u64()?;
+        let mut entry = reader.entry_metadata(version)?;
+        let value_length = reader.u32()? as usize;
+        entry.value = reader.bytes(value_length)?.to_vec();
+        kv_pairs.push((key, entry));
+    }
+
+    Ok(kv_pairs)
+}
+
+/// Encodes the metadata of the given entry in the formats of the given protocol version.
+/// Before the history protocol version, the metadata had only the expiry time, the version and the flags.
+fn encode_entry_metadata(entry: &Entry, version: u16) -> Vec<u8> {
+    if version >= HISTORY_PROTOCOL_VERSION {
+        return entry.encode_metadata();
+    }
+    let mut metadata = entry.expires_at.unwrap_or(0).to_be_bytes().to_vec();
+    metadata.extend_from_slice(&entry.version.to_be_bytes());
+    metadata.push(if entry.deleted { DELETED_FLAG } else { 0 });
+    metadata
+}
+
+/// Decodes the optional time to live in milliseconds at the end of a write request.
+fn decode_ttl(reader: &mut PayloadReader) -> Result<Option<Duration>, DecodeError> {
+    if reader.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Duration::from_millis(reader.u64()?)))
+}
+
+/// Decodes the optional read point at the end of a read request.
+fn decode_read_point(reader: &mut PayloadReader) -> Result<Option<ReadPoint>, DecodeError> {
+    if reader.is_empty() {
+        return Ok(None);
+    }
+    match reader.bytes(1)?[0] {
+        0 => Ok(Some(ReadPoint::Version(reader.u64()?))),
+        1 => Ok(Some(ReadPoint::Time(reader.u64()?))),
+        other => Err(DecodeError::UnknownReadPoint(other)),
+    }
+}
+
+/// Encodes a node list as concatenated items of node ID, IP address and port.
+pub fn encode_node_list(node_list: &[PeerNode]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for node in node_list {
+        bytes.extend_from_slice(&node.id.to_be_bytes());
+        bytes.extend_from_slice(&encode_ip_address(node.ip_address));
+        bytes.extend_from_slice(&node.port.to_be_bytes());
+    }
+    bytes
+}
+
+/// Decodes a node list encoded by `encode_node_list`.
+pub fn decode_node_list(bytes: &[u8]) -> Result<Vec<PeerNode>, DecodeError> {
+    let mut reader = PayloadReader::new(bytes);
+    let mut node_list = Vec::new();
+
+    while !reader.is_empty() {
+        let id = reader.u64()?;
+        let ip_address = decode_ip_address(&mut reader)?;
+        let port = reader.u16()?;
+        node_list.push(PeerNode {
+            id,
+            ip_address,
+            port,
+        });
+    }
+
+    Ok(node_list)
+}
+
+/// Encodes an IP address as its family (`4` or `6`) followed by its 4 or 16 bytes.
+fn encode_ip_address(ip_address: IpAddr) -> Vec<u8> {
+    match ip_address {
+        IpAddr::V4(address) => [vec![4], address.octets().to_vec()].concat(),
+        IpAddr::V6(address) => [vec![6], address.octets().to_vec()].concat(),
+    }
+}
+
+/// Decodes an IP address encoded by `encode_ip_address`.
+fn decode_ip_address(reader: &mut PayloadReader) -> Result<IpAddr, DecodeError> {
+    match reader.bytes(1)?[0] {
+        4 => {
+            let octets: [u8; 4] = reader.bytes(4)?.try_into().unwrap();
+            Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        6 => {
+            let octets: [u8; 16] = reader.bytes(16)?.try_into().unwrap();
+            Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        other => Err(DecodeError::UnknownAddressFamily(other)),
+    }
+}
+
+/// Reads fields from a payload without ever reading out of its bounds.
+struct PayloadReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        PayloadReader { bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < count {
+            return Err(DecodeError::TooShort);
+        }
+        let (head, tail) = self.bytes.split_at(count);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Reads the metadata of an entry encoded by `encode_entry_metadata`, returning an entry with an empty value.
+    /// Unknown flags are ignored.
+    fn entry_metadata(&mut self, version: u16) -> Result<Entry, DecodeError> {
+        if version < HISTORY_PROTOCOL_VERSION {
+            let expires_at = Some(self.u64()?).filter(|expires_at| *expires_at != 0);
+            let entry_version = self.u64()?;
+            let flags = self.bytes(1)?[0];
+            return Ok(Entry {
+                expires_at,
+                deleted: flags & DELETED_FLAG != 0,
+                version: entry_version,
+                ..Entry::new(Vec::new())
+            });
+        }
+
+        let (entry, length) = Entry::decode_metadata(self.bytes).ok_or(DecodeError::TooShort)?;
+        self.bytes = &self.bytes[length..];
+        Ok(entry)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.bytes)
+    }
+
+    fn finish(&self) -> Result<(), DecodeError> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingBytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ok_response_encoding() {
+        assert_eq!(Message::ok().encode(), vec![0, 0, 0, 0, 7, 111, 107]);
+        assert!(Message::decode(&[0, 0, 0, 0, 7, 111, 107]).unwrap().is_ok());
+    }
+
+    #[test]
+    fn value_response_encoding() {
+        assert_eq!(
+            Message::value_response(Some(vec![7]), 258).encode(),
+            vec![0, 0, 0, 0, 15, 1, 0, 0, 0, 0, 0, 0, 1, 2, 7]
+        );
+        assert_eq!(
+            Message::value_response(Some(Vec::new()), 1).encode(),
+            vec![0, 0, 0, 0, 14, 1, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+        assert_eq!(
+            Message::value_response(None, 0).encode(),
+            vec![0, 0, 0, 0, 14, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            Message::write_ok(2).encode(),
+            vec![0, 0, 0, 0, 15, 111, 107, 0, 0, 0, 0, 0, 0, 0, 2]
+        );
+    }
+
+    #[test]
+    fn fixed_length_message_encoding() {
+        let message = Message::ClientRead { key: 258, at: None };
+        let encoded = message.encode();
+        assert_eq!(encoded, vec![200, 0, 0, 0, 13, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+
+        assert_eq!(Message::EnableChecksums.encode(), vec![16, 0, 0, 0, 5]);
+        assert_eq!(
+            Message::decode(&[16, 0, 0, 0, 5]).unwrap(),
+            Message::EnableChecksums
+        );
+
+        let message = Message::LeaderRead {
+            key: 258,
+            at: Some(ReadPoint::Version(3)),
+        };
+        let encoded = message.encode();
+        assert_eq!(
+            encoded,
+            vec![1, 0, 0, 0, 22, 0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 3]
+        );
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+
+        let message = Message::ClientRead {
+            key: 258,
+            at: Some(ReadPoint::Time(1000)),
+        };
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+        assert_eq!(
+            Message::decode(&[200, 0, 0, 0, 22, 0, 0, 0, 0, 0, 0, 1, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(DecodeError::UnknownReadPoint(2))
+        );
+
+        let message = Message::LeaderTransferRequest { key_range: 5..=10 };
+        let encoded = message.encode();
+        assert_eq!(encoded.len(), 21);
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+
+        let message = Message::OpenLink {
+            version: 1,
+            capabilities: 6,
+        };
+        let encoded = message.encode();
+        assert_eq!(encoded, vec![14, 0, 0, 0, 15, 0, 1, 0, 0, 0, 0, 0, 0, 0, 6]);
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+
+        let message = Message::ClientWrite {
+            key: 258,
+            ttl: Some(Duration::from_millis(1500)),
+        };
+        let encoded = message.encode();
+        assert_eq!(encoded.len(), 21);
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+        let message = Message::ClientWrite {
+            key: 258,
+            ttl: None,
+        };
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+
+        let message = Message::ClientDelete { key: 258 };
+        let encoded = message.encode();
+        assert_eq!(encoded, vec![206, 0, 0, 0, 13, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+
+        let encoded = Message::UsageRequest.encode();
+        assert_eq!(encoded, vec![204, 0, 0, 0, 5]);
+        assert_eq!(Message::decode(&encoded).unwrap(), Message::UsageRequest);
+
+        let message = Message::ExportRequest {
+            include_backups: true,
+        };
+        let encoded = message.encode();
+        assert_eq!(encoded, vec![210, 0, 0, 0, 6, 1]);
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+    }
+
+    #[test]
+    fn previous_protocol_version_encoding() {
+        let entry = Entry {
+            version: 3,
+            written_at: 1000,
+            history: vec![Entry::new(vec![1])],
+            ..Entry::with_ttl(vec![2], Some(Duration::from_secs(60)))
+        };
+        let message = Message::BackupWrite { key: 258, entry };
+        let encoded = message.encode_for(MIN_PROTOCOL_VERSION);
+        assert_eq!(encoded.len(), 5 + 8 + 17 + 1);
+
+        // the write time and the history do not exist in the previous version
+        let Message::BackupWrite { entry, .. } =
+            Message::decode_for(&encoded, MIN_PROTOCOL_VERSION).unwrap()
+        else {
+            panic!("decoded a different message");
+        };
+        assert_eq!((entry.version, entry.written_at), (3, 0));
+        assert!(entry.history.is_empty());
+
+        let read = Message::LeaderRead {
+            key: 258,
+            at: Some(ReadPoint::Version(3)),
+        };
+        assert!(read.min_protocol_version() > MIN_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn compare_and_swap_encoding() {
+        let message = Message::CompareAndSwap {
+            condition: WriteCondition::Version(258),
+            value: vec![7],
+        };
+        let encoded = message.encode();
+        assert_eq!(
+            encoded,
+            vec![208, 0, 0, 0, 15, 0, 0, 0, 0, 0, 0, 0, 1, 2, 7]
+        );
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+
+        let message = Message::CompareAndSwap {
+            condition: WriteCondition::Value(vec![1, 2]),
+            value: vec![7],
+        };
+        let encoded = message.encode();
+        assert_eq!(encoded, vec![208, 0, 0, 0, 13, 1, 0, 0, 0, 2, 1, 2, 7]);
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+
+        let message = Message::CompareAndSwap {
+            condition: WriteCondition::Absent,
+            value: Vec::new(),
+        };
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+        assert_eq!(
+            Message::decode(&[208, 0, 0, 0, 7, 3, 7]),
+            Err(DecodeError::UnknownCondition(3))
+        );
+    }
+
+    #[test]
+    fn kv_pair_message_encoding() {
+        let message = Message::BackupArrayWrite {
+            kv_pairs: vec![
+                (1, Entry::new(vec![1, 2, 3])),
+                (2, Entry::with_ttl(vec![], Some(Duration::from_secs(60)))),
+                (3, Entry::tombstone(Duration::from_secs(60))),
+            ],
+        };
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+
+        let message = Message::BackupWrite {
+            key: 1,
+            entry: Entry::with_ttl(vec![1], Some(Duration::from_secs(60))),
+        };
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+    }
+
+    #[test]
+    fn authentication_message_encoding() {
+        let message = Message::Authenticate {
+            client: Some("alice".to_string()),
+            timestamp: 1,
+            nonce: 2,
+            mac: vec![7; MAC_LENGTH],
+        };
+        let encoded = message.encode();
+        assert_eq!(encoded.len(), HEADER_LENGTH + 1 + 8 + 8 + MAC_LENGTH + 5);
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+
+        let message = Message::Authenticate {
+            client: None,
+            timestamp: 1,
+            nonce: 2,
+            mac: vec![7; MAC_LENGTH],
+        };
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+    }
+
+    #[test]
+    fn error_message_encoding() {
+        let message = Message::error(ErrorCode::Unavailable, "down");
+        let encoded = message.encode();
+        assert_eq!(encoded, vec![255, 0, 0, 0, 10, 2, 100, 111, 119, 110]);
+        assert_eq!(Message::decode(&encoded).unwrap(), message);
+        assert_eq!(
+            Message::decode(&[255, 0, 0, 0, 6, 9]),
+            Err(DecodeError::UnknownErrorCode(9))
+        );
+    }
+
+    #[test]
+    fn node_list_encoding() {
+        let node_list = vec![
+            PeerNode {
+                id: 7,
+                ip_address: "192.168.0.7".parse().unwrap(),
+                port: 52525,
+            },
+            PeerNode {
+                id: 8,
+                ip_address: "fd00::8".parse().unwrap(),
+                port: 52526,
+            },
+        ];
+        let encoded = encode_node_list(&node_list);
+        assert_eq!(
+            encoded[..15],
+            [0, 0, 0, 0, 0, 0, 0, 7, 4, 192, 168, 0, 7, 205, 45]
+        );
+        assert_eq!(encoded.len(), 15 + 27);
+        let decoded = decode_node_list(&encoded).unwrap();
+        assert_eq!(decoded[0].id, 7);
+        assert_eq!(decoded[0].ip_address.to_string(), "192.168.0.7");
+        assert_eq!(decoded[0].port, 52525);
+        assert_eq!(decoded[1].id, 8);
+        assert_eq!(decoded[1].ip_address.to_string(), "fd00::8");
+        assert_eq!(decoded[1].port, 52526);
+        assert!(matches!(
+            decode_node_list(&[0, 0, 0, 0, 0, 0, 0, 7, 5]),
+            Err(DecodeError::UnknownAddressFamily(5))
+        ));
+    }
+
+    #[test]
+    fn malformed_frame_decoding() {
+        assert_eq!(Message::decode(&[1, 0, 0]), Err(DecodeError::TooShort));
+        assert_eq!(
+            Message::decode(&[1, 0, 0, 0, 9, 0, 0, 0, 0]),
+            Err(DecodeError::TooShort)
+        );
+        assert_eq!(
+            Message::decode(&[10, 0, 0, 0, 6, 0]),
+            Err(DecodeError::TrailingBytes)
+        );
+        assert_eq!(
+            Message::decode(&[10, 0, 0, 0, 7]),
+            Err(DecodeError::LengthMismatch {
+                header: 7,
+                actual: 5
+            })
+        );
+        assert_eq!(
+            Message::decode(&[99, 0, 0, 0, 5]),
+            Err(DecodeError::UnknownType(99))
+        );
+        assert_eq!(
+            Message::decode(&[21, 0, 0, 0, 17, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 9]),
+            Err(DecodeError::TooShort)
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{timeout_at, Instant};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+
+pub use auth::{AuthenticationSettings, Principal};
+pub use connection::{configure, Connection, ConnectionError, ConnectionSettings};
+pub use link::ConnectionPool;
+pub use message::{
+    decode_node_list, encode_node_list, ErrorCode, Message, ReadPoint, WriteCondition,
+};
+pub use tls::TlsSettings;
+pub use transfer::{ExportSender, ExportedStorage, KvPairsReceiver, KvPairsSender};
+
+mod auth;
+mod connection;
+mod link;
+mod message;
+mod tls;
+mod transfer;
+
+/// The port used by nodes when no other port is configured or known.
+pub const DEFAULT_PORT: u16 = 52525;
+
+/// Infinitely listens to incoming connections on the given address and port.
+/// For every connection, and every request on the links opened by other nodes,
+/// sends `Connection` to the returned stream.
+/// If the address is `::` but IPv6 is not available, listens on `0.0.0.0` instead.
+/// Panics if the address cannot be bound.
+pub async fn listen_messages(bind_address: IpAddr, port: u16) -> impl Stream<Item = Connection> {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let listener = match TcpListener::bind((bind_address, port)).await {
+        Ok(listener) => listener,
+        Err(error) if bind_address == IpAddr::V6(Ipv6Addr::UNSPECIFIED) => {
+            println!(
+                "failed to listen on [::]:{} ({}), listening on IPv4 only",
+                port, error
+            );
+            match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+                Ok(listener) => listener,
+                Err(error) => panic!("failed to listen on 0.0.0.0:{} ({})", port, error),
+            }
+        }
+        Err(error) => panic!("failed to listen on {}:{} ({})", bind_address, port, error),
+    };
+
+    tokio::task::spawn(async move {
+        while let Ok((stream, address)) = listener.accept().await {
+            tokio::task::spawn(handle_accepted_stream(stream, address, tx.clone()));
+        }
+    });
+
+    UnboundedReceiverStream::new(rx)
+}
+
+/// Secures an accepted stream, reads its first message and either serves the stream as a link
+/// or sends it as a single connection to the given channel.
+/// An authentication message preceding the first message decides the sender of the stream.
+async fn handle_accepted_stream(
+    stream: TcpStream,
+    address: SocketAddr,
+    incoming_connections: mpsc::UnboundedSender<Connection>,
+) {
+    let read_deadline = Instant::now() + connection::settings().read_timeout;
+
+    // a socket listening on `::` also accepts IPv4 connections, as IPv4-mapped IPv6 addresses
+    let address = canonical_address(address);
+    let local_address = match stream.local_addr() {
+        Ok(local_address) => canonical_address(local_address),
+        Err(error) => {
+            println!("failed to accept connection from {} ({})", address, error);
+            return;
+        }
+    };
+    let (mut stream, has_certificate) =
+        match timeout_at(read_deadline, tls::secure_incoming(stream)).await {
+            Ok(Ok(secured)) => secured,
+            Ok(Err(error)) => {
+                println!("failed TLS handshake with {} ({})", address, error);
+                return;
+            }
+            Err(_) => {
+                println!("TLS handshake with {} timed out", address);
+                return;
+            }
+        };
+
+    // a certificate signed by the cluster CA is only ever given to nodes
+    let mut principal = has_certificate.then_some(Principal::Node);
+
+    let mut first_message = read_first_message(&mut stream, read_deadline, false).await;
+
+    if let Ok(authentication @ Message::Authenticate { .. }) = &first_message {
+        match auth::verify(authentication) {
+            Some(authenticated) => principal = Some(authenticated),
+            None => {
+                println!("rejected authentication from {}", address);
+                let refusal = Message::error(ErrorCode::Unauthorized, "authentication failed");
+                let _ = timeout_at(read_deadline, async {
+                    stream.write_all(&refusal.encode()).await?;
+                    stream.flush().await
+                })
+                .await;
+                return;
+            }
+        }
+        first_message = read_first_message(&mut stream, read_deadline, false).await;
+    }
+
+    // a plain connection may ask every later message to carry its checksum, like the messages on links
+    let frame_checksums = matches!(first_message, Ok(Message::EnableChecksums));
+    if frame_checksums {
+        let acknowledged = timeout_at(read_deadline, async {
+            stream.write_all(&Message::ok().encode()).await?;
+            stream.flush().await
+        })
+        .await;
+        if !matches!(acknowledged, Ok(Ok(()))) {
+            println!("failed to enable checksums for {}", address);
+            return;
+        }
+        first_message = read_first_message(&mut stream, read_deadline, true).await;
+    }
+
+    if let Ok(Message::OpenLink {
+        version,
+        capabilities,
+    }) = first_message
+    {
+        let link = link::IncomingLink {
+            address,
+            local_address,
+            version,
+            capabilities,
+            principal,
+        };
+        link::serve_link(stream, link, incoming_connections).await;
+        return;
+    }
+
+    let mut incoming_connection = Connection::accepted(
+        stream,
+        address,
+        local_address,
+        principal,
+        Some(first_message),
+    );
+    if frame_checksums {
+        incoming_connection.enable_frame_checksums();
+    }
+    let _ = incoming_connections.send(incoming_connection);
+}
+
+/// Returns the given socket address with an IPv4-mapped IPv6 address converted to IPv4.
+fn canonical_address(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(address.ip().to_canonical(), address.port())
+}
+
+/// Reads the next message of a newly accepted stream before the given deadline,
+/// checking and removing its checksum if asked.
+async fn read_first_message(
+    stream: &mut tls::Stream,
+    read_deadline: Instant,
+    frame_checksums: bool,
+) -> Result<Message, ConnectionError> {
+    match timeout_at(
+        read_deadline,
+        connection::read_stream_frame(stream, frame_checksums),
+    )
+    .await
+    {
+        Ok(Ok(frame)) => Ok(Message::decode(&frame)?),
+        Ok(Err(error)) => Err(error),
+        Err(_) => Err(ConnectionError::Timeout),
+    }
+}
+
+/// Splits the given `host`, `host:port`, `[ipv6]` or `[ipv6]:port` into the host and the port.
+/// A bare IPv6 address without brackets is treated as a host without a port.
+/// Returns `None` if the address is invalid.
+fn split_host_and_port(address: &str) -> Option<(&str, Option<u16>)> {
+    if let Some(bracketed) = address.strip_prefix('[') {
+        let (host, rest) = bracketed.split_once(']')?;
+        return match rest.strip_prefix(':') {
+            Some(port) => Some((host, Some(port.parse().ok()?))),
+            None if rest.is_empty() => Some((host, None)),
+            None => None,
+        };
+    }
+
+    match address.split_once(':') {
+        Some((host, port)) if !port.contains(':') => Some((host, Some(port.parse().ok()?))),
+        _ => Some((address, None)),
+    }
+}
+
+/// Resolves the given `host`, `host:port`, `[ipv6]` or `[ipv6]:port` into an IP address and a port.
+/// Uses the given default port if the port is not given. Returns `None` if resolving fails.
+pub fn resolve_address(address: &str, default_port: u16) -> Option<(IpAddr, u16)> {
+    let (host, port) = split_host_and_port(address)?;
+    Some((
+        resolve_hostname_to_ip_address(host)?,
+        port.unwrap_or(default_port),
+    ))
+}
+
+/// Returns the IP address that corresponds to the given hostname, if any.
+/// IPv4 addresses are preferred over IPv6 addresses when the hostname has both.
+fn resolve_hostname_to_ip_address(hostname: &str) -> Option<IpAddr> {
+    let addresses: Vec<IpAddr> = match (hostname, 0).to_socket_addrs() {
+        Ok(addrs) => addrs.map(|address| address.ip()).collect(),
+        Err(_) => return None,
+    };
+
+    addresses
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or_else(|| addresses.first())
+        .copied()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn host_and_port_splitting() {
+        assert_eq!(
+            split_host_and_port("example.com"),
+            Some(("example.com", None))
+        );
+        assert_eq!(
+            split_host_and_port("10.0.0.1:5000"),
+            Some(("10.0.0.1", Some(5000)))
+        );
+        assert_eq!(split_host_and_port("fd00::1"), Some(("fd00::1", None)));
+        assert_eq!(split_host_and_port("[fd00::1]"), Some(("fd00::1", None)));
+        assert_eq!(
+            split_host_and_port("[fd00::1]:5000"),
+            Some(("fd00::1", Some(5000)))
+        );
+        assert_eq!(split_host_and_port("10.0.0.1:port"), None);
+        assert_eq!(split_host_and_port("[fd00::1]5000"), None);
+    }
+}
@@ -0,0 +1,230 @@
+use super::connection::ConnectionError;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Paths to the PEM files used to secure the connections of this node with TLS.
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    /// Certificate chain of this node, signed by the cluster CA.
+    pub certificate_path: PathBuf,
+    /// Private key of the certificate of this node.
+    pub private_key_path: PathBuf,
+    /// Certificate of the cluster CA that signs the certificates of all nodes.
+    pub ca_certificate_path: PathBuf,
+}
+
+/// A readable and writable byte stream, either plain TCP or TLS over TCP.
+pub(super) trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+pub(super) type Stream = Box<dyn AsyncStream>;
+
+/// The TLS configurations of this node for both directions of connections.
+struct Tls {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+}
+
+static TLS: OnceLock<Option<Tls>> = OnceLock::new();
+
+/// Loads the certificates and the key used by all connections of this node.
+/// Connections are plain TCP if `None` is given or this is never called.
+/// Has no effect if called more than once. Panics if the files cannot be loaded.
+pub(super) fn configure_tls(settings: Option<&TlsSettings>) {
+    let tls = settings.map(|settings| match load(settings) {
+        Ok(tls) => tls,
+        Err(error) => panic!("failed to load TLS certificates ({}), aborting", error),
+    });
+    let _ = TLS.set(tls);
+}
+
+fn tls() -> Option<&'static Tls> {
+    TLS.get_or_init(|| None).as_ref()
+}
+
+/// Secures a connection opened to the given address if TLS is enabled.
+/// This node presents its own certificate and expects one from the cluster CA for the address.
+pub(super) async fn secure_outgoing(
+    stream: TcpStream,
+    address: IpAddr,
+) -> Result<Stream, ConnectionError> {
+    match tls() {
+        Some(tls) => {
+            let server_name = ServerName::from(address);
+            Ok(Box::new(tls.connector.connect(server_name, stream).await?))
+        }
+        None => Ok(Box::new(stream)),
+    }
+}
+
+/// Secures an accepted connection if TLS is enabled.
+/// Returns the stream and whether the other end presented a certificate signed by the cluster CA.
+pub(super) async fn secure_incoming(stream: TcpStream) -> io::Result<(Stream, bool)> {
+    match tls() {
+        Some(tls) => {
+            let stream = tls.acceptor.accept(stream).await?;
+            let authenticated = stream.get_ref().1.peer_certificates().is_some();
+            Ok((Box::new(stream), authenticated))
+        }
+        None => Ok((Box::new(stream), false)),
+    }
+}
+
+/// Returns `true` if the connections of this node use TLS.
+pub(super) fn is_enabled() -> bool {
+    tls().is_some()
+}
+
+fn load(settings: &TlsSettings) -> Result<Tls, String> {
+    let certificates = load_certificates(&settings.certificate_path)?;
+    let private_key = load_private_key(&settings.private_key_path)?;
+
+    let mut ca_certificates = RootCertStore::empty();
+    for certificate in load_certificates(&settings.ca_certificate_path)? {
+        ca_certificates
+            .add(certificate)
+            .map_err(|error| format!("invalid CA certificate: {}", error))?;
+    }
+    let ca_certificates = Arc::new(ca_certificates);
+
+    let provider = Arc::new(ring::default_provider());
+
+    // clients may connect without a certificate, peers are required to present one for links
+    let client_verifier = WebPkiClientVerifier::builder_with_provider(
+        Arc::clone(&ca_certificates),
+        Arc::clone(&provider),
+    )
+    .allow_unauthenticated()
+    .build()
+    .map_err(|error| error.to_string())?;
+
+    let server_config = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|error| error.to_string())?
+        .with_client_cert_verifier(client_verifier)
+        .with_single_cert(certificates.clone(), private_key.clone_key())
+        .map_err(|error| error.to_string())?;
+
+    let client_config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|error| error.to_string())?
+        .with_root_certificates(ca_certificates)
+        .with_client_auth_cert(certificates, private_key)
+        .map_err(|error| error.to_string())?;
+
+    Ok(Tls {
+        acceptor: TlsAcceptor::from(Arc::new(server_config)),
+        connector: TlsConnector::from(Arc::new(client_config)),
+    })
+}
+
+fn open_pem_file(path: &Path) -> Result<BufReader<File>, String> {
+    match File::open(path) {
+        Ok(file) => Ok(BufReader::new(file)),
+        Err(error) => Err(format!("{}: {}", path.display(), error)),
+    }
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certificates = rustls_pemfile::certs(&mut open_pem_file(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| format!("{}: {}", path.display(), error))?;
+
+    if certificates.is_empty() {
+        return Err(format!("{}: no certificates found", path.display()));
+    }
+    Ok(certificates)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    match rustls_pemfile::private_key(&mut open_pem_file(path)?) {
+        Ok(Some(private_key)) => Ok(private_key),
+        Ok(None) => Err(format!("{}: no private key found", path.display())),
+        Err(error) => Err(format!("{}: {}", path.display(), error)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use std::net::Ipv4Addr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Creates a CA and a certificate for 127.0.0.1 signed by it.
+    /// Returns the CA certificate, the node certificate and the node key in PEM.
+    fn create_certificates() -> (String, String, String) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let node_key = KeyPair::generate().unwrap();
+        let node_params = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap();
+        let node = node_params.signed_by(&node_key, &ca, &ca_key).unwrap();
+
+        (ca.pem(), node.pem(), node_key.serialize_pem())
+    }
+
+    fn write_temporary_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ds-project-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn mutual_authentication() {
+        let (ca, certificate, private_key) = create_certificates();
+        let tls = load(&TlsSettings {
+            certificate_path: write_temporary_file("node.pem", &certificate),
+            private_key_path: write_temporary_file("node.key", &private_key),
+            ca_certificate_path: write_temporary_file("ca.pem", &ca),
+        })
+        .unwrap();
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let acceptor = tls.acceptor.clone();
+        let server = tokio::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let authenticated = stream.get_ref().1.peer_certificates().is_some();
+            let mut buffer = [0u8; 5];
+            stream.read_exact(&mut buffer).await.unwrap();
+            (authenticated, buffer)
+        });
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut stream = tls
+            .connector
+            .connect(ServerName::from(address.ip()), stream)
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+
+        assert_eq!(server.await.unwrap(), (true, *b"hello"));
+    }
+
+    #[test]
+    fn missing_files() {
+        let result = load(&TlsSettings {
+            certificate_path: PathBuf::from("/nonexistent/node.pem"),
+            private_key_path: PathBuf::from("/nonexistent/node.key"),
+            ca_certificate_path: PathBuf::from("/nonexistent/ca.pem"),
+        });
+        assert!(result.is_err());
+    }
+}
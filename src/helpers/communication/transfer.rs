@@ -0,0 +1,393 @@
+use super::connection::{Connection, ConnectionError};
+use super::message::{
+    decode_kv_pairs, encode_kv_pairs, Message, HIGH_WATER_PROTOCOL_VERSION,
+    HISTORY_PROTOCOL_VERSION,
+};
+use crate::helpers::entry::Entry;
+
+/// Length of the encoded key-value pairs after which a chunk of a bulk transfer is sent.
+const CHUNK_LENGTH: usize = 1024 * 1024;
+
+/// Protocol version whose formats the exported key-value pairs are encoded in, whatever the connection speaks.
+/// The sample client saves the exported items as such in version 1 of its export file format,
+/// so this must not change without a new version of the file format.
+const EXPORT_PROTOCOL_VERSION: u16 = HISTORY_PROTOCOL_VERSION;
+
+/// Length of a single key-value pair encoded by `encode_kv_pairs`.
+fn encoded_length(entry: &Entry) -> usize {
+    8 + entry.metadata_length() + 4 + entry.value.len()
+}
+
+/// Sends the key-value pairs of a bulk transfer over a connection.
+/// If the other end supports it, the pairs are sent in chunks of bounded length
+/// followed by an empty end-of-transfer message. Otherwise all pairs are sent as one message.
+pub struct KvPairsSender<'a> {
+    connection: &'a mut Connection,
+    chunk: Vec<(u64, Entry)>,
+    chunk_length: usize,
+}
+
+impl<'a> KvPairsSender<'a> {
+    pub fn new(connection: &'a mut Connection) -> KvPairsSender<'a> {
+        KvPairsSender {
+            connection,
+            chunk: Vec::new(),
+            chunk_length: 0,
+        }
+    }
+
+    /// Adds a key-value pair to the current chunk.
+    pub fn push(&mut self, key: u64, entry: Entry) {
+        self.chunk_length += encoded_length(&entry);
+        self.chunk.push((key, entry));
+    }
+
+    /// Returns `true` if the current chunk should be sent before adding more pairs.
+    /// Never `true` if the other end does not support chunks.
+    pub fn is_full(&self) -> bool {
+        self.connection.supports_chunked_transfers() && self.chunk_length >= CHUNK_LENGTH
+    }
+
+    /// Sends the current chunk if it is full.
+    pub async fn send_full_chunk(&mut self) -> Result<(), ConnectionError> {
+        if self.is_full() {
+            self.send_chunk().await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the remaining key-value pairs and ends the transfer.
+    /// The end of a chunked transfer carries the given version high-water mark of the sending storage
+    /// if the other end supports it.
+    pub async fn finish(mut self, version_high_water: u64) -> Result<(), ConnectionError> {
+        if !self.connection.supports_chunked_transfers() {
+            return self.send_chunk().await;
+        }
+
+        if !self.chunk.is_empty() {
+            self.send_chunk().await?;
+        }
+        let end = if self.connection.protocol_version() >= HIGH_WATER_PROTOCOL_VERSION {
+            version_high_water.to_be_bytes().to_vec()
+        } else {
+            Vec::new()
+        };
+        self.connection.write(&Message::Response(end)).await
+    }
+
+    async fn send_chunk(&mut self) -> Result<(), ConnectionError> {
+        let payload = encode_kv_pairs(&self.chunk, self.connection.protocol_version());
+        self.chunk.clear();
+        self.chunk_length = 0;
+        self.connection.write(&Message::Response(payload)).await
+    }
+}
+
+/// Receives the key-value pairs of a bulk transfer sent by `KvPairsSender`.
+pub struct KvPairsReceiver<'a> {
+    connection: &'a mut Connection,
+    finished: bool,
+    version_high_water: Option<u64>,
+}
+
+impl<'a> KvPairsReceiver<'a> {
+    /// Receives the key-value pairs sent as the response to a request.
+    pub fn new(connection: &'a mut Connection) -> KvPairsReceiver<'a> {
+        KvPairsReceiver {
+            connection,
+            finished: false,
+            version_high_water: None,
+        }
+    }
+
+    /// Receives the rest of the key-value pairs of a request that carried the first ones itself.
+    /// Nothing follows the request if the other end does not support chunks.
+    pub fn following_request(connection: &'a mut Connection) -> KvPairsReceiver<'a> {
+        let finished = !connection.supports_chunked_transfers();
+        KvPairsReceiver {
+            connection,
+            finished,
+            version_high_water: None,
+        }
+    }
+
+    /// Returns the next chunk of key-value pairs, or `None` after the transfer has ended.
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<(u64, Entry)>>, ConnectionError> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let payload = match self.connection.read_message().await? {
+            Message::Response(payload) => payload,
+            Message::Error { code, message } => {
+                return Err(ConnectionError::ErrorResponse { code, message })
+            }
+            message => return Err(ConnectionError::UnexpectedMessage(message.message_type())),
+        };
+
+        if !self.connection.supports_chunked_transfers() {
+            self.finished = true;
+        } else if self.connection.protocol_version() >= HIGH_WATER_PROTOCOL_VERSION {
+            // every chunk is longer than the high-water mark that ends the transfer
+            if let Ok(version_high_water) = <[u8; 8]>::try_from(payload.as_slice()) {
+                self.finished = true;
+                self.version_high_water = Some(u64::from_be_bytes(version_high_water));
+                return Ok(None);
+            }
+        } else if payload.is_empty() {
+            self.finished = true;
+            return Ok(None);
+        }
+
+        Ok(Some(decode_kv_pairs(
+            &payload,
+            self.connection.protocol_version(),
+        )?))
+    }
+
+    /// Returns the version high-water mark of the sending storage, if the transfer has ended with one.
+    pub fn version_high_water(&self) -> Option<u64> {
+        self.version_high_water
+    }
+}
+
+/// The storage of a node that an exported key-value pair comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportedStorage {
+    Leader = 0,
+    Backup = 1,
+}
+
+/// Sends the exported key-value pairs of the storages of this node to a client
+/// in chunks of bounded length, each pair preceded by the byte of its storage,
+/// followed by an empty end-of-export message.
+/// The pairs are sent as they are given, so the storages never have to be copied as a whole.
+pub struct ExportSender<'a> {
+    connection: &'a mut Connection,
+    chunk: Vec<u8>,
+}
+
+impl<'a> ExportSender<'a> {
+    pub fn new(connection: &'a mut Connection) -> ExportSender<'a> {
+        ExportSender {
+            connection,
+            chunk: Vec::new(),
+        }
+    }
+
+    /// Adds the given key-value pairs of the given storage to the export,
+    /// sending the current chunk whenever it is full.
+    pub async fn send(
+        &mut self,
+        storage: ExportedStorage,
+        kv_pairs: &[(u64, Entry)],
+    ) -> Result<(), ConnectionError> {
+        for kv_pair in kv_pairs {
+            self.chunk.push(storage as u8);
+            self.chunk.extend(encode_kv_pairs(
+                std::slice::from_ref(kv_pair),
+                EXPORT_PROTOCOL_VERSION,
+            ));
+            if self.chunk.len() >= CHUNK_LENGTH {
+                self.connection
+                    .write(&Message::Response(std::mem::take(&mut self.chunk)))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the remaining key-value pairs and ends the export.
+    pub async fn finish(self) -> Result<(), ConnectionError> {
+        if !self.chunk.is_empty() {
+            self.connection
+                .write(&Message::Response(self.chunk))
+                .await?;
+        }
+        self.connection.write(&Message::Response(Vec::new())).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{handle_accepted_stream, ConnectionPool};
+    use super::*;
+    use crate::PeerNode;
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// Starts a node that responds to every backup array write request with the pairs it received,
+    /// and to every export request with large pairs in both storages.
+    async fn start_echo_node() -> PeerNode {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, mut rx) = mpsc::unbounded_channel::<Connection>();
+
+        tokio::task::spawn(async move {
+            while let Ok((stream, address)) = listener.accept().await {
+                tokio::task::spawn(handle_accepted_stream(stream, address, tx.clone()));
+            }
+        });
+
+        tokio::task::spawn(async move {
+            while let Some(mut connection) = rx.recv().await {
+                tokio::task::spawn(async move {
+                    let mut kv_pairs = match connection.read_message().await {
+                        Ok(Message::BackupArrayWrite { kv_pairs }) => kv_pairs,
+                        Ok(Message::ExportRequest { .. }) => {
+                            let kv_pairs = large_kv_pairs();
+                            let mut sender = ExportSender::new(&mut connection);
+                            for kv_pair in &kv_pairs {
+                                sender
+                                    .send(ExportedStorage::Leader, std::slice::from_ref(kv_pair))
+                                    .await
+                                    .unwrap();
+                            }
+                            sender
+                                .send(ExportedStorage::Backup, &kv_pairs[..1])
+                                .await
+                                .unwrap();
+                            sender.finish().await.unwrap();
+                            return;
+                        }
+                        _ => return,
+                    };
+                    let mut receiver = KvPairsReceiver::following_request(&mut connection);
+                    while let Some(chunk) = receiver.next_chunk().await.unwrap() {
+                        kv_pairs.extend(chunk);
+                    }
+                    let version_high_water = receiver.version_high_water().unwrap_or(0);
+
+                    let mut sender = KvPairsSender::new(&mut connection);
+                    for (key, entry) in kv_pairs {
+                        sender.push(key, entry);
+                        sender.send_full_chunk().await.unwrap();
+                    }
+                    sender.finish(version_high_water).await.unwrap();
+                });
+            }
+        });
+
+        PeerNode {
+            id: 1,
+            ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+        }
+    }
+
+    /// Returns pairs whose encoded length spans several chunks.
+    fn large_kv_pairs() -> Vec<(u64, Entry)> {
+        (0..40)
+            .map(|key| (key, Entry::new(vec![key as u8; 100_000])))
+            .collect()
+    }
+
+    /// Receives the pairs sent as the response, the number of messages they were sent in
+    /// and the version high-water mark that ended the transfer.
+    async fn receive_all(connection: &mut Connection) -> (Vec<(u64, Entry)>, usize, Option<u64>) {
+        let mut receiver = KvPairsReceiver::new(connection);
+        let mut kv_pairs = Vec::new();
+        let mut chunks = 0;
+        while let Some(chunk) = receiver.next_chunk().await.unwrap() {
+            kv_pairs.extend(chunk);
+            chunks += 1;
+        }
+        (kv_pairs, chunks, receiver.version_high_water())
+    }
+
+    #[tokio::test]
+    async fn chunked_transfer_over_link() {
+        let node = start_echo_node().await;
+        let pool = ConnectionPool::new();
+        assert!(pool.supports_chunked_transfers(&node).await);
+
+        let request = Message::BackupArrayWrite {
+            kv_pairs: Vec::new(),
+        };
+        let mut connection = pool.open(&node, &request).await.unwrap();
+        assert!(connection.supports_chunked_transfers());
+
+        let mut sender = KvPairsSender::new(&mut connection);
+        for (key, entry) in large_kv_pairs() {
+            sender.push(key, entry);
+            sender.send_full_chunk().await.unwrap();
+        }
+        sender.finish(42).await.unwrap();
+
+        let (kv_pairs, chunks, version_high_water) = receive_all(&mut connection).await;
+        assert_eq!(kv_pairs, large_kv_pairs());
+        assert!(chunks > 1);
+        assert_eq!(version_high_water, Some(42));
+    }
+
+    #[tokio::test]
+    async fn single_message_without_chunk_support() {
+        let node = start_echo_node().await;
+
+        let request = Message::BackupArrayWrite {
+            kv_pairs: large_kv_pairs(),
+        };
+        let mut connection = Connection::new(node.ip_address, node.port, &request)
+            .await
+            .unwrap();
+        assert!(!connection.supports_chunked_transfers());
+
+        let (kv_pairs, chunks, version_high_water) = receive_all(&mut connection).await;
+        assert_eq!(kv_pairs, large_kv_pairs());
+        assert_eq!(chunks, 1);
+        assert_eq!(version_high_water, None);
+    }
+
+    #[tokio::test]
+    async fn empty_chunked_transfer() {
+        let node = start_echo_node().await;
+        let pool = ConnectionPool::new();
+
+        let request = Message::BackupArrayWrite {
+            kv_pairs: Vec::new(),
+        };
+        let mut connection = pool.open(&node, &request).await.unwrap();
+        KvPairsSender::new(&mut connection).finish(7).await.unwrap();
+
+        assert_eq!(receive_all(&mut connection).await, (Vec::new(), 0, Some(7)));
+    }
+
+    #[tokio::test]
+    async fn chunked_export() {
+        let node = start_echo_node().await;
+
+        let request = Message::ExportRequest {
+            include_backups: true,
+        };
+        let mut connection = Connection::new(node.ip_address, node.port, &request)
+            .await
+            .unwrap();
+
+        let mut items = Vec::new();
+        let mut chunks = 0;
+        while let Message::Response(chunk) = connection.read_message().await.unwrap() {
+            if chunk.is_empty() {
+                break;
+            }
+            items.extend(chunk);
+            chunks += 1;
+        }
+
+        let kv_pairs = large_kv_pairs();
+        let mut expected = Vec::new();
+        for (storage, kv_pair) in kv_pairs
+            .iter()
+            .map(|kv_pair| (0, kv_pair))
+            .chain([(1, &kv_pairs[0])])
+        {
+            expected.push(storage);
+            expected.extend(encode_kv_pairs(
+                std::slice::from_ref(kv_pair),
+                HISTORY_PROTOCOL_VERSION,
+            ));
+        }
+        assert_eq!(items, expected);
+        assert!(chunks > 1);
+    }
+}
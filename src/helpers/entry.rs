@@ -0,0 +1,219 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Length of the metadata of an entry without its past versions.
+const FIXED_METADATA_LENGTH: usize = 25;
+
+/// Flag of an entry that marks its key as deleted.
+pub const DELETED_FLAG: u8 = 1 << 0;
+
+/// A value together with the metadata that is stored and replicated with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub value: Vec<u8>,
+    /// Milliseconds since the Unix epoch from which on the value is expired, `None` if it never expires.
+    pub expires_at: Option<u64>,
+    /// `true` for a tombstone that keeps a deleted key from coming back from older copies.
+    pub deleted: bool,
+    /// Number of the write that stored the entry, growing with every write and delete of the key.
+    /// `0` for an entry that has not been given a version by a leader.
+    pub version: u64,
+    /// Milliseconds since the Unix epoch at which the leader stored the entry,
+    /// `0` for an entry that has not been stored by a leader.
+    pub written_at: u64,
+    /// The past versions of the key from the newest to the oldest, without histories of their own.
+    pub history: Vec<Entry>,
+}
+
+impl Entry {
+    /// Creates an entry that never expires.
+    pub fn new(value: Vec<u8>) -> Entry {
+        Entry::with_ttl(value, None)
+    }
+
+    /// Creates an entry that expires after the given time to live, or never if it is `None`.
+    pub fn with_ttl(value: Vec<u8>, ttl: Option<Duration>) -> Entry {
+        Entry {
+            value,
+            expires_at: ttl.map(|ttl| now_millis().saturating_add(ttl.as_millis() as u64)),
+            deleted: false,
+            version: 0,
+            written_at: 0,
+            history: Vec::new(),
+        }
+    }
+
+    /// Creates a tombstone that is removed like an expired value after the given lifetime.
+    pub fn tombstone(lifetime: Duration) -> Entry {
+        Entry {
+            deleted: true,
+            ..Entry::with_ttl(Vec::new(), Some(lifetime))
+        }
+    }
+
+    /// Returns `true` if the entry has expired at the given time in milliseconds since the Unix epoch.
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        is_expired_at(self.expires_at, now)
+    }
+
+    /// Returns the value, or `None` if the key has been deleted.
+    pub fn into_value(self) -> Option<Vec<u8>> {
+        (!self.deleted).then_some(self.value)
+    }
+
+    /// Encodes the metadata of the entry: the fixed metadata, the number of past versions,
+    /// 4 big-endian bytes, and for each past version its fixed metadata,
+    /// the length of its value, 4 big-endian bytes, and the value.
+    pub fn encode_metadata(&self) -> Vec<u8> {
+        let mut metadata = Vec::with_capacity(self.metadata_length());
+        metadata.extend_from_slice(&self.encode_fixed_metadata());
+        metadata.extend_from_slice(&(self.history.len() as u32).to_be_bytes());
+        for past in &self.history {
+            metadata.extend_from_slice(&past.encode_fixed_metadata());
+            metadata.extend_from_slice(&(past.value.len() as u32).to_be_bytes());
+            metadata.extend_from_slice(&past.value);
+        }
+        metadata
+    }
+
+    /// Returns the length of the metadata encoded by `encode_metadata`.
+    pub fn metadata_length(&self) -> usize {
+        let history_length: usize = self
+            .history
+            .iter()
+            .map(|past| FIXED_METADATA_LENGTH + 4 + past.value.len())
+            .sum();
+        FIXED_METADATA_LENGTH + 4 + history_length
+    }
+
+    /// Decodes the metadata encoded by `encode_metadata` from the start of the given bytes.
+    /// Returns an entry with an empty value and the length of the metadata,
+    /// or `None` if the bytes end before the metadata.
+    pub fn decode_metadata(bytes: &[u8]) -> Option<(Entry, usize)> {
+        let mut rest = bytes;
+        let mut entry = Entry::decode_fixed_metadata(take(&mut rest, FIXED_METADATA_LENGTH)?);
+        let history_length = u32::from_be_bytes(take(&mut rest, 4)?.try_into().unwrap());
+        for _ in 0..history_length {
+            let mut past = Entry::decode_fixed_metadata(take(&mut rest, FIXED_METADATA_LENGTH)?);
+            let value_length = u32::from_be_bytes(take(&mut rest, 4)?.try_into().unwrap());
+            past.value = take(&mut rest, value_length as usize)?.to_vec();
+            entry.history.push(past);
+        }
+        Some((entry, bytes.len() - rest.len()))
+    }
+
+    /// Encodes the expiry time, 8 big-endian bytes, `0` if the entry never expires,
+    /// the version and the write time, 8 big-endian bytes each, and the flags, one byte.
+    fn encode_fixed_metadata(&self) -> [u8; FIXED_METADATA_LENGTH] {
+        let mut metadata = [0; FIXED_METADATA_LENGTH];
+        metadata[..8].copy_from_slice(&self.expires_at.unwrap_or(0).to_be_bytes());
+        metadata[8..16].copy_from_slice(&self.version.to_be_bytes());
+        metadata[16..24].copy_from_slice(&self.written_at.to_be_bytes());
+        if self.deleted {
+            metadata[24] |= DELETED_FLAG;
+        }
+        metadata
+    }
+
+    /// Creates an entry with an empty value and no history from the metadata encoded by `encode_fixed_metadata`.
+    /// Unknown flags are ignored.
+    fn decode_fixed_metadata(metadata: &[u8]) -> Entry {
+        let field =
+            |range: std::ops::Range<usize>| u64::from_be_bytes(metadata[range].try_into().unwrap());
+        Entry {
+            value: Vec::new(),
+            expires_at: Some(field(0..8)).filter(|expires_at| *expires_at != 0),
+            deleted: metadata[24] & DELETED_FLAG != 0,
+            version: field(8..16),
+            written_at: field(16..24),
+            history: Vec::new(),
+        }
+    }
+}
+
+/// Removes and returns the given number of bytes from the start of the given bytes,
+/// or returns `None` if there are not enough bytes.
+fn take<'a>(bytes: &mut &'a [u8], count: usize) -> Option<&'a [u8]> {
+    let (taken, rest) = bytes.split_at_checked(count)?;
+    *bytes = rest;
+    Some(taken)
+}
+
+/// Returns `true` if a value expiring at the given time has expired at the other given time.
+pub fn is_expired_at(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        let entry = Entry::with_ttl(vec![1], Some(Duration::from_secs(60)));
+        let expires_at = entry.expires_at.unwrap();
+        assert!(!entry.is_expired_at(expires_at - 1));
+        assert!(entry.is_expired_at(expires_at));
+        assert!(!Entry::new(vec![1]).is_expired_at(u64::MAX));
+    }
+
+    /// Encodes the metadata of the given entry and decodes it back with the value of the entry.
+    fn reencoded(entry: &Entry) -> Entry {
+        let metadata = entry.encode_metadata();
+        assert_eq!(metadata.len(), entry.metadata_length());
+        let (mut decoded, length) = Entry::decode_metadata(&metadata).unwrap();
+        assert_eq!(length, metadata.len());
+        decoded.value = entry.value.clone();
+        decoded
+    }
+
+    #[test]
+    fn metadata_encoding() {
+        let entry = Entry {
+            version: 3,
+            written_at: 1000,
+            ..Entry::with_ttl(vec![1], Some(Duration::from_secs(60)))
+        };
+        assert_eq!(reencoded(&entry), entry);
+
+        let tombstone = Entry::tombstone(Duration::from_secs(60));
+        assert_eq!(reencoded(&tombstone), tombstone);
+        assert_eq!(tombstone.into_value(), None);
+
+        let entry = Entry::new(vec![1]);
+        assert_eq!(entry.encode_metadata(), vec![0; FIXED_METADATA_LENGTH + 4]);
+        assert_eq!(entry.into_value(), Some(vec![1]));
+    }
+
+    #[test]
+    fn history_encoding() {
+        let entry = Entry {
+            version: 3,
+            history: vec![
+                Entry {
+                    version: 2,
+                    ..Entry::tombstone(Duration::from_secs(60))
+                },
+                Entry {
+                    version: 1,
+                    ..Entry::new(vec![1, 2])
+                },
+            ],
+            ..Entry::new(vec![3])
+        };
+        assert_eq!(reencoded(&entry), entry);
+
+        let metadata = entry.encode_metadata();
+        assert_eq!(
+            Entry::decode_metadata(&metadata[..metadata.len() - 1]),
+            None
+        );
+        assert_eq!(Entry::decode_metadata(&[0; FIXED_METADATA_LENGTH]), None);
+    }
+}
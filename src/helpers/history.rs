@@ -0,0 +1,153 @@
+use crate::helpers::communication::ReadPoint;
+use crate::helpers::entry::Entry;
+use std::time::Duration;
+
+/// How many past versions of a key the leader keeps with the current entry of the key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HistoryRetention {
+    /// The largest number of past versions kept per key, `None` for no limit.
+    pub versions: Option<usize>,
+    /// How long a past version is kept after it has been replaced, `None` for no limit.
+    pub window: Option<Duration>,
+}
+
+impl HistoryRetention {
+    /// Returns `true` if any past versions are kept, that is, if either limit is set.
+    pub fn is_enabled(&self) -> bool {
+        self.versions.is_some() || self.window.is_some()
+    }
+
+    /// Moves the given previous entry and its history to the history of the given new entry,
+    /// leaving out the past versions that are beyond the limits at the given time.
+    pub fn supersede(&self, entry: &mut Entry, mut previous: Entry, now: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let older = std::mem::take(&mut previous.history);
+        let oldest_kept_replacement = self
+            .window
+            .map(|window| now.saturating_sub(window.as_millis() as u64));
+
+        // each past version was replaced when the next newer version was written
+        let mut replaced_at = entry.written_at;
+        for past in std::iter::once(previous).chain(older) {
+            if oldest_kept_replacement.is_some_and(|oldest| replaced_at < oldest)
+                || self
+                    .versions
+                    .is_some_and(|versions| entry.history.len() >= versions)
+            {
+                break;
+            }
+            replaced_at = past.written_at;
+            entry.history.push(past);
+        }
+    }
+}
+
+/// Returns the version of the given entry, the entry itself or one of its past versions,
+/// that the key had at the given read point, or `None` if it is not kept.
+/// The version is returned with the time at which it is read: the requested time,
+/// or the last moment at which the requested version was the current one.
+pub fn find_version(mut entry: Entry, at: ReadPoint, now: u64) -> Option<(Entry, u64)> {
+    let history = std::mem::take(&mut entry.history);
+
+    // each version was the current one until the next newer version was written
+    let mut last_current_at = now;
+    for version in std::iter::once(entry).chain(history) {
+        let found = match at {
+            ReadPoint::Version(number) => version.version == number,
+            ReadPoint::Time(time) => version.written_at <= time,
+        };
+        if found {
+            let read_at = match at {
+                ReadPoint::Version(_) => last_current_at,
+                ReadPoint::Time(time) => time.min(now),
+            };
+            return Some((version, read_at));
+        }
+        last_current_at = version.written_at.saturating_sub(1);
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn written(value: u8, version: u64, written_at: u64) -> Entry {
+        Entry {
+            version,
+            written_at,
+            ..Entry::new(vec![value])
+        }
+    }
+
+    #[test]
+    fn retention_limits() {
+        let superseded = |retention: HistoryRetention| {
+            let mut entry = written(1, 1, 1000);
+            for version in 2..=4 {
+                let mut next = written(version as u8, version, version * 1000);
+                retention.supersede(&mut next, entry, version * 1000);
+                entry = next;
+            }
+            entry
+                .history
+                .iter()
+                .map(|past| past.version)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(superseded(HistoryRetention::default()), Vec::<u64>::new());
+        assert_eq!(
+            superseded(HistoryRetention {
+                versions: Some(2),
+                window: None,
+            }),
+            vec![3, 2]
+        );
+        assert_eq!(
+            superseded(HistoryRetention {
+                versions: None,
+                window: Some(Duration::from_millis(1500)),
+            }),
+            vec![3, 2]
+        );
+        assert_eq!(
+            superseded(HistoryRetention {
+                versions: Some(1),
+                window: Some(Duration::from_secs(60)),
+            }),
+            vec![3]
+        );
+    }
+
+    #[test]
+    fn version_lookup() {
+        let entry = Entry {
+            history: vec![
+                Entry {
+                    expires_at: Some(2500),
+                    ..written(2, 2, 2000)
+                },
+                written(1, 1, 1000),
+            ],
+            ..written(3, 3, 3000)
+        };
+        let found = |at| {
+            find_version(entry.clone(), at, 4000)
+                .map(|(version, read_at)| (version.version, read_at))
+        };
+
+        assert_eq!(found(ReadPoint::Version(3)), Some((3, 4000)));
+        assert_eq!(found(ReadPoint::Version(2)), Some((2, 2999)));
+        assert_eq!(found(ReadPoint::Version(1)), Some((1, 1999)));
+        assert_eq!(found(ReadPoint::Version(4)), None);
+        assert_eq!(found(ReadPoint::Time(5000)), Some((3, 4000)));
+        assert_eq!(found(ReadPoint::Time(2999)), Some((2, 2999)));
+        assert_eq!(found(ReadPoint::Time(1000)), Some((1, 1000)));
+        assert_eq!(found(ReadPoint::Time(999)), None);
+    }
+}
@@ -1,2 +1,6 @@
 pub mod communication;
+pub mod entry;
+pub mod history;
 pub mod neighbors;
+pub mod storage;
+pub mod stored_value;
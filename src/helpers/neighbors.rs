@@ -1,12 +1,10 @@
 use crate::PeerNode;
+use std::ops::RangeInclusive;
 
 /// Finds the neighbors of this node and wraps around the ring if necessary.
 /// Does not return this node itself in any case.
 /// If greater and smaller neighbour would be the same, it is only returned once.
-pub fn find_neighbors_wrapping(
-    this_node_id: u64,
-    node_list: &Vec<PeerNode>,
-) -> [Option<PeerNode>; 2] {
+pub fn find_neighbors_wrapping(this_node_id: u64, node_list: &[PeerNode]) -> [Option<PeerNode>; 2] {
     // smaller neighbor = node with greatest ID smaller than self
     // if not found, use the greatest node
     let greatest_node = node_list
@@ -76,6 +74,23 @@ pub fn find_neighbors_nonwrapping(
     (smaller_neighbor.cloned(), greater_neighbor.cloned())
 }
 
+/// Returns the inclusive range of keys for which the given node is the leader.
+/// The node itself does not need to be in the node list.
+pub fn leader_key_range(node_id: u64, node_list: &[PeerNode]) -> RangeInclusive<u64> {
+    let (smaller_neighbor, greater_neighbor) = find_neighbors_nonwrapping(node_id, node_list);
+
+    let lower_bound = match smaller_neighbor {
+        Some(node) => node.id + 1,
+        None => 0,
+    };
+    let upper_bound = match greater_neighbor {
+        Some(_) => node_id,
+        None => u64::MAX,
+    };
+
+    lower_bound..=upper_bound
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -85,19 +100,23 @@ mod test {
         let node_list = vec![
             PeerNode {
                 id: 100,
-                ip_address: "192.168.0.100".to_string(),
+                ip_address: "192.168.0.100".parse().unwrap(),
+                port: 52525,
             },
             PeerNode {
                 id: 200,
-                ip_address: "192.168.0.200".to_string(),
+                ip_address: "192.168.0.200".parse().unwrap(),
+                port: 52525,
             },
             PeerNode {
                 id: 150,
-                ip_address: "192.168.0.150".to_string(),
+                ip_address: "192.168.0.150".parse().unwrap(),
+                port: 52525,
             },
             PeerNode {
                 id: 10,
-                ip_address: "192.168.0.10".to_string(),
+                ip_address: "192.168.0.10".parse().unwrap(),
+                port: 52525,
             },
         ];
         let result = find_neighbors_wrapping(200, &node_list);
@@ -110,11 +129,13 @@ mod test {
         let node_list = vec![
             PeerNode {
                 id: 100,
-                ip_address: "192.168.0.100".to_string(),
+                ip_address: "192.168.0.100".parse().unwrap(),
+                port: 52525,
             },
             PeerNode {
                 id: 200,
-                ip_address: "192.168.0.200".to_string(),
+                ip_address: "192.168.0.200".parse().unwrap(),
+                port: 52525,
             },
         ];
         let result = find_neighbors_wrapping(200, &node_list);
@@ -122,11 +143,32 @@ mod test {
         assert!(result[1].is_none());
     }
 
+    #[test]
+    fn leader_key_range_finding() {
+        let node_list = vec![
+            PeerNode {
+                id: 100,
+                ip_address: "192.168.0.100".parse().unwrap(),
+                port: 52525,
+            },
+            PeerNode {
+                id: 200,
+                ip_address: "192.168.0.200".parse().unwrap(),
+                port: 52525,
+            },
+        ];
+        assert_eq!(leader_key_range(100, &node_list), 0..=100);
+        assert_eq!(leader_key_range(150, &node_list), 101..=150);
+        assert_eq!(leader_key_range(200, &node_list), 101..=u64::MAX);
+        assert_eq!(leader_key_range(200, &[]), 0..=u64::MAX);
+    }
+
     #[test]
     fn alone_neighbor_wrapping_finding() {
         let node_list = vec![PeerNode {
             id: 200,
-            ip_address: "192.168.0.200".to_string(),
+            ip_address: "192.168.0.200".parse().unwrap(),
+            port: 52525,
         }];
         let result = find_neighbors_wrapping(200, &node_list);
         assert!(result[0].is_none());
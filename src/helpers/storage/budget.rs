@@ -0,0 +1,412 @@
+use super::{StorageEngine, StorageError, StorageStats};
+use crate::helpers::entry::Entry;
+use crate::helpers::stored_value::StoredValue;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The number of bytes counted for every key in addition to the length of its value.
+const KEY_OVERHEAD: usize = 8;
+
+/// What a node does when a write would exceed its memory budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BudgetPolicy {
+    /// Reject the write with an error response.
+    #[default]
+    Reject,
+    /// Remove the least recently used values of the leader storage until the write fits.
+    Evict,
+}
+
+/// Limits the total size of the leader and backup storages of a node.
+/// Every key counts the length of its value and a fixed overhead.
+/// With the evict policy, the budget also keeps the order in which the keys of all leader storage shards
+/// were last used, so that the least recently used values of the whole leader storage are evicted first.
+/// Each shard keeps the order of its own keys behind a lock of its own, so that reads of different shards
+/// do not wait for each other, and only finding the value to evict looks at all of them.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    used: AtomicUsize,
+    /// The recencies of the leader storage shards, with the evict policy.
+    recencies: Option<Mutex<Vec<Arc<Mutex<Recency>>>>>,
+    /// The tick of the next use of a key, shared by the shards so that their recencies can be compared.
+    next_tick: AtomicU64,
+}
+
+impl MemoryBudget {
+    /// Creates a budget of the given number of bytes, or an unlimited one for `None`.
+    pub fn new(limit: Option<usize>, policy: BudgetPolicy) -> Arc<MemoryBudget> {
+        let recencies = match (limit, policy) {
+            (Some(_), BudgetPolicy::Evict) => Some(Mutex::new(Vec::new())),
+            _ => None,
+        };
+        Arc::new(MemoryBudget {
+            limit,
+            used: AtomicUsize::new(0),
+            recencies,
+            next_tick: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the number of bytes the budget allows, or `None` if it is unlimited.
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Returns the number of bytes currently used by the storages.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Wraps the given storage engine so that its contents are counted in this budget.
+    pub fn accounted(self: &Arc<Self>, engine: Box<dyn StorageEngine>) -> Box<dyn StorageEngine> {
+        Box::new(AccountedEngine {
+            engine,
+            budget: Arc::clone(self),
+            sizes: HashMap::new(),
+            recency: None,
+        })
+    }
+
+    /// Wraps the given shard of the leader storage like `accounted`,
+    /// also keeping track of when its keys were last used so that their values may be evicted.
+    pub fn accounted_evictable(
+        self: &Arc<Self>,
+        engine: Box<dyn StorageEngine>,
+    ) -> Box<dyn StorageEngine> {
+        Box::new(AccountedEngine {
+            engine,
+            budget: Arc::clone(self),
+            sizes: HashMap::new(),
+            recency: self.recencies.as_ref().map(|recencies| {
+                let recency = Arc::new(Mutex::new(Recency::default()));
+                recencies.lock().unwrap().push(Arc::clone(&recency));
+                recency
+            }),
+        })
+    }
+
+    /// Counts a value of the given size in place of one of the released size if the result fits the budget,
+    /// atomically, so that concurrent writes to different storages cannot together exceed it.
+    /// Returns `false` without counting anything if it does not fit.
+    fn reserve(&self, additional: usize, released: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                let used = used - released + additional;
+                match self.limit {
+                    Some(limit) if used > limit => None,
+                    _ => Some(used),
+                }
+            })
+            .is_ok()
+    }
+
+    fn charge(&self, additional: usize, released: usize) {
+        self.used.fetch_add(additional, Ordering::Relaxed);
+        self.used.fetch_sub(released, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if a value of the additional size replacing one of the released size of the given key
+    /// would fit the budget after evicting every other evictable value.
+    /// The used bytes and the evictable keys are updated in separate steps,
+    /// so a value being released concurrently may still be counted as evictable but no longer as used.
+    fn fits_after_evicting(&self, key: u64, additional: usize, released: usize) -> bool {
+        let Some(limit) = self.limit else {
+            return false;
+        };
+        let evictable: usize = self
+            .recencies()
+            .iter()
+            .map(|recency| recency.lock().unwrap().bytes_except(key))
+            .sum();
+        (self.used() + additional).saturating_sub(released + evictable) <= limit
+    }
+
+    /// Returns the least recently used evictable key other than the given one.
+    pub(super) fn least_recent_except(&self, key: u64) -> Option<u64> {
+        self.recencies()
+            .iter()
+            .filter_map(|recency| recency.lock().unwrap().least_recent_except(key))
+            .min()
+            .map(|(_, key)| key)
+    }
+
+    /// Forgets the given key, which no longer has a value to evict.
+    pub(super) fn forget(&self, key: u64) {
+        for recency in self.recencies() {
+            recency.lock().unwrap().remove(key);
+        }
+    }
+
+    /// Returns the recencies of the leader storage shards, which are empty without the evict policy.
+    fn recencies(&self) -> Vec<Arc<Mutex<Recency>>> {
+        match &self.recencies {
+            Some(recencies) => recencies.lock().unwrap().clone(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// The order in which the evictable keys of a leader storage shard were last used
+/// and the sizes counted for them.
+#[derive(Debug, Default)]
+struct Recency {
+    ticks: HashMap<u64, (u64, usize)>,
+    keys: BTreeMap<u64, u64>,
+    bytes: usize,
+}
+
+impl Recency {
+    /// Marks the key as used at the given tick, with the given new size if it has been replaced.
+    fn touch(&mut self, key: u64, size: Option<usize>, tick: u64) {
+        let previous_size = self.remove(key);
+        let Some(size) = size.or(previous_size) else {
+            return;
+        };
+        self.ticks.insert(key, (tick, size));
+        self.keys.insert(tick, key);
+        self.bytes += size;
+    }
+
+    /// Removes the key and returns its size, if it was kept.
+    fn remove(&mut self, key: u64) -> Option<usize> {
+        let (tick, size) = self.ticks.remove(&key)?;
+        self.keys.remove(&tick);
+        self.bytes -= size;
+        Some(size)
+    }
+
+    /// Returns the least recently used key other than the given one and the tick of its last use.
+    fn least_recent_except(&self, key: u64) -> Option<(u64, u64)> {
+        self.keys
+            .iter()
+            .map(|(tick, other)| (*tick, *other))
+            .find(|(_, other)| *other != key)
+    }
+
+    /// Returns the total size of the keys other than the given one.
+    fn bytes_except(&self, key: u64) -> usize {
+        self.bytes - self.ticks.get(&key).map_or(0, |(_, size)| *size)
+    }
+}
+
+/// A storage engine that counts the size of the wrapped engine in the memory budget of the node
+/// and, with the evict policy, keeps track of when the keys of a leader storage shard were last used.
+struct AccountedEngine {
+    engine: Box<dyn StorageEngine>,
+    budget: Arc<MemoryBudget>,
+    sizes: HashMap<u64, usize>,
+    /// The recency of the keys of this engine, if its values may be evicted.
+    recency: Option<Arc<Mutex<Recency>>>,
+}
+
+impl AccountedEngine {
+    fn touch(&self, key: u64, size: Option<usize>) {
+        if let Some(recency) = &self.recency {
+            let tick = self.budget.next_tick.fetch_add(1, Ordering::Relaxed);
+            recency.lock().unwrap().touch(key, size, tick);
+        }
+    }
+
+    fn release(&mut self, key: u64) {
+        if let Some(size) = self.sizes.remove(&key) {
+            self.budget.charge(0, size);
+        }
+        if let Some(recency) = &self.recency {
+            recency.lock().unwrap().remove(key);
+        }
+    }
+
+    /// Releases the given keys that are no longer in the wrapped engine.
+    fn release_removed(&mut self, keys: &[u64]) {
+        for key in keys {
+            if self.sizes.contains_key(key) && self.engine.keys(*key..=*key).is_empty() {
+                self.release(*key);
+            }
+        }
+    }
+
+    /// Stores a value whose size has already been counted in the budget in place of the previous one.
+    fn put_counted(
+        &mut self,
+        key: u64,
+        value: StoredValue,
+        size: usize,
+        previous: usize,
+    ) -> Result<(), StorageError> {
+        if let Err(error) = self.engine.put(key, value) {
+            self.budget.charge(previous, size);
+            return Err(error);
+        }
+        self.sizes.insert(key, size);
+        self.touch(key, Some(size));
+        Ok(())
+    }
+}
+
+impl StorageEngine for AccountedEngine {
+    fn get(&self, key: u64) -> Result<Option<StoredValue>, StorageError> {
+        let value = self.engine.get(key)?;
+        if value.is_some() {
+            self.touch(key, None);
+        }
+        Ok(value)
+    }
+
+    fn put(&mut self, key: u64, value: StoredValue) -> Result<(), StorageError> {
+        let size = value.value_length() + KEY_OVERHEAD;
+        let previous = self.sizes.get(&key).copied().unwrap_or(0);
+        self.budget.charge(size, previous);
+        self.put_counted(key, value, size, previous)
+    }
+
+    fn put_within_budget(&mut self, key: u64, value: StoredValue) -> Result<(), StorageError> {
+        let size = value.value_length() + KEY_OVERHEAD;
+        let previous = self.sizes.get(&key).copied().unwrap_or(0);
+        if self.budget.reserve(size, previous) {
+            return self.put_counted(key, value, size, previous);
+        }
+
+        // the values to evict may be in other shards, whose locks the caller takes
+        if self.recency.is_some() && self.budget.fits_after_evicting(key, size, previous) {
+            return Err(StorageError::EvictionNeeded);
+        }
+        Err(StorageError::BudgetExceeded)
+    }
+
+    fn delete(&mut self, key: u64) -> Result<Option<StoredValue>, StorageError> {
+        // a value that could not be decoded may have been removed anyway
+        let result = self.engine.delete(key);
+        self.release_removed(&[key]);
+        result
+    }
+
+    fn keys(&self, key_range: RangeInclusive<u64>) -> Vec<u64> {
+        self.engine.keys(key_range)
+    }
+
+    fn expired_keys(&self, now: u64) -> Vec<u64> {
+        self.engine.expired_keys(now)
+    }
+
+    fn copy_range(
+        &self,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, Entry)>, StorageError> {
+        // copies for transfers and snapshots are not uses of the values
+        self.engine.copy_range(key_range)
+    }
+
+    fn extract_range(
+        &mut self,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, StoredValue)>, StorageError> {
+        let keys = self.engine.keys(key_range.clone());
+        let result = self.engine.extract_range(key_range);
+        self.release_removed(&keys);
+        result
+    }
+
+    fn stats(&self) -> StorageStats {
+        self.engine.stats()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::MemoryEngine;
+    use super::*;
+
+    fn value(length: usize) -> StoredValue {
+        StoredValue::new(Entry::new(vec![0; length]))
+    }
+
+    #[test]
+    fn rejection_over_budget() {
+        let budget = MemoryBudget::new(Some(3 * KEY_OVERHEAD + 30), BudgetPolicy::Reject);
+        let mut leader = budget.accounted(Box::new(MemoryEngine::new()));
+        let mut backup = budget.accounted(Box::new(MemoryEngine::new()));
+
+        backup.put(1, value(10)).unwrap();
+        leader.put_within_budget(2, value(10)).unwrap();
+        assert!(matches!(
+            leader.put_within_budget(3, value(11)),
+            Err(StorageError::BudgetExceeded)
+        ));
+        assert_eq!(budget.used(), 2 * KEY_OVERHEAD + 20);
+
+        // replacing a value only counts the difference
+        leader.put_within_budget(2, value(21)).unwrap();
+        assert!(leader.put_within_budget(2, value(29)).is_err());
+
+        backup.delete(1).unwrap();
+        leader.put_within_budget(3, value(11)).unwrap();
+        assert_eq!(budget.used(), 2 * KEY_OVERHEAD + 32);
+    }
+
+    #[test]
+    fn eviction_across_engines() {
+        let budget = MemoryBudget::new(Some(3 * (KEY_OVERHEAD + 10)), BudgetPolicy::Evict);
+        let mut first = budget.accounted_evictable(Box::new(MemoryEngine::new()));
+        let mut second = budget.accounted_evictable(Box::new(MemoryEngine::new()));
+
+        first.put_within_budget(1, value(10)).unwrap();
+        second.put_within_budget(2, value(10)).unwrap();
+        first.put_within_budget(3, value(10)).unwrap();
+        first.get(1).unwrap();
+
+        // the engine of the write cannot evict the values of the other one itself
+        assert!(matches!(
+            first.put_within_budget(4, value(10)),
+            Err(StorageError::EvictionNeeded)
+        ));
+        assert_eq!(budget.least_recent_except(4), Some(2));
+        second.delete(2).unwrap();
+        first.put_within_budget(4, value(10)).unwrap();
+        assert_eq!(budget.least_recent_except(4), Some(3));
+
+        assert!(matches!(
+            second.put_within_budget(5, value(100)),
+            Err(StorageError::BudgetExceeded)
+        ));
+        assert_eq!(budget.used(), 3 * (KEY_OVERHEAD + 10));
+    }
+
+    #[test]
+    fn eviction_check_during_release() {
+        let budget = MemoryBudget::new(Some(KEY_OVERHEAD + 10), BudgetPolicy::Evict);
+        let mut leader = budget.accounted_evictable(Box::new(MemoryEngine::new()));
+        leader.put_within_budget(1, value(10)).unwrap();
+
+        // the value is no longer counted as used but is still evictable, as while it is being deleted
+        budget.charge(0, KEY_OVERHEAD + 10);
+        assert!(budget.fits_after_evicting(2, KEY_OVERHEAD + 10, 0));
+        assert!(!budget.fits_after_evicting(2, 100, 0));
+    }
+
+    #[test]
+    fn concurrent_reservations() {
+        const VALUES: usize = 50;
+        let budget = MemoryBudget::new(Some(VALUES * (KEY_OVERHEAD + 10)), BudgetPolicy::Reject);
+        let stored: usize = std::thread::scope(|scope| {
+            let writers: Vec<_> = (0..8)
+                .map(|_| {
+                    let mut engine = budget.accounted(Box::new(MemoryEngine::new()));
+                    scope.spawn(move || {
+                        (0..VALUES as u64)
+                            .filter(|key| engine.put_within_budget(*key, value(10)).is_ok())
+                            .count()
+                    })
+                })
+                .collect();
+            writers
+                .into_iter()
+                .map(|writer| writer.join().unwrap())
+                .sum()
+        });
+        assert_eq!(stored, VALUES);
+        assert_eq!(budget.used(), VALUES * (KEY_OVERHEAD + 10));
+    }
+}
@@ -0,0 +1,137 @@
+use super::{StorageEngine, StorageError, StorageStats};
+use crate::helpers::entry::is_expired_at;
+use crate::helpers::stored_value::StoredValue;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// Name of the file that marks a directory as created by a disk storage engine.
+const MARKER_FILE_NAME: &str = ".ds-disk-storage";
+
+/// A storage engine that keeps every value in its own file under a directory.
+/// Only the keys, ordered, the value lengths and the expiry times are kept in memory.
+#[derive(Debug)]
+pub struct DiskEngine {
+    directory: PathBuf,
+    value_lengths: BTreeMap<u64, usize>,
+    expiry_times: HashMap<u64, u64>,
+    value_bytes: usize,
+}
+
+impl DiskEngine {
+    /// Opens an empty storage in the given directory, removing the value files left there earlier.
+    /// Only a directory created by a disk storage engine is emptied, and only of its value files.
+    /// Fails if the directory has other contents but was not created by a disk storage engine.
+    pub fn open(directory: PathBuf) -> Result<DiskEngine, StorageError> {
+        match fs::read_dir(&directory) {
+            Ok(mut entries) => {
+                if directory.join(MARKER_FILE_NAME).exists() {
+                    remove_value_files(&directory)?;
+                } else if entries.next().is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!(
+                            "{} is not empty and was not created by a disk storage",
+                            directory.display()
+                        ),
+                    )
+                    .into());
+                }
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+        fs::create_dir_all(&directory)?;
+        fs::write(directory.join(MARKER_FILE_NAME), [])?;
+
+        Ok(DiskEngine {
+            directory,
+            value_lengths: BTreeMap::new(),
+            expiry_times: HashMap::new(),
+            value_bytes: 0,
+        })
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.directory.join(format!("{:016x}", key))
+    }
+}
+
+/// Returns `true` if the given file name is that of a value file, the key in 16 hexadecimal digits.
+fn is_value_file_name(name: &str) -> bool {
+    name.len() == 16 && name.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Removes the value files in the given directory, leaving any other files in place.
+fn remove_value_files(directory: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let is_value_file = entry.file_name().to_str().is_some_and(is_value_file_name);
+        if is_value_file && entry.file_type()?.is_file() {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+impl StorageEngine for DiskEngine {
+    fn get(&self, key: u64) -> Result<Option<StoredValue>, StorageError> {
+        if !self.value_lengths.contains_key(&key) {
+            return Ok(None);
+        }
+        let bytes = fs::read(self.path(key))?;
+        Ok(Some(StoredValue::from_bytes(&bytes)?))
+    }
+
+    fn put(&mut self, key: u64, value: StoredValue) -> Result<(), StorageError> {
+        fs::write(self.path(key), value.to_bytes())?;
+
+        self.value_bytes += value.value_length();
+        if let Some(previous) = self.value_lengths.insert(key, value.value_length()) {
+            self.value_bytes -= previous;
+        }
+        match value.expires_at() {
+            Some(expires_at) => self.expiry_times.insert(key, expires_at),
+            None => self.expiry_times.remove(&key),
+        };
+        Ok(())
+    }
+
+    fn delete(&mut self, key: u64) -> Result<Option<StoredValue>, StorageError> {
+        if !self.value_lengths.contains_key(&key) {
+            return Ok(None);
+        }
+
+        // the file is removed even if its value cannot be decoded, so that a corrupted value can be deleted
+        let bytes = fs::read(self.path(key))?;
+        fs::remove_file(self.path(key))?;
+
+        self.value_bytes -= self.value_lengths.remove(&key).unwrap();
+        self.expiry_times.remove(&key);
+        Ok(Some(StoredValue::from_bytes(&bytes)?))
+    }
+
+    fn keys(&self, key_range: RangeInclusive<u64>) -> Vec<u64> {
+        self.value_lengths
+            .range(key_range)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    fn expired_keys(&self, now: u64) -> Vec<u64> {
+        self.expiry_times
+            .iter()
+            .filter(|(_, expires_at)| is_expired_at(Some(**expires_at), now))
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    fn stats(&self) -> StorageStats {
+        StorageStats {
+            keys: self.value_lengths.len(),
+            value_bytes: self.value_bytes,
+        }
+    }
+}
@@ -0,0 +1,829 @@
+use super::{StorageEngine, StorageError, StorageStats};
+use crate::helpers::entry::Entry;
+use crate::helpers::stored_value::StoredValue;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use tokio::sync::oneshot;
+
+const SNAPSHOT_FILE_NAME: &str = "snapshot";
+const TEMPORARY_SNAPSHOT_FILE_NAME: &str = "snapshot.tmp";
+
+const PUT_RECORD: u8 = 1;
+const DELETE_RECORD: u8 = 2;
+
+/// Identifies the storage of a node that a logged change belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    Leader,
+    Backup,
+}
+
+impl StorageKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            StorageKind::Leader => 0,
+            StorageKind::Backup => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<StorageKind> {
+        match byte {
+            0 => Some(StorageKind::Leader),
+            1 => Some(StorageKind::Backup),
+            _ => None,
+        }
+    }
+}
+
+/// The contents of the storages of a node recovered from its data directory.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RecoveredState {
+    /// The ID of the node when the latest snapshot was taken.
+    pub node_id: Option<u64>,
+    pub leader_kv_pairs: Vec<(u64, Entry)>,
+    pub backup_kv_pairs: Vec<(u64, Entry)>,
+}
+
+/// The write-ahead log and the snapshots of the storages of a node, kept in a data directory.
+/// The log is split into numbered files, and a snapshot replaces the files
+/// that were complete when it was started.
+/// The records are written and synced by a dedicated thread, so that no task waits for the disk
+/// while it holds a thread of the runtime.
+pub struct Durability {
+    directory: PathBuf,
+    queue: Arc<LogQueue>,
+    writer: Option<JoinHandle<()>>,
+}
+
+/// The log shared by the appending storages and the writer thread.
+struct LogQueue {
+    log: Mutex<Log>,
+    /// Notifies the writer thread of new records, waiters and closing.
+    work: Condvar,
+}
+
+/// The log file that changes are currently appended to.
+#[derive(Clone)]
+struct LogFile {
+    sequence: u64,
+    file: Arc<File>,
+}
+
+/// The failure to write to the current log file, after which the file is unusable until it is rotated.
+#[derive(Debug, Clone)]
+struct LogFailure {
+    kind: io::ErrorKind,
+    message: String,
+}
+
+impl LogFailure {
+    fn to_error(&self) -> StorageError {
+        io::Error::new(self.kind, self.message.clone()).into()
+    }
+}
+
+/// The state of the log.
+/// Records are appended in batches: while the writer thread writes and syncs one batch,
+/// the records appended meanwhile are collected into the next one,
+/// so that a single sync covers all of them.
+struct Log {
+    file: LogFile,
+    /// The batches waiting for the writer thread, the last one of which may still be collected.
+    batches: VecDeque<Batch>,
+    failure: Option<LogFailure>,
+    /// Whether the writer thread should stop once the batches are written.
+    closed: bool,
+}
+
+/// Records to be written to a log file with a single sync.
+struct Batch {
+    file: LogFile,
+    records: Vec<u8>,
+    /// Notified once the records are on disk.
+    waiters: Vec<oneshot::Sender<Result<(), LogFailure>>>,
+}
+
+impl Log {
+    /// Returns the batch collected for the current log file, starting a new one if necessary.
+    fn collected_batch(&mut self) -> &mut Batch {
+        let collecting = self
+            .batches
+            .back()
+            .is_some_and(|batch| batch.file.sequence == self.file.sequence);
+        if !collecting {
+            self.batches.push_back(Batch {
+                file: self.file.clone(),
+                records: Vec::new(),
+                waiters: Vec::new(),
+            });
+        }
+        self.batches.back_mut().unwrap()
+    }
+}
+
+impl Durability {
+    /// Opens the data directory, creating it if necessary,
+    /// and recovers the storages from the latest snapshot and the log files written after it.
+    /// A partially written record at the end of the last written log file is removed,
+    /// any other record that cannot be decoded fails the recovery.
+    pub fn open(directory: &Path) -> Result<(Arc<Durability>, RecoveredState), StorageError> {
+        fs::create_dir_all(directory)?;
+
+        let mut node_id = None;
+        let mut leader = HashMap::new();
+        let mut backup = HashMap::new();
+
+        match fs::read(directory.join(SNAPSHOT_FILE_NAME)) {
+            Ok(bytes) => {
+                let snapshot = decode_snapshot(&bytes).ok_or(StorageError::Corrupted)?;
+                node_id = Some(snapshot.0);
+                leader = snapshot.1;
+                backup = snapshot.2;
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+
+        let sequences = log_sequences(directory)?;
+        // the records are written in order, so only the last file with any records
+        // may end with one cut short by a crash, even if a later file has already been created
+        let mut last_written = 0;
+        for (index, sequence) in sequences.iter().enumerate() {
+            if fs::metadata(log_path(directory, *sequence))?.len() > 0 {
+                last_written = index;
+            }
+        }
+        for (index, sequence) in sequences.iter().enumerate() {
+            let path = log_path(directory, *sequence);
+            let bytes = fs::read(&path)?;
+            let mut position = 0;
+            while position < bytes.len() {
+                let Some((kind, key, value, length)) = decode_record(&bytes[position..]) else {
+                    if index != last_written || !is_torn_record(&bytes[position..]) {
+                        println!(
+                            "log file {} is corrupted at position {}",
+                            sequence, position
+                        );
+                        return Err(StorageError::Corrupted);
+                    }
+                    println!(
+                        "removing partial record at the end of log file {}",
+                        sequence
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)?
+                        .set_len(position as u64)?;
+                    break;
+                };
+                let storage = match kind {
+                    StorageKind::Leader => &mut leader,
+                    StorageKind::Backup => &mut backup,
+                };
+                match value {
+                    Some(value) => storage.insert(key, value),
+                    None => storage.remove(&key),
+                };
+                position += length;
+            }
+        }
+
+        let sequence = sequences.last().map_or(1, |sequence| sequence + 1);
+        let queue = Arc::new(LogQueue {
+            log: Mutex::new(Log {
+                file: LogFile {
+                    sequence,
+                    file: Arc::new(create_log_file(directory, sequence)?),
+                },
+                batches: VecDeque::new(),
+                failure: None,
+                closed: false,
+            }),
+            work: Condvar::new(),
+        });
+        let writer_queue = Arc::clone(&queue);
+        let writer = thread::Builder::new()
+            .name("log-writer".to_string())
+            .spawn(move || write_batches(&writer_queue))?;
+
+        let recovered = RecoveredState {
+            node_id,
+            leader_kv_pairs: verified_kv_pairs(leader),
+            backup_kv_pairs: verified_kv_pairs(backup),
+        };
+
+        let durability = Durability {
+            directory: directory.to_path_buf(),
+            queue,
+            writer: Some(writer),
+        };
+
+        Ok((Arc::new(durability), recovered))
+    }
+
+    /// Wraps the given storage engine so that every change to it is appended to the log.
+    pub fn logged(
+        self: &Arc<Self>,
+        kind: StorageKind,
+        engine: Box<dyn StorageEngine>,
+    ) -> Box<dyn StorageEngine> {
+        Box::new(LoggedEngine {
+            engine,
+            kind,
+            durability: Arc::clone(self),
+        })
+    }
+
+    /// Returns `true` if changes have been logged since the latest snapshot was started.
+    pub fn has_changes(&self) -> bool {
+        let log = self.queue.log.lock().unwrap();
+        log.batches
+            .iter()
+            .any(|batch| batch.file.sequence == log.file.sequence && !batch.records.is_empty())
+            || log
+                .file
+                .file
+                .metadata()
+                .map_or(true, |metadata| metadata.len() > 0)
+    }
+
+    /// Starts a new log file for the changes made from now on.
+    /// Returns the sequence number of the new file, to be passed to `write_snapshot`.
+    /// A failed log file is left behind, so that the changes can be logged again.
+    /// The records appended before the rotation are still written to the previous file.
+    pub fn rotate(&self) -> Result<u64, StorageError> {
+        let mut log = self.queue.log.lock().unwrap();
+        let sequence = log.file.sequence + 1;
+        log.file = LogFile {
+            sequence,
+            file: Arc::new(create_log_file(&self.directory, sequence)?),
+        };
+        log.failure = None;
+        Ok(sequence)
+    }
+
+    /// Replaces the previous snapshot and the log files before the given sequence number
+    /// with a snapshot of the given key-value pairs, read after the log was rotated to that number.
+    pub fn write_snapshot(
+        &self,
+        node_id: u64,
+        leader_kv_pairs: &[(u64, Entry)],
+        backup_kv_pairs: &[(u64, Entry)],
+        first_kept_sequence: u64,
+    ) -> Result<(), StorageError> {
+        let bytes = encode_snapshot(node_id, leader_kv_pairs, backup_kv_pairs);
+
+        let temporary_path = self.directory.join(TEMPORARY_SNAPSHOT_FILE_NAME);
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, self.directory.join(SNAPSHOT_FILE_NAME))?;
+        File::open(&self.directory)?.sync_all()?;
+
+        for sequence in log_sequences(&self.directory)? {
+            if sequence < first_kept_sequence {
+                fs::remove_file(log_path(&self.directory, sequence))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits until the records appended so far are on disk.
+    /// Fails if any of them could not be written.
+    pub async fn sync(&self) -> Result<(), StorageError> {
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut log = self.queue.log.lock().unwrap();
+            if let Some(failure) = &log.failure {
+                return Err(failure.to_error());
+            }
+            log.collected_batch().waiters.push(sender);
+            self.queue.work.notify_one();
+        }
+        match receiver.await {
+            Ok(result) => result.map_err(|failure| failure.to_error()),
+            Err(_) => Err(io::Error::other("the log writer has stopped").into()),
+        }
+    }
+
+    /// Appends a record to the batch that the writer thread writes to the current log file next.
+    /// Returns without waiting for the record to be on disk, which `sync` waits for,
+    /// but fails without appending it if writing to the current log file has failed.
+    fn append(&self, record: &[u8]) -> Result<(), StorageError> {
+        let mut log = self.queue.log.lock().unwrap();
+        if let Some(failure) = &log.failure {
+            return Err(failure.to_error());
+        }
+        log.collected_batch().records.extend_from_slice(record);
+        self.queue.work.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for Durability {
+    /// Stops the writer thread once it has written the records appended so far.
+    fn drop(&mut self) {
+        self.queue.log.lock().unwrap().closed = true;
+        self.queue.work.notify_one();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Writes and syncs the batches of the log until it is closed, then notifies the waiters of each batch.
+/// A batch collected for the current log file after it has failed is not written.
+fn write_batches(queue: &LogQueue) {
+    let mut log = queue.log.lock().unwrap();
+    loop {
+        let Some(batch) = log.batches.pop_front() else {
+            if log.closed {
+                return;
+            }
+            log = queue.work.wait(log).unwrap();
+            continue;
+        };
+
+        // write the batch without holding the lock, so that the next batch can be collected
+        let previous_failure = log
+            .failure
+            .clone()
+            .filter(|_| batch.file.sequence == log.file.sequence);
+        drop(log);
+
+        let result = match previous_failure {
+            Some(failure) => Err(failure),
+            None if batch.records.is_empty() => Ok(()),
+            None => write_records(&batch.file.file, &batch.records).map_err(|error| LogFailure {
+                kind: error.kind(),
+                message: error.to_string(),
+            }),
+        };
+
+        log = queue.log.lock().unwrap();
+        if let Err(failure) = &result {
+            if log.file.sequence == batch.file.sequence && log.failure.is_none() {
+                log.failure = Some(failure.clone());
+            }
+        }
+        for waiter in batch.waiters {
+            let _ = waiter.send(result.clone());
+        }
+    }
+}
+
+/// Appends the records to the log file and syncs it.
+/// If that fails, the file is cut back to its previous length,
+/// so that it does not end with a partial record once the next file has been started.
+fn write_records(file: &File, records: &[u8]) -> io::Result<()> {
+    let length = file.metadata()?.len();
+    let result = (&*file).write_all(records).and_then(|_| file.sync_data());
+    if result.is_err() {
+        let _ = file.set_len(length);
+    }
+    result
+}
+
+/// A storage engine that appends every change of the wrapped engine to the log.
+/// Changes are appended before they are made and while the storage is still locked,
+/// so the log has the changes of each storage in the same order as the storage,
+/// and no change is made once the log file has failed.
+/// The changes are written to disk in the background, and a change must not be acknowledged
+/// before `Durability::sync` has returned after it.
+/// If a logged change then fails, the current value of the key is logged again to undo it.
+struct LoggedEngine {
+    engine: Box<dyn StorageEngine>,
+    kind: StorageKind,
+    durability: Arc<Durability>,
+}
+
+impl StorageEngine for LoggedEngine {
+    fn get(&self, key: u64) -> Result<Option<StoredValue>, StorageError> {
+        self.engine.get(key)
+    }
+
+    fn put(&mut self, key: u64, value: StoredValue) -> Result<(), StorageError> {
+        self.durability
+            .append(&encode_record(self.kind, key, Some(&value)))?;
+        self.engine
+            .put(key, value)
+            .inspect_err(|_| self.log_current(&[key]))
+    }
+
+    fn delete(&mut self, key: u64) -> Result<Option<StoredValue>, StorageError> {
+        if self.engine.keys(key..=key).is_empty() {
+            return Ok(None);
+        }
+        self.durability
+            .append(&encode_record(self.kind, key, None))?;
+        self.engine
+            .delete(key)
+            .inspect_err(|_| self.log_current(&[key]))
+    }
+
+    fn keys(&self, key_range: RangeInclusive<u64>) -> Vec<u64> {
+        self.engine.keys(key_range)
+    }
+
+    fn expired_keys(&self, now: u64) -> Vec<u64> {
+        self.engine.expired_keys(now)
+    }
+
+    fn extract_range(
+        &mut self,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, StoredValue)>, StorageError> {
+        let keys = self.engine.keys(key_range.clone());
+        let records: Vec<u8> = keys
+            .iter()
+            .flat_map(|key| encode_record(self.kind, *key, None))
+            .collect();
+        self.durability.append(&records)?;
+        self.engine
+            .extract_range(key_range)
+            .inspect_err(|_| self.log_current(&keys))
+    }
+
+    fn stats(&self) -> StorageStats {
+        self.engine.stats()
+    }
+}
+
+impl LoggedEngine {
+    /// Logs the current values of the given keys after a logged change to them failed,
+    /// so that the log agrees with the storage again.
+    fn log_current(&self, keys: &[u64]) {
+        let mut records = Vec::new();
+        for key in keys {
+            match self.engine.get(*key) {
+                Ok(value) => records.extend(encode_record(self.kind, *key, value.as_ref())),
+                Err(error) => println!("failed to read key={} to undo its change ({})", key, error),
+            }
+        }
+        if let Err(error) = self.durability.append(&records) {
+            println!("failed to undo a change in the log ({})", error);
+        }
+    }
+}
+
+/// Encodes a log record of a value stored, or removed if `None`, for the given key.
+/// The record is followed by its CRC32C checksum so that a partially written record can be detected.
+fn encode_record(kind: StorageKind, key: u64, value: Option<&StoredValue>) -> Vec<u8> {
+    let mut record = Vec::new();
+    match value {
+        Some(value) => {
+            let bytes = value.to_bytes();
+            record.push(PUT_RECORD);
+            record.push(kind.to_byte());
+            record.extend_from_slice(&key.to_be_bytes());
+            record.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            record.extend_from_slice(&bytes);
+        }
+        None => {
+            record.push(DELETE_RECORD);
+            record.push(kind.to_byte());
+            record.extend_from_slice(&key.to_be_bytes());
+        }
+    }
+    record.extend_from_slice(&crc32c::crc32c(&record).to_be_bytes());
+    record
+}
+
+/// Decodes the log record at the start of the given bytes.
+/// Returns the storage, the key, the stored value or `None` for a removal, and the length of the record,
+/// or `None` if the bytes do not start with a complete record.
+/// Returns whether the undecodable record at the start of the given bytes
+/// runs to the end of them, as a record partially written before a crash does.
+fn is_torn_record(bytes: &[u8]) -> bool {
+    let length = match bytes[0] {
+        PUT_RECORD => bytes
+            .get(10..14)
+            .map(|length| 14 + u32::from_be_bytes(length.try_into().unwrap()) as usize),
+        DELETE_RECORD => Some(10),
+        _ => None,
+    };
+    length.map_or(bytes.len() < 14, |length| length + 4 >= bytes.len())
+}
+
+fn decode_record(bytes: &[u8]) -> Option<(StorageKind, u64, Option<StoredValue>, usize)> {
+    let kind = StorageKind::from_byte(*bytes.get(1)?)?;
+    let key = u64::from_be_bytes(bytes.get(2..10)?.try_into().unwrap());
+
+    let (value, length) = match bytes[0] {
+        PUT_RECORD => {
+            let value_length = u32::from_be_bytes(bytes.get(10..14)?.try_into().unwrap()) as usize;
+            let value = StoredValue::from_bytes(bytes.get(14..14 + value_length)?).ok()?;
+            (Some(value), 14 + value_length)
+        }
+        DELETE_RECORD => (None, 10),
+        _ => return None,
+    };
+
+    let checksum = u32::from_be_bytes(bytes.get(length..length + 4)?.try_into().unwrap());
+    if crc32c::crc32c(&bytes[..length]) != checksum {
+        return None;
+    }
+
+    Some((kind, key, value, length + 4))
+}
+
+/// Encodes a snapshot: the node ID, the leader and the backup key-value pairs
+/// each preceded by their count, and the CRC32C checksum of everything before it.
+fn encode_snapshot(
+    node_id: u64,
+    leader_kv_pairs: &[(u64, Entry)],
+    backup_kv_pairs: &[(u64, Entry)],
+) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&node_id.to_be_bytes());
+    for kv_pairs in [leader_kv_pairs, backup_kv_pairs] {
+        bytes.extend_from_slice(&(kv_pairs.len() as u64).to_be_bytes());
+        for (key, entry) in kv_pairs {
+            let value_bytes = StoredValue::new(entry.clone()).to_bytes();
+            bytes.extend_from_slice(&key.to_be_bytes());
+            bytes.extend_from_slice(&(value_bytes.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&value_bytes);
+        }
+    }
+    bytes.extend_from_slice(&crc32c::crc32c(&bytes).to_be_bytes());
+    bytes
+}
+
+/// Decodes a snapshot encoded by `encode_snapshot`, or returns `None` if it is corrupted.
+#[allow(clippy::type_complexity)]
+fn decode_snapshot(
+    bytes: &[u8],
+) -> Option<(u64, HashMap<u64, StoredValue>, HashMap<u64, StoredValue>)> {
+    let content_length = bytes.len().checked_sub(4)?;
+    let checksum = u32::from_be_bytes(bytes[content_length..].try_into().unwrap());
+    if crc32c::crc32c(&bytes[..content_length]) != checksum {
+        return None;
+    }
+    let bytes = &bytes[..content_length];
+
+    let node_id = u64::from_be_bytes(bytes.get(..8)?.try_into().unwrap());
+    let mut position = 8;
+    let mut storages = Vec::new();
+
+    for _ in 0..2 {
+        let count = u64::from_be_bytes(bytes.get(position..position + 8)?.try_into().unwrap());
+        position += 8;
+
+        let mut storage = HashMap::new();
+        for _ in 0..count {
+            let key = u64::from_be_bytes(bytes.get(position..position + 8)?.try_into().unwrap());
+            let value_length =
+                u32::from_be_bytes(bytes.get(position + 8..position + 12)?.try_into().unwrap())
+                    as usize;
+            position += 12;
+            let value = StoredValue::from_bytes(bytes.get(position..position + value_length)?);
+            storage.insert(key, value.ok()?);
+            position += value_length;
+        }
+        storages.push(storage);
+    }
+
+    let backup = storages.pop().unwrap();
+    let leader = storages.pop().unwrap();
+    Some((node_id, leader, backup))
+}
+
+/// Returns the verified key-value pairs, leaving out the corrupted values.
+fn verified_kv_pairs(storage: HashMap<u64, StoredValue>) -> Vec<(u64, Entry)> {
+    storage
+        .into_iter()
+        .filter_map(|(key, value)| match value.into_verified() {
+            Ok(entry) => Some((key, entry)),
+            Err(error) => {
+                println!("dropping recovered value of key={} ({})", key, error);
+                None
+            }
+        })
+        .collect()
+}
+
+fn log_path(directory: &Path, sequence: u64) -> PathBuf {
+    directory.join(format!("wal-{:020}.log", sequence))
+}
+
+fn create_log_file(directory: &Path, sequence: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path(directory, sequence))
+}
+
+/// Returns the sequence numbers of the log files in the directory in ascending order.
+fn log_sequences(directory: &Path) -> io::Result<Vec<u64>> {
+    let mut sequences = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let name = entry?.file_name();
+        let sequence = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("wal-"))
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|sequence| sequence.parse().ok());
+        if let Some(sequence) = sequence {
+            sequences.push(sequence);
+        }
+    }
+    sequences.sort();
+    Ok(sequences)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::MemoryEngine;
+    use super::*;
+    use std::env;
+
+    fn sorted(mut kv_pairs: Vec<(u64, Entry)>) -> Vec<(u64, Entry)> {
+        kv_pairs.sort_by_key(|(key, _)| *key);
+        kv_pairs
+    }
+
+    #[test]
+    fn recovery_from_log_and_snapshot() {
+        let directory =
+            env::temp_dir().join(format!("ds-durability-test-{}", rand::random::<u64>()));
+
+        let (durability, recovered) = Durability::open(&directory).unwrap();
+        assert_eq!(recovered, RecoveredState::default());
+
+        let mut leader = durability.logged(StorageKind::Leader, Box::new(MemoryEngine::new()));
+        let mut backup = durability.logged(StorageKind::Backup, Box::new(MemoryEngine::new()));
+        leader
+            .put(1, StoredValue::new(Entry::new(vec![1])))
+            .unwrap();
+        leader
+            .put(2, StoredValue::new(Entry::new(vec![2])))
+            .unwrap();
+        backup
+            .put(3, StoredValue::new(Entry::new(vec![3])))
+            .unwrap();
+
+        let first_kept_sequence = durability.rotate().unwrap();
+        durability
+            .write_snapshot(
+                7,
+                &[(1, Entry::new(vec![1])), (2, Entry::new(vec![2]))],
+                &[(3, Entry::new(vec![3]))],
+                first_kept_sequence,
+            )
+            .unwrap();
+        assert!(!durability.has_changes());
+
+        leader
+            .put(2, StoredValue::new(Entry::new(vec![22])))
+            .unwrap();
+        leader.extract_range(0..=1).unwrap();
+        backup
+            .put(4, StoredValue::new(Entry::new(vec![4])))
+            .unwrap();
+        drop((leader, backup, durability));
+
+        // a partially written record is ignored
+        let mut file = create_log_file(&directory, first_kept_sequence).unwrap();
+        file.write_all(&[PUT_RECORD, 0, 1]).unwrap();
+
+        let (_, recovered) = Durability::open(&directory).unwrap();
+        assert_eq!(recovered.node_id, Some(7));
+        assert_eq!(
+            sorted(recovered.leader_kv_pairs),
+            vec![(2, Entry::new(vec![22]))]
+        );
+        assert_eq!(
+            sorted(recovered.backup_kv_pairs),
+            vec![(3, Entry::new(vec![3])), (4, Entry::new(vec![4]))]
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn recovery_from_corrupted_log() {
+        let directory =
+            env::temp_dir().join(format!("ds-durability-test-{}", rand::random::<u64>()));
+
+        let (durability, _) = Durability::open(&directory).unwrap();
+        let mut leader = durability.logged(StorageKind::Leader, Box::new(MemoryEngine::new()));
+        leader
+            .put(1, StoredValue::new(Entry::new(vec![1])))
+            .unwrap();
+        leader
+            .put(2, StoredValue::new(Entry::new(vec![2])))
+            .unwrap();
+        let sequence = durability.rotate().unwrap();
+        drop((leader, durability));
+
+        // a partially written record at the end of the last written file is removed,
+        // even if an empty file has been started after it
+        let mut file = create_log_file(&directory, sequence).unwrap();
+        file.write_all(&encode_record(StorageKind::Leader, 3, None)[..5])
+            .unwrap();
+        let (_, recovered) = Durability::open(&directory).unwrap();
+        assert_eq!(recovered.leader_kv_pairs.len(), 2);
+        assert_eq!(
+            fs::metadata(log_path(&directory, sequence)).unwrap().len(),
+            0
+        );
+
+        // the same partial record in an earlier file means that records are missing
+        let mut file = create_log_file(&directory, sequence).unwrap();
+        file.write_all(&encode_record(StorageKind::Leader, 3, None)[..5])
+            .unwrap();
+        let mut file = create_log_file(&directory, sequence + 1).unwrap();
+        file.write_all(&encode_record(StorageKind::Leader, 4, None))
+            .unwrap();
+        assert!(matches!(
+            Durability::open(&directory),
+            Err(StorageError::Corrupted)
+        ));
+        fs::OpenOptions::new()
+            .write(true)
+            .open(log_path(&directory, sequence))
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+        assert!(Durability::open(&directory).is_ok());
+
+        // a corrupted record followed by others fails the recovery, also in the last file
+        for later_sequence in log_sequences(&directory).unwrap() {
+            if later_sequence >= sequence {
+                fs::remove_file(log_path(&directory, later_sequence)).unwrap();
+            }
+        }
+        let path = log_path(&directory, sequence - 1);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[15] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            Durability::open(&directory),
+            Err(StorageError::Corrupted)
+        ));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sync_waits_for_the_log_writer() {
+        let directory =
+            env::temp_dir().join(format!("ds-durability-test-{}", rand::random::<u64>()));
+        let (durability, _) = Durability::open(&directory).unwrap();
+
+        let mut leader = durability.logged(StorageKind::Leader, Box::new(MemoryEngine::new()));
+        leader
+            .put(1, StoredValue::new(Entry::new(vec![1])))
+            .unwrap();
+        durability.sync().await.unwrap();
+
+        // the change is on disk while the log is still open
+        let (_, recovered) = Durability::open(&directory).unwrap();
+        assert_eq!(recovered.leader_kv_pairs, vec![(1, Entry::new(vec![1]))]);
+
+        drop((leader, durability));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn concurrent_appends() {
+        let directory =
+            env::temp_dir().join(format!("ds-durability-test-{}", rand::random::<u64>()));
+        let (durability, _) = Durability::open(&directory).unwrap();
+
+        let writers: Vec<_> = (0..8u64)
+            .map(|writer| {
+                let mut engine =
+                    durability.logged(StorageKind::Leader, Box::new(MemoryEngine::new()));
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        let key = writer * 100 + i;
+                        engine
+                            .put(key, StoredValue::new(Entry::new(vec![writer as u8])))
+                            .unwrap();
+                    }
+                    engine.delete(writer * 100).unwrap();
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        drop(durability);
+
+        let (_, recovered) = Durability::open(&directory).unwrap();
+        let recovered = sorted(recovered.leader_kv_pairs);
+        assert_eq!(recovered.len(), 8 * 49);
+        assert!(recovered
+            .iter()
+            .all(|(key, entry)| key % 100 != 0 && entry.value == vec![(key / 100) as u8]));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,59 @@
+use super::{StorageEngine, StorageError, StorageStats};
+use crate::helpers::entry::is_expired_at;
+use crate::helpers::stored_value::StoredValue;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+/// A storage engine that keeps the key-value pairs in memory, ordered by key.
+#[derive(Debug, Default)]
+pub struct MemoryEngine {
+    values: BTreeMap<u64, StoredValue>,
+    value_bytes: usize,
+}
+
+impl MemoryEngine {
+    pub fn new() -> MemoryEngine {
+        MemoryEngine::default()
+    }
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&self, key: u64) -> Result<Option<StoredValue>, StorageError> {
+        Ok(self.values.get(&key).cloned())
+    }
+
+    fn put(&mut self, key: u64, value: StoredValue) -> Result<(), StorageError> {
+        self.value_bytes += value.value_length();
+        if let Some(previous) = self.values.insert(key, value) {
+            self.value_bytes -= previous.value_length();
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, key: u64) -> Result<Option<StoredValue>, StorageError> {
+        let value = self.values.remove(&key);
+        if let Some(value) = &value {
+            self.value_bytes -= value.value_length();
+        }
+        Ok(value)
+    }
+
+    fn keys(&self, key_range: RangeInclusive<u64>) -> Vec<u64> {
+        self.values.range(key_range).map(|(key, _)| *key).collect()
+    }
+
+    fn expired_keys(&self, now: u64) -> Vec<u64> {
+        self.values
+            .iter()
+            .filter(|(_, value)| is_expired_at(value.expires_at(), now))
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    fn stats(&self) -> StorageStats {
+        StorageStats {
+            keys: self.values.len(),
+            value_bytes: self.value_bytes,
+        }
+    }
+}
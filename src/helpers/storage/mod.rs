@@ -0,0 +1,322 @@
+use crate::helpers::communication::ErrorCode;
+use crate::helpers::entry::{now_millis, Entry};
+use crate::helpers::stored_value::{CorruptedValue, StoredValue};
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+pub use budget::{BudgetPolicy, MemoryBudget};
+pub use disk::DiskEngine;
+pub use durable::{Durability, RecoveredState, StorageKind};
+pub use memory::MemoryEngine;
+pub use sharded::ShardedStorage;
+pub use versioned::VersionedEngine;
+
+mod budget;
+mod disk;
+mod durable;
+mod memory;
+mod sharded;
+mod versioned;
+
+/// A storage of key-value pairs used as the leader or backup storage of a node.
+pub trait StorageEngine: Send {
+    /// Returns the stored value of the given key, if any.
+    fn get(&self, key: u64) -> Result<Option<StoredValue>, StorageError>;
+
+    /// Stores the value of the given key, replacing the previous value.
+    fn put(&mut self, key: u64, value: StoredValue) -> Result<(), StorageError>;
+
+    /// Stores the value of the given key like `put`,
+    /// unless the value does not fit the memory budget of the node.
+    /// Fails with `EvictionNeeded` if the value would fit after the caller evicts other values.
+    fn put_within_budget(&mut self, key: u64, value: StoredValue) -> Result<(), StorageError> {
+        self.put(key, value)
+    }
+
+    /// Removes and returns the stored value of the given key, if any.
+    /// A value that cannot be decoded is still removed, and `Corrupted` is returned.
+    fn delete(&mut self, key: u64) -> Result<Option<StoredValue>, StorageError>;
+
+    /// Returns the verified entry of the given key, if any and not expired.
+    fn get_verified(&self, key: u64) -> Result<Option<Entry>, StorageError> {
+        let entry = match self.get(key)? {
+            Some(value) => value.into_verified()?,
+            None => return Ok(None),
+        };
+        if entry.is_expired_at(now_millis()) {
+            return Ok(None);
+        }
+        Ok(Some(entry))
+    }
+
+    /// Returns the keys that are in the given range in ascending order.
+    fn keys(&self, key_range: RangeInclusive<u64>) -> Vec<u64>;
+
+    /// Returns the keys whose values have expired at the given time in milliseconds since the Unix epoch.
+    fn expired_keys(&self, now: u64) -> Vec<u64>;
+
+    /// Removes the expired values and returns their keys.
+    fn remove_expired(&mut self) -> Result<Vec<u64>, StorageError> {
+        let keys = self.expired_keys(now_millis());
+        for key in &keys {
+            delete_dropping_corrupted(self, *key)?;
+        }
+        Ok(keys)
+    }
+
+    /// Returns the verified entries whose keys are in the given range in ascending order,
+    /// leaving out the expired ones, or an error if any of the values cannot be read.
+    fn copy_range(
+        &self,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, Entry)>, StorageError> {
+        let mut kv_pairs = Vec::new();
+        for key in self.keys(key_range) {
+            if let Some(entry) = self.get_verified(key)? {
+                kv_pairs.push((key, entry));
+            }
+        }
+        Ok(kv_pairs)
+    }
+
+    /// Removes the key-value pairs whose keys are in the given range and returns
+    /// the verified entries that have not expired in ascending order.
+    /// Nothing is removed if any of the values cannot be read.
+    fn take_range(
+        &mut self,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, Entry)>, StorageError> {
+        let kv_pairs = self.copy_range(key_range.clone())?;
+        self.extract_range(key_range)?;
+        Ok(kv_pairs)
+    }
+
+    /// Removes and returns the key-value pairs whose keys are in the given range in ascending order.
+    /// The values that cannot be decoded are removed but left out.
+    fn extract_range(
+        &mut self,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, StoredValue)>, StorageError> {
+        let mut kv_pairs = Vec::new();
+        for key in self.keys(key_range) {
+            if let Some(value) = delete_dropping_corrupted(self, key)? {
+                kv_pairs.push((key, value));
+            }
+        }
+        Ok(kv_pairs)
+    }
+
+    /// Returns the number of keys and the total length of the values.
+    fn stats(&self) -> StorageStats;
+}
+
+/// Deletes the value of the given key, treating a value that could not be decoded as removed,
+/// so that a corrupted value does not stop the removal of the other values.
+fn delete_dropping_corrupted<E: StorageEngine + ?Sized>(
+    engine: &mut E,
+    key: u64,
+) -> Result<Option<StoredValue>, StorageError> {
+    match engine.delete(key) {
+        Err(StorageError::Corrupted) => {
+            println!("dropped corrupted value of key={}", key);
+            Ok(None)
+        }
+        result => result,
+    }
+}
+
+/// The size of the contents of a storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StorageStats {
+    pub keys: usize,
+    pub value_bytes: usize,
+}
+
+/// The error returned when a storage cannot serve a request.
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    Corrupted,
+    BudgetExceeded,
+    /// The value fits the memory budget only after evicting values of the leader storage,
+    /// which may be in the shards that the writer has not locked.
+    EvictionNeeded,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(error) => write!(f, "storage failed: {}", error),
+            StorageError::Corrupted => write!(f, "{}", CorruptedValue),
+            StorageError::BudgetExceeded | StorageError::EvictionNeeded => {
+                write!(f, "memory budget of the node exceeded")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl StorageError {
+    /// Returns the code of the error response sent when a request fails with this error.
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            StorageError::Io(_) => ErrorCode::Internal,
+            StorageError::Corrupted => ErrorCode::Corrupted,
+            StorageError::BudgetExceeded | StorageError::EvictionNeeded => ErrorCode::StorageFull,
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(error: io::Error) -> Self {
+        StorageError::Io(error)
+    }
+}
+
+impl From<CorruptedValue> for StorageError {
+    fn from(_: CorruptedValue) -> Self {
+        StorageError::Corrupted
+    }
+}
+
+/// Selects the storage engine used for the leader and backup storages of a node.
+#[derive(Debug, Clone, Default)]
+pub enum StorageSettings {
+    /// Keep the key-value pairs in memory.
+    #[default]
+    Memory,
+    /// Keep the key-value pairs in files under the given directory.
+    Disk { directory: PathBuf },
+}
+
+impl StorageSettings {
+    /// Opens an empty storage with the given name, unique within the node.
+    pub fn open(&self, name: &str) -> Result<Box<dyn StorageEngine>, StorageError> {
+        match self {
+            StorageSettings::Memory => Ok(Box::new(MemoryEngine::new())),
+            StorageSettings::Disk { directory } => {
+                Ok(Box::new(DiskEngine::open(directory.join(name))?))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    fn stored(value: Vec<u8>) -> StoredValue {
+        StoredValue::new(Entry::new(value))
+    }
+
+    /// Runs the same checks against every engine.
+    fn check_engine(mut engine: Box<dyn StorageEngine>) {
+        assert_eq!(engine.get(1).unwrap(), None);
+
+        engine.put(1, stored(vec![1])).unwrap();
+        engine.put(5, stored(vec![5, 5])).unwrap();
+        engine.put(9, stored(vec![9, 9, 9])).unwrap();
+        engine.put(5, stored(vec![5])).unwrap();
+        assert_eq!(engine.get(5).unwrap(), Some(stored(vec![5])));
+        assert_eq!(
+            engine.stats(),
+            StorageStats {
+                keys: 3,
+                value_bytes: 5
+            }
+        );
+
+        assert_eq!(engine.keys(2..=u64::MAX), vec![5, 9]);
+        assert_eq!(
+            engine.copy_range(0..=5).unwrap(),
+            vec![(1, Entry::new(vec![1])), (5, Entry::new(vec![5]))]
+        );
+
+        assert_eq!(engine.delete(1).unwrap(), Some(stored(vec![1])));
+        assert_eq!(engine.delete(1).unwrap(), None);
+
+        assert_eq!(
+            engine.take_range(0..=5).unwrap(),
+            vec![(5, Entry::new(vec![5]))]
+        );
+        assert_eq!(engine.keys(0..=u64::MAX), vec![9]);
+        assert_eq!(
+            engine.stats(),
+            StorageStats {
+                keys: 1,
+                value_bytes: 3
+            }
+        );
+
+        // expired values are hidden until they are removed
+        let expiring = |expires_at| {
+            StoredValue::new(Entry {
+                expires_at: Some(expires_at),
+                ..Entry::new(vec![7])
+            })
+        };
+        engine.put(7, expiring(1)).unwrap();
+        engine.put(8, expiring(u64::MAX)).unwrap();
+        engine.put(8, expiring(2)).unwrap();
+        engine.put(9, expiring(3)).unwrap();
+        engine.put(9, stored(vec![9])).unwrap();
+        assert_eq!(engine.get_verified(7).unwrap(), None);
+        assert_eq!(engine.copy_range(0..=u64::MAX).unwrap().len(), 1);
+        assert_eq!(engine.keys(0..=u64::MAX), vec![7, 8, 9]);
+
+        let mut expired = engine.remove_expired().unwrap();
+        expired.sort();
+        assert_eq!(expired, vec![7, 8]);
+        assert_eq!(engine.keys(0..=u64::MAX), vec![9]);
+    }
+
+    #[test]
+    fn memory_engine() {
+        check_engine(StorageSettings::Memory.open("leader").unwrap());
+    }
+
+    #[test]
+    fn disk_engine() {
+        let directory = env::temp_dir().join(format!("ds-storage-test-{}", rand::random::<u64>()));
+        let settings = StorageSettings::Disk {
+            directory: directory.clone(),
+        };
+
+        let mut engine = settings.open("leader").unwrap();
+        engine.put(3, stored(vec![3])).unwrap();
+
+        // reopening starts from an empty storage but keeps files that are not values
+        std::fs::write(directory.join("leader").join("notes"), [1]).unwrap();
+        check_engine(settings.open("leader").unwrap());
+        assert!(directory.join("leader").join("notes").exists());
+
+        // a corrupted value file is deleted anyway, and does not stop the removal of other values
+        let mut engine = settings.open("leader").unwrap();
+        engine.put(1, stored(vec![1])).unwrap();
+        engine.put(2, stored(vec![2])).unwrap();
+        engine.put(3, stored(vec![3])).unwrap();
+        std::fs::write(directory.join("leader").join(format!("{:016x}", 1)), [0, 1]).unwrap();
+        assert!(matches!(engine.delete(1), Err(StorageError::Corrupted)));
+        assert_eq!(engine.keys(0..=u64::MAX), vec![2, 3]);
+        std::fs::write(directory.join("leader").join(format!("{:016x}", 2)), [0, 1]).unwrap();
+        let extracted = engine.extract_range(0..=u64::MAX).unwrap();
+        assert_eq!(extracted, vec![(3, stored(vec![3]))]);
+        assert_eq!(engine.stats(), StorageStats::default());
+        assert_eq!(
+            std::fs::read_dir(directory.join("leader")).unwrap().count(),
+            2
+        );
+
+        // a directory that the engine did not create is not emptied
+        std::fs::create_dir_all(directory.join("backup")).unwrap();
+        std::fs::write(directory.join("backup").join("notes"), [1]).unwrap();
+        assert!(settings.open("backup").is_err());
+        assert!(directory.join("backup").join("notes").exists());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,358 @@
+use super::{Durability, MemoryBudget, StorageEngine, StorageError, StorageStats, VersionedEngine};
+use crate::helpers::entry::Entry;
+use crate::helpers::stored_value::StoredValue;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// A storage split into shards by a hash of the key, each behind a lock of its own,
+/// so that requests for keys in different shards do not wait for each other.
+/// The keys are hashed instead of split into ranges, since a node stores a single range of the ring
+/// and clients mostly use small keys, which would all fall into the same range.
+/// Each shard gives out the versions of its own keys,
+/// while the memory budget of the shards is shared, so that a write may evict values of any shard.
+pub struct ShardedStorage {
+    shards: Vec<Mutex<VersionedEngine>>,
+    budget: Arc<MemoryBudget>,
+    durability: Option<Arc<Durability>>,
+}
+
+impl ShardedStorage {
+    /// Creates a storage of the given shards, each storing about an equal share of any range of keys
+    /// and counted in the given budget, whose changes are logged to the given durability, if any.
+    /// Panics if there are no shards.
+    pub fn new(
+        shards: Vec<Box<dyn StorageEngine>>,
+        budget: Arc<MemoryBudget>,
+        durability: Option<Arc<Durability>>,
+    ) -> ShardedStorage {
+        assert!(!shards.is_empty(), "a storage needs at least one shard");
+        ShardedStorage {
+            shards: shards
+                .into_iter()
+                .map(|shard| Mutex::new(VersionedEngine::new(shard)))
+                .collect(),
+            budget,
+            durability,
+        }
+    }
+
+    /// Waits until the changes made to the storage so far are on disk, if they are logged.
+    pub async fn sync(&self) -> Result<(), StorageError> {
+        match &self.durability {
+            Some(durability) => durability.sync().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the shard that stores the given key.
+    pub fn shard(&self, key: u64) -> &Mutex<VersionedEngine> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    /// Returns the index of the shard of the given key, spreading consecutive keys evenly over the shards
+    /// with Fibonacci hashing.
+    fn shard_index(&self, key: u64) -> usize {
+        let hash = key.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        ((hash as u128 * self.shards.len() as u128) >> 64) as usize
+    }
+
+    /// Returns the number of shards.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Runs the given write on the shard of the given key, which stores the value with `put_within_budget`.
+    /// If the value only fits after evicting values, the least recently used value of the whole storage
+    /// is evicted, locking its shard after the shard of the key has been unlocked, and the write is run again.
+    /// Returns the result of the write and the keys evicted for it, also if it failed,
+    /// each with the version given to its removal, so that the backups can drop the evicted values as well.
+    pub async fn write_within_budget<T>(
+        &self,
+        key: u64,
+        mut write: impl FnMut(&mut VersionedEngine) -> Result<T, StorageError>,
+    ) -> (Result<T, StorageError>, Vec<(u64, u64)>) {
+        let mut evicted = Vec::new();
+        loop {
+            let result = write(&mut *self.shard(key).lock().await);
+            match result {
+                Err(StorageError::EvictionNeeded) => {
+                    match self.evict_least_recent_except(key).await {
+                        Ok(Some(eviction)) => evicted.push(eviction),
+                        Ok(None) => {}
+                        Err(error) => return (Err(error), evicted),
+                    }
+                }
+                result => return (result, evicted),
+            }
+        }
+    }
+
+    /// Removes the least recently used value of the storage other than the one of the given key,
+    /// or fails with `BudgetExceeded` if there is no such value.
+    /// Returns the evicted key with a new version for its removal, or `None` if the key had no value anymore.
+    async fn evict_least_recent_except(
+        &self,
+        key: u64,
+    ) -> Result<Option<(u64, u64)>, StorageError> {
+        let victim = self
+            .budget
+            .least_recent_except(key)
+            .ok_or(StorageError::BudgetExceeded)?;
+        let mut shard = self.shard(victim).lock().await;
+        if shard.delete(victim)?.is_none() {
+            self.budget.forget(victim);
+            return Ok(None);
+        }
+        Ok(Some((victim, shard.next_version())))
+    }
+
+    /// Inserts the given key-value pairs, replacing the existing values of the same keys,
+    /// and waits until they are on disk.
+    pub async fn insert_many(&self, kv_pairs: Vec<(u64, Entry)>) -> Result<(), StorageError> {
+        for (key, entry) in kv_pairs {
+            self.shard(key)
+                .lock()
+                .await
+                .put(key, StoredValue::new(entry))?;
+        }
+        self.sync().await
+    }
+
+    /// Returns the verified entries whose keys are in the given range in ascending order,
+    /// leaving out the expired ones, or an error if any of the values cannot be read.
+    /// The shards are copied one at a time, so the other shards can be written meanwhile.
+    pub async fn copy_range(
+        &self,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, Entry)>, StorageError> {
+        let mut kv_pairs = Vec::new();
+        for index in 0..self.shards.len() {
+            kv_pairs.extend(self.copy_shard_range(index, key_range.clone()).await?);
+            tokio::task::yield_now().await;
+        }
+        kv_pairs.sort_unstable_by_key(|(key, _)| *key);
+        Ok(kv_pairs)
+    }
+
+    /// Returns the verified entries of the shard of the given index whose keys are in the given range
+    /// like `copy_range`, so that a large range can be handled one shard at a time.
+    pub async fn copy_shard_range(
+        &self,
+        index: usize,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, Entry)>, StorageError> {
+        self.shards[index].lock().await.copy_range(key_range)
+    }
+
+    /// Returns the greatest version stored or given out by any of the shards.
+    pub async fn version_high_water(&self) -> u64 {
+        let mut version_high_water = 0;
+        for shard in &self.shards {
+            version_high_water = version_high_water.max(shard.lock().await.version_high_water());
+        }
+        version_high_water
+    }
+
+    /// Raises the version high-water marks of all shards to the given version.
+    pub async fn raise_version_high_water(&self, version: u64) {
+        for shard in &self.shards {
+            shard.lock().await.raise_version_high_water(version);
+        }
+    }
+
+    /// Removes the expired values and returns their keys.
+    pub async fn remove_expired(&self) -> Result<Vec<u64>, StorageError> {
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            keys.extend(shard.lock().await.remove_expired()?);
+        }
+        Ok(keys)
+    }
+
+    /// Returns the number of keys and the total length of the values of all shards.
+    pub async fn stats(&self) -> StorageStats {
+        let mut stats = StorageStats::default();
+        for shard in &self.shards {
+            let shard_stats = shard.lock().await.stats();
+            stats.keys += shard_stats.keys;
+            stats.value_bytes += shard_stats.value_bytes;
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::helpers::storage::{BudgetPolicy, MemoryEngine};
+    use std::time::{Duration, Instant};
+
+    fn sharded(count: usize) -> ShardedStorage {
+        let shards = (0..count)
+            .map(|_| Box::new(MemoryEngine::new()) as Box<dyn StorageEngine>)
+            .collect();
+        ShardedStorage::new(shards, MemoryBudget::new(None, BudgetPolicy::Reject), None)
+    }
+
+    #[tokio::test]
+    async fn ranges_across_shards() {
+        let storage = sharded(4);
+        let keys = [0, 1, 2, 3, 1000, u64::MAX / 2, u64::MAX];
+        storage
+            .insert_many(keys.iter().map(|key| (*key, Entry::new(vec![1]))).collect())
+            .await
+            .unwrap();
+        assert_eq!(storage.stats().await.keys, keys.len());
+
+        let copied = storage.copy_range(1..=u64::MAX / 2).await.unwrap();
+        let copied_keys: Vec<u64> = copied.iter().map(|(key, _)| *key).collect();
+        assert_eq!(copied_keys, keys[1..6]);
+
+        let mut shard_keys = Vec::new();
+        for index in 0..storage.shard_count() {
+            let copied = storage.copy_shard_range(index, 1..=u64::MAX / 2).await;
+            shard_keys.extend(copied.unwrap().into_iter().map(|(key, _)| key));
+        }
+        shard_keys.sort();
+        assert_eq!(shard_keys, copied_keys);
+    }
+
+    #[test]
+    fn small_keys_spread_over_shards() {
+        let storage = sharded(16);
+        let mut counts = [0; 16];
+        for key in 0..1600 {
+            counts[storage.shard_index(key)] += 1;
+        }
+        assert!(counts.iter().all(|count| (90..=110).contains(count)));
+    }
+
+    #[tokio::test]
+    async fn eviction_across_shards() {
+        let budget = MemoryBudget::new(Some(3 * (8 + 10)), BudgetPolicy::Evict);
+        let shards = (0..2)
+            .map(|_| budget.accounted_evictable(Box::new(MemoryEngine::new())))
+            .collect();
+        let storage = ShardedStorage::new(shards, Arc::clone(&budget), None);
+        let storage = &storage;
+        let write = |key: u64, length: usize| async move {
+            storage
+                .write_within_budget(key, move |shard| {
+                    shard.put_within_budget(key, StoredValue::new(Entry::new(vec![0; length])))
+                })
+                .await
+        };
+
+        let mut near = (0..).filter(|key| storage.shard_index(*key) == 0);
+        let near: Vec<u64> = (0..4).map(|_| near.next().unwrap()).collect();
+        let far = (0..).find(|key| storage.shard_index(*key) == 1).unwrap();
+
+        write(far, 10).await.0.unwrap();
+        write(near[0], 10).await.0.unwrap();
+        write(near[1], 10).await.0.unwrap();
+        storage.shard(near[0]).lock().await.get(near[0]).unwrap();
+
+        // the least recently used value is in the other shard
+        let (result, evicted) = write(near[2], 10).await;
+        result.unwrap();
+        assert_eq!(evicted, vec![(far, 1)]);
+        assert_eq!(storage.copy_range(0..=u64::MAX).await.unwrap().len(), 3);
+        assert!(storage
+            .shard(far)
+            .lock()
+            .await
+            .keys(0..=u64::MAX)
+            .is_empty());
+
+        let (result, evicted) = write(near[3], 10).await;
+        result.unwrap();
+        assert_eq!(evicted, vec![(near[1], 1)]);
+        assert_eq!(
+            storage.shard(near[0]).lock().await.keys(0..=u64::MAX),
+            vec![near[0], near[2], near[3]]
+        );
+
+        assert!(matches!(
+            write(far, 100).await,
+            (Err(StorageError::BudgetExceeded), evicted) if evicted.is_empty()
+        ));
+        assert_eq!(budget.used(), 3 * (8 + 10));
+    }
+
+    /// Measures how many single-key reads and writes go through while backup snapshots
+    /// of the whole storage are copied one shard at a time, like `handle_backup_request` does.
+    /// The keys are a contiguous block of small keys, like the keys that clients write to a single node.
+    /// Run with `cargo test --release throughput -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore]
+    async fn throughput_during_bulk_copies() {
+        const KEYS: u64 = 100_000;
+        const WORKERS: usize = 8;
+        const DURATION: Duration = Duration::from_secs(3);
+
+        for shard_count in [1, 16] {
+            let storage = Arc::new(sharded(shard_count));
+            storage
+                .insert_many(
+                    (0..KEYS)
+                        .map(|key| (key, Entry::new(vec![7; 64])))
+                        .collect(),
+                )
+                .await
+                .unwrap();
+
+            let deadline = Instant::now() + DURATION;
+            let copier_storage = Arc::clone(&storage);
+            let copier = tokio::spawn(async move {
+                let mut copies = 0;
+                while Instant::now() < deadline {
+                    for index in 0..copier_storage.shard_count() {
+                        copier_storage
+                            .copy_shard_range(index, 0..=u64::MAX)
+                            .await
+                            .unwrap();
+                        tokio::task::yield_now().await;
+                    }
+                    copies += 1;
+                }
+                copies
+            });
+            let workers: Vec<_> = (0..WORKERS)
+                .map(|worker| {
+                    let storage = Arc::clone(&storage);
+                    tokio::spawn(async move {
+                        let mut operations: u64 = 0;
+                        while Instant::now() < deadline {
+                            let key = (operations * 7919 + worker as u64 * 104729) % KEYS;
+                            let mut shard = storage.shard(key).lock().await;
+                            if operations.is_multiple_of(2) {
+                                shard.get_verified(key).unwrap();
+                            } else {
+                                shard
+                                    .put(key, StoredValue::new(Entry::new(vec![8; 64])))
+                                    .unwrap();
+                            }
+                            drop(shard);
+                            operations += 1;
+                            tokio::task::yield_now().await;
+                        }
+                        operations
+                    })
+                })
+                .collect();
+
+            let mut operations = 0;
+            for worker in workers {
+                operations += worker.await.unwrap();
+            }
+            let copies = copier.await.unwrap();
+            println!(
+                "{} shards: {} reads and writes per second, {:.1} full copies per second",
+                shard_count,
+                operations / DURATION.as_secs(),
+                copies as f64 / DURATION.as_secs_f64()
+            );
+        }
+    }
+}
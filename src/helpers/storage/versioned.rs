@@ -0,0 +1,101 @@
+use super::{StorageEngine, StorageError, StorageStats};
+use crate::helpers::entry::Entry;
+use crate::helpers::stored_value::StoredValue;
+use std::ops::RangeInclusive;
+
+/// A storage engine that remembers the greatest version of the values ever stored in the wrapped engine,
+/// so that the versions it gives to new values keep growing
+/// even after the values with the greatest versions have expired or been removed.
+pub struct VersionedEngine {
+    engine: Box<dyn StorageEngine>,
+    version_high_water: u64,
+}
+
+impl VersionedEngine {
+    pub fn new(engine: Box<dyn StorageEngine>) -> VersionedEngine {
+        VersionedEngine {
+            engine,
+            version_high_water: 0,
+        }
+    }
+
+    /// Returns the greatest version stored or given out so far.
+    pub fn version_high_water(&self) -> u64 {
+        self.version_high_water
+    }
+
+    /// Raises the high-water mark to the given version, if it is greater,
+    /// for example to the mark of the node that the stored values were received from.
+    pub fn raise_version_high_water(&mut self, version: u64) {
+        self.version_high_water = self.version_high_water.max(version);
+    }
+
+    /// Returns a version greater than every version stored or given out so far.
+    pub fn next_version(&mut self) -> u64 {
+        self.version_high_water += 1;
+        self.version_high_water
+    }
+
+    /// Returns the version that `next_version` would give out, without giving it out.
+    /// Storing a value of this version gives it out, so a write that fails does not use up a version.
+    pub fn upcoming_version(&self) -> u64 {
+        self.version_high_water + 1
+    }
+
+    fn raise_to(&mut self, value: &StoredValue) {
+        if let Ok(entry) = value.verified() {
+            self.raise_version_high_water(entry.version);
+        }
+    }
+}
+
+impl StorageEngine for VersionedEngine {
+    fn get(&self, key: u64) -> Result<Option<StoredValue>, StorageError> {
+        self.engine.get(key)
+    }
+
+    fn put(&mut self, key: u64, value: StoredValue) -> Result<(), StorageError> {
+        self.raise_to(&value);
+        self.engine.put(key, value)
+    }
+
+    fn put_within_budget(&mut self, key: u64, value: StoredValue) -> Result<(), StorageError> {
+        // a value that does not fit the budget is not stored, so its version is not given out
+        let version = value.verified().ok().map(|entry| entry.version);
+        self.engine.put_within_budget(key, value)?;
+        if let Some(version) = version {
+            self.raise_version_high_water(version);
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, key: u64) -> Result<Option<StoredValue>, StorageError> {
+        self.engine.delete(key)
+    }
+
+    fn keys(&self, key_range: RangeInclusive<u64>) -> Vec<u64> {
+        self.engine.keys(key_range)
+    }
+
+    fn expired_keys(&self, now: u64) -> Vec<u64> {
+        self.engine.expired_keys(now)
+    }
+
+    fn copy_range(
+        &self,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, Entry)>, StorageError> {
+        self.engine.copy_range(key_range)
+    }
+
+    fn extract_range(
+        &mut self,
+        key_range: RangeInclusive<u64>,
+    ) -> Result<Vec<(u64, StoredValue)>, StorageError> {
+        self.engine.extract_range(key_range)
+    }
+
+    fn stats(&self) -> StorageStats {
+        self.engine.stats()
+    }
+}
@@ -0,0 +1,115 @@
+use crate::helpers::entry::Entry;
+use std::fmt;
+
+/// An entry kept in a storage together with the CRC32C checksum it had when it was stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredValue {
+    entry: Entry,
+    checksum: u32,
+}
+
+/// The error returned when a stored value no longer matches its checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptedValue;
+
+impl fmt::Display for CorruptedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stored value does not match its checksum")
+    }
+}
+
+impl std::error::Error for CorruptedValue {}
+
+impl StoredValue {
+    pub fn new(entry: Entry) -> StoredValue {
+        let checksum = checksum(&entry);
+        StoredValue { entry, checksum }
+    }
+
+    /// Returns the entry, or an error if it has changed since it was stored.
+    pub fn verified(&self) -> Result<&Entry, CorruptedValue> {
+        if checksum(&self.entry) != self.checksum {
+            return Err(CorruptedValue);
+        }
+        Ok(&self.entry)
+    }
+
+    /// Returns the entry, or an error if it has changed since it was stored.
+    pub fn into_verified(self) -> Result<Entry, CorruptedValue> {
+        self.verified()?;
+        Ok(self.entry)
+    }
+
+    /// Returns the length of the value and the past values kept with it in bytes.
+    pub fn value_length(&self) -> usize {
+        let history_length: usize = self.entry.history.iter().map(|past| past.value.len()).sum();
+        self.entry.value.len() + history_length
+    }
+
+    /// Returns the unverified expiry time of the entry.
+    pub fn expires_at(&self) -> Option<u64> {
+        self.entry.expires_at
+    }
+
+    /// Encodes the checksum, four big-endian bytes, the metadata of the entry and the value.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(4 + self.entry.metadata_length() + self.entry.value.len());
+        bytes.extend_from_slice(&self.checksum.to_be_bytes());
+        bytes.extend_from_slice(&self.entry.encode_metadata());
+        bytes.extend_from_slice(&self.entry.value);
+        bytes
+    }
+
+    /// Decodes a value encoded by `to_bytes`, keeping the checksum it was stored with.
+    pub fn from_bytes(bytes: &[u8]) -> Result<StoredValue, CorruptedValue> {
+        let checksum =
+            u32::from_be_bytes(bytes.get(..4).ok_or(CorruptedValue)?.try_into().unwrap());
+        let (mut entry, metadata_length) =
+            Entry::decode_metadata(&bytes[4..]).ok_or(CorruptedValue)?;
+        entry.value = bytes[4 + metadata_length..].to_vec();
+        Ok(StoredValue { entry, checksum })
+    }
+}
+
+/// Returns the checksum of the metadata and the value of the given entry.
+fn checksum(entry: &Entry) -> u32 {
+    let checksum = crc32c::crc32c(&entry.encode_metadata());
+    crc32c::crc32c_append(checksum, &entry.value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn corruption_detection() {
+        let mut stored = StoredValue::new(Entry::new(b"hello".to_vec()));
+        assert_eq!(stored.verified(), Ok(&Entry::new(b"hello".to_vec())));
+
+        stored.entry.value[0] ^= 1;
+        assert_eq!(stored.verified(), Err(CorruptedValue));
+        assert_eq!(stored.into_verified(), Err(CorruptedValue));
+    }
+
+    #[test]
+    fn byte_encoding() {
+        let stored = StoredValue::new(Entry {
+            value: b"hello".to_vec(),
+            expires_at: Some(1000),
+            deleted: false,
+            version: 2,
+            written_at: 500,
+            history: vec![Entry::new(b"hi".to_vec())],
+        });
+        let mut bytes = stored.to_bytes();
+        assert_eq!(StoredValue::from_bytes(&bytes), Ok(stored));
+
+        bytes[11] ^= 1;
+        assert_eq!(
+            StoredValue::from_bytes(&bytes).unwrap().verified(),
+            Err(CorruptedValue)
+        );
+        assert_eq!(StoredValue::from_bytes(&[1, 2]), Err(CorruptedValue));
+    }
+}
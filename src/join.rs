@@ -1,63 +1,109 @@
-use crate::helpers::communication::{resolve_hostname_to_ip_address, Connection};
+use crate::blocks::backup::BackupHandle;
+use crate::blocks::leader::LeaderHandle;
+use crate::helpers::communication::{
+    decode_node_list, resolve_address, Connection, ConnectionPool, KvPairsReceiver, Message,
+    DEFAULT_PORT,
+};
 use crate::helpers::neighbors::{find_neighbors_nonwrapping, find_neighbors_wrapping};
-use crate::PeerNode;
+use crate::{Config, PeerNode};
 use rand::{thread_rng, Rng};
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
 use std::ops::RangeInclusive;
+use std::sync::Arc;
 
 /// Runs the join sequence of communications to become a member of the system.
-/// Returns this node ID, node list and initial leader and backup key-value pairs.
+/// The initial leader and backup key-value pairs are written to the given storages as they arrive.
+/// Returns this node ID, node list and the keys received for the leader and the backup storage.
+/// Other nodes are contacted through the given pool, except for the first request to the known node.
+/// The preferred node ID is used if no other node has it.
 pub async fn run_join_procedure(
-    known_node_host: Option<&str>,
-) -> (u64, Vec<PeerNode>, Vec<(u64, Vec<u8>)>, Vec<(u64, Vec<u8>)>) {
-    let known_node_ip_address = match known_node_host {
-        Some(host) => {
-            if let Some(result) = resolve_hostname_to_ip_address(host) {
-                Some(result)
-            } else {
-                panic!("failed to resolve known host address, aborting");
-            }
-        }
+    config: &Config,
+    connection_pool: &Arc<ConnectionPool>,
+    preferred_node_id: Option<u64>,
+    leader: &LeaderHandle,
+    backup: &BackupHandle,
+) -> (u64, Vec<PeerNode>, HashSet<u64>, HashSet<u64>) {
+    let known_node_address = match config.known_node_host.as_deref() {
+        Some(address) => match resolve_address(address, DEFAULT_PORT) {
+            Some(result) => Some(result),
+            None => panic!("failed to resolve known host address, aborting"),
+        },
         None => None,
     };
 
-    let mut node_list = match known_node_ip_address {
-        Some(ip_address) => request_node_list(&ip_address).await,
-        None => Vec::new(),
+    let (mut node_list, local_ip_address) = match known_node_address {
+        Some((ip_address, port)) => {
+            let (node_list, local_ip_address) = request_node_list(ip_address, port).await;
+            (node_list, Some(local_ip_address))
+        }
+        None => (Vec::new(), None),
     };
 
-    let node_id = thread_rng().gen();
+    let known_node_ip_address = known_node_address.map(|(ip_address, _)| ip_address);
+    let (advertised_ip_address, advertised_port) =
+        match advertised_address(config, known_node_ip_address, local_ip_address) {
+            Ok(result) => result,
+            Err(error) => panic!(
+                "failed to determine the address of this node ({}), set DS_ADVERTISED_ADDRESS, aborting",
+                error
+            ),
+        };
+
+    println!(
+        "advertising address {} port {}",
+        advertised_ip_address, advertised_port
+    );
+
+    let node_id = match preferred_node_id {
+        Some(node_id) if node_list.iter().all(|node| node.id != node_id) => node_id,
+        _ => thread_rng().gen(),
+    };
 
     println!("using node id {}", node_id);
 
     let (smaller_neighbor, greater_neighbor) = find_neighbors_nonwrapping(node_id, &node_list);
 
-    let leader_kv_pairs = match (smaller_neighbor, greater_neighbor) {
-        (None, Some(greater)) => request_primary_kv_pairs(&greater, 0..=node_id).await,
-        (Some(smaller), None) => {
-            request_primary_kv_pairs(&smaller, (smaller.id + 1)..=u64::MAX).await
-        }
-        (Some(smaller), Some(greater)) => {
-            request_primary_kv_pairs(&greater, (smaller.id + 1)..=node_id).await
-        }
-        (None, None) => Vec::new(),
+    let leader_transfer = match (smaller_neighbor, greater_neighbor) {
+        (None, Some(greater)) => Some((greater, 0..=node_id)),
+        (Some(smaller), None) => Some((smaller.clone(), (smaller.id + 1)..=u64::MAX)),
+        (Some(smaller), Some(greater)) => Some((greater, (smaller.id + 1)..=node_id)),
+        (None, None) => None,
     };
+    let mut leader_keys = HashSet::new();
+    if let Some((neighbor, key_range)) = leader_transfer {
+        request_primary_kv_pairs(
+            connection_pool,
+            &neighbor,
+            key_range,
+            leader,
+            &mut leader_keys,
+        )
+        .await;
+    }
 
     // request backup key-value pairs
     let [smaller_neighbor, greater_neighbor] = find_neighbors_wrapping(node_id, &node_list);
-    let mut backup_kv_pairs = Vec::new();
-    if let Some(smaller_neighbor) = smaller_neighbor {
-        backup_kv_pairs.extend_from_slice(&request_backup_kv_pairs(&smaller_neighbor).await);
-    }
-    if let Some(greater_neighbor) = greater_neighbor {
-        backup_kv_pairs.extend_from_slice(&request_backup_kv_pairs(&greater_neighbor).await);
+    let mut backup_keys = HashSet::new();
+    for neighbor in [smaller_neighbor, greater_neighbor].into_iter().flatten() {
+        request_backup_kv_pairs(connection_pool, &neighbor, backup, &mut backup_keys).await;
     }
 
     // announce every existing node about the join in parallel
     let mut announce_handles = Vec::new();
     for peer_node in node_list.iter() {
-        let peer_ip_address = peer_node.ip_address.clone();
+        let peer_node = peer_node.clone();
+        let connection_pool_clone = Arc::clone(connection_pool);
         let handle = tokio::task::spawn(async move {
-            announce_joining(node_id, peer_ip_address).await;
+            announce_joining(
+                &connection_pool_clone,
+                node_id,
+                advertised_ip_address,
+                advertised_port,
+                &peer_node,
+            )
+            .await;
         });
         announce_handles.push(handle);
     }
@@ -68,131 +114,235 @@ pub async fn run_join_procedure(
     // add this node itself to the list of nodes
     node_list.push(PeerNode {
         id: node_id,
-        ip_address: "127.0.0.1".to_string(),
+        ip_address: advertised_ip_address,
+        port: advertised_port,
     });
 
-    (node_id, node_list, leader_kv_pairs, backup_kv_pairs)
+    (node_id, node_list, leader_keys, backup_keys)
 }
 
-async fn request_node_list(known_node_ip_address: &str) -> Vec<PeerNode> {
-    let mut connection = Connection::new(known_node_ip_address.to_string(), &[10, 0, 0, 0, 5])
-        .await
-        .unwrap();
-
-    let response = connection.read_message().await;
+/// Returns the address and port that other nodes should use to contact this node.
+/// Uses the configured advertised address if there is one, otherwise the bind address,
+/// otherwise the local address of the connection to the known node,
+/// and as a last resort the address of the interface routing to the public internet
+/// in the address family of the known node, or of the bind address for the first node of a system.
+/// Fails if none of them can be determined, since a loopback address would be unreachable for the other nodes.
+fn advertised_address(
+    config: &Config,
+    known_node_ip_address: Option<IpAddr>,
+    local_ip_address: Option<IpAddr>,
+) -> io::Result<(IpAddr, u16)> {
+    if let Some(address) = config.advertised_address.as_deref() {
+        return match resolve_address(address, config.port) {
+            Some(result) => Ok(result),
+            None => panic!("failed to resolve advertised address, aborting"),
+        };
+    }
 
-    let response_length = u32::from_be_bytes(response[1..5].try_into().unwrap()) as usize;
+    if !config.bind_address.is_unspecified() {
+        return Ok((config.bind_address, config.port));
+    }
 
-    if response[0] != 0 || response_length != response.len() {
-        panic!("invalid node list response");
+    if let Some(ip_address) = local_ip_address {
+        return Ok((ip_address.to_canonical(), config.port));
     }
 
-    let mut node_list = Vec::new();
+    // a node listening on `::` also accepts IPv4, so the first node of a system prefers an IPv4 address
+    let families = match known_node_ip_address.map(|ip_address| ip_address.to_canonical()) {
+        Some(ip_address) => vec![ip_address],
+        None if config.bind_address.is_ipv4() => vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+        None => vec![
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        ],
+    };
 
-    for i in (5..response_length).step_by(12) {
-        let node_id = u64::from_be_bytes(response[i..i + 8].try_into().unwrap());
-        let node_ip_address = ip_address_from_bytes(response[i + 8..i + 12].try_into().unwrap());
+    let mut last_error = None;
+    for family in families {
+        match routed_ip_address(family) {
+            Ok(ip_address) => return Ok((ip_address, config.port)),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    Err(last_error.unwrap())
+}
 
-        node_list.push(PeerNode {
-            id: node_id,
-            ip_address: if node_ip_address == "127.0.0.1" {
-                known_node_ip_address.to_string()
-            } else {
-                node_ip_address
-            },
-        });
+/// Returns the address of the interface routing to the public internet
+/// in the address family of the given address.
+fn routed_ip_address(family: IpAddr) -> io::Result<IpAddr> {
+    // connecting a UDP socket sends no packets but selects the outgoing interface,
+    // here towards the addresses reserved for documentation
+    let (unspecified, probe) = match family {
+        IpAddr::V4(_) => (
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+        ),
+        IpAddr::V6(_) => (
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        ),
+    };
+    let socket = UdpSocket::bind((unspecified, 0))?;
+    socket.connect((probe, 9))?;
+    let ip_address = socket.local_addr()?.ip();
+
+    if ip_address.is_unspecified() || ip_address.is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("no route to the internet over {}", ip_address),
+        ));
     }
+    Ok(ip_address)
+}
 
-    println!("received node list {:?}", node_list);
+/// Requests the list of nodes from the known node.
+/// Returns the node list and the local IP address of the connection to the known node.
+async fn request_node_list(
+    known_node_ip_address: IpAddr,
+    known_node_port: u16,
+) -> (Vec<PeerNode>, IpAddr) {
+    let mut connection = match Connection::new(
+        known_node_ip_address,
+        known_node_port,
+        &Message::NodeListRequest,
+    )
+    .await
+    {
+        Ok(connection) => connection,
+        Err(error) => panic!("failed to request node list ({}), aborting", error),
+    };
 
-    node_list
-}
+    let node_list = match connection.read_message().await {
+        Ok(Message::Response(payload)) => decode_node_list(&payload),
+        _ => panic!("invalid node list response"),
+    };
+    let node_list = match node_list {
+        Ok(node_list) => node_list,
+        Err(error) => panic!("malformed node list response ({}), aborting", error),
+    };
+
+    println!("received node list {:?}", node_list);
 
-/// Returns the IPv4 address encoded by the given bytes.
-fn ip_address_from_bytes(bytes: &[u8; 4]) -> String {
-    bytes.map(|b| b.to_string()).join(".")
+    (node_list, connection.local_ip_address())
 }
 
+/// Requests the leader key-value pairs of the given range from the given neighbor,
+/// writing each chunk to the leader storage as it arrives and adding its keys to the given set.
 async fn request_primary_kv_pairs(
+    connection_pool: &ConnectionPool,
     neighbor: &PeerNode,
     key_range: RangeInclusive<u64>,
-) -> Vec<(u64, Vec<u8>)> {
-    let request = [
-        vec![11, 0, 0, 0, 21],
-        key_range.start().to_be_bytes().to_vec(),
-        key_range.end().to_be_bytes().to_vec(),
-    ]
-    .concat();
-
-    let mut connection = Connection::new(neighbor.ip_address.clone(), &request)
-        .await
-        .unwrap();
-
-    let response = connection.read_message().await;
+    leader: &LeaderHandle,
+    keys: &mut HashSet<u64>,
+) {
+    let request = Message::LeaderTransferRequest { key_range };
+
+    let mut connection = match connection_pool.open(neighbor, &request).await {
+        Ok(connection) => connection,
+        Err(error) => panic!("failed to request leader transfer ({}), aborting", error),
+    };
 
-    if response.len() < 5 || response[0] != 0 {
-        panic!("received invalid leader transfer response, aborting");
-    }
-    let response_length = u32::from_be_bytes(response[1..5].try_into().unwrap()) as usize;
-    if response.len() != response_length {
-        panic!("received invalid leader transfer response, aborting");
+    let mut receiver = KvPairsReceiver::new(&mut connection);
+    loop {
+        let chunk = match receiver.next_chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(error) => panic!(
+                "received invalid leader transfer response ({}), aborting",
+                error
+            ),
+        };
+        keys.extend(chunk.iter().map(|(key, _)| *key));
+        if let Err(error) = leader.insert_many(chunk).await {
+            // the neighbor keeps the keys unless the transfer is acknowledged
+            connection
+                .send_error(error.error_code(), &error.to_string())
+                .await;
+            panic!(
+                "failed to write initial leader kv-pairs ({}), aborting",
+                error
+            );
+        }
     }
 
-    let mut kv_pairs = Vec::new();
-
-    let mut i = 5;
-    while i < response_length {
-        let key = u64::from_be_bytes(response[i..i + 8].try_into().unwrap());
-        let value_length = u32::from_be_bytes(response[i + 8..i + 12].try_into().unwrap()) as usize;
-        let value = &response[i + 12..i + 12 + value_length];
-        kv_pairs.push((key, value.to_vec()));
-        i += value_length + 12;
+    // the versions of the transferred keys that were removed before the transfer are not reused
+    if let Some(version_high_water) = receiver.version_high_water() {
+        leader.raise_version_high_water(version_high_water).await;
     }
 
-    kv_pairs
+    // the neighbor removes the transferred keys only once they are stored here
+    if connection.acknowledges_transfers() {
+        connection.send_message(&Message::ok()).await;
+    }
 }
 
-async fn request_backup_kv_pairs(neighbor: &PeerNode) -> Vec<(u64, Vec<u8>)> {
+/// Requests the leader key-value pairs of the given neighbor for backup,
+/// writing each chunk to the backup storage as it arrives and adding its keys to the given set.
+async fn request_backup_kv_pairs(
+    connection_pool: &ConnectionPool,
+    neighbor: &PeerNode,
+    backup: &BackupHandle,
+    keys: &mut HashSet<u64>,
+) {
     println!("requesting initial backups from {}", neighbor.ip_address);
 
     // make request
-    let request = [12, 0, 0, 0, 5];
-    let mut connection = Connection::new(neighbor.ip_address.to_string(), &request)
+    let mut connection = match connection_pool
+        .open(neighbor, &Message::BackupRequest)
         .await
-        .unwrap();
-
-    let response = connection.read_message().await;
+    {
+        Ok(connection) => connection,
+        Err(error) => panic!("failed to request backup transfer ({}), panicing", error),
+    };
 
-    if response.len() < 5 || response[0] != 0 {
-        panic!("received invalid backup transfer response, panicing")
+    let mut receiver = KvPairsReceiver::new(&mut connection);
+    loop {
+        let chunk = match receiver.next_chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(error) => panic!(
+                "received invalid backup transfer response ({}), panicing",
+                error
+            ),
+        };
+        keys.extend(chunk.iter().map(|(key, _)| *key));
+        if let Err(error) = backup.insert_many(chunk).await {
+            panic!("failed to write initial backup kv-pairs ({})", error);
+        }
     }
 
-    let response_length = u32::from_be_bytes(response[1..5].try_into().unwrap()) as usize;
-
-    let mut kv_pairs = Vec::new();
-
-    let mut i = 5;
-    while i < response_length {
-        let key = u64::from_be_bytes(response[i..i + 8].try_into().unwrap());
-        let value_length = u32::from_be_bytes(response[i + 8..i + 12].try_into().unwrap()) as usize;
-        let value = &response[i + 12..i + 12 + value_length];
-        kv_pairs.push((key, value.to_vec()));
-        i += value_length + 12;
+    if let Some(version_high_water) = receiver.version_high_water() {
+        backup.raise_version_high_water(version_high_water).await;
     }
-
-    kv_pairs
 }
 
-async fn announce_joining(this_node_id: u64, peer_node_ip_address: String) {
-    let request = [vec![13, 0, 0, 0, 13], this_node_id.to_be_bytes().to_vec()].concat();
+async fn announce_joining(
+    connection_pool: &ConnectionPool,
+    this_node_id: u64,
+    this_node_ip_address: IpAddr,
+    this_node_port: u16,
+    peer_node: &PeerNode,
+) {
+    let request = Message::JoinAnnouncement {
+        node_id: this_node_id,
+        ip_address: this_node_ip_address,
+        port: this_node_port,
+    };
 
-    let mut connection = Connection::new(peer_node_ip_address, &request)
-        .await
-        .unwrap();
+    let mut connection = match connection_pool.open(peer_node, &request).await {
+        Ok(connection) => connection,
+        Err(error) => {
+            println!(
+                "failed to announce joining to {} ({}), ignoring",
+                peer_node.ip_address, error
+            );
+            return;
+        }
+    };
 
     let response = connection.read_message().await;
 
-    if response != vec![0, 0, 0, 0, 7, 111, 107] {
+    if !matches!(response, Ok(message) if message.is_ok()) {
         println!(
             "received invalid ack to join announcement from {}, ignoring",
             connection.address
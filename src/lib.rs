@@ -1,34 +1,154 @@
-use crate::helpers::communication::listen_messages;
+use crate::helpers::communication::{
+    configure, listen_messages, ConnectionError, ConnectionPool, ErrorCode, Message,
+};
+use crate::helpers::storage::{
+    Durability, MemoryBudget, RecoveredState, ShardedStorage, StorageEngine, StorageError,
+    StorageKind,
+};
+use blocks::backup::BackupHandle;
+use blocks::leader::LeaderHandle;
+use helpers::neighbors::find_neighbors_wrapping;
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{mpsc, Mutex};
 use tokio_stream::StreamExt;
 
+pub use config::Config;
+
 mod blocks;
+mod config;
 mod helpers;
 mod join;
+mod recovery;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PeerNode {
     pub id: u64,
-    pub ip_address: String,
+    pub ip_address: IpAddr,
+    pub port: u16,
 }
 
-pub async fn start_node(known_node_host: Option<String>) {
-    // run the join sequence of communications
-    let (this_node_id, node_list, initial_leader_kv_pairs, initial_backup_kv_pairs) =
-        join::run_join_procedure(known_node_host.as_deref()).await;
+pub async fn start_node(config: Config) {
+    configure(config.connection.clone());
+    let connection_pool = Arc::new(ConnectionPool::new());
+
+    // recover the storages of the previous run from the data directory
+    let (durability, recovered) = match &config.data_directory {
+        Some(directory) => match Durability::open(directory) {
+            Ok((durability, recovered)) => (Some(durability), recovered),
+            Err(error) => panic!("failed to recover from the data directory ({})", error),
+        },
+        None => (None, RecoveredState::default()),
+    };
+    println!(
+        "recovered {} leader and {} backup kv-pairs",
+        recovered.leader_kv_pairs.len(),
+        recovered.backup_kv_pairs.len()
+    );
+
+    // create the storages, shared in-process with the fault tolerance block
+    let budget = MemoryBudget::new(config.memory_budget, config.budget_policy);
+    let leader = LeaderHandle::new(open_leader_storage(&config, &durability, &budget));
+    let backup = BackupHandle::new(
+        open_storage(&config, &durability, &budget, StorageKind::Backup, "backup"),
+        durability.clone(),
+    );
+
+    // run the join sequence of communications, which fills the storages
+    let (this_node_id, node_list, joined_leader_keys, joined_backup_keys) =
+        join::run_join_procedure(
+            &config,
+            &connection_pool,
+            recovered.node_id,
+            &leader,
+            &backup,
+        )
+        .await;
+
+    let restored = recovery::restore_kv_pairs(
+        recovered,
+        this_node_id,
+        &node_list,
+        &joined_leader_keys,
+        &joined_backup_keys,
+    );
+    let backup_nodes = find_neighbors_wrapping(this_node_id, &node_list);
     let node_list = Arc::new(Mutex::new(node_list));
 
+    println!(
+        "leader storage starting with {} received and {} restored kv-pairs",
+        joined_leader_keys.len(),
+        restored.leader_kv_pairs.len()
+    );
+    if let Err(error) = leader.insert_many(restored.leader_kv_pairs).await {
+        panic!("failed to write restored leader kv-pairs ({})", error);
+    }
+    if let Err(error) = backup.insert_many(restored.backup_kv_pairs).await {
+        panic!("failed to write restored backup kv-pairs ({})", error);
+    }
+
+    // the backups of the keys that only this node had are created again
+    if !restored.restored_leader_keys.is_empty() {
+        println!(
+            "restored leader keys {:?}, replicating to backup nodes",
+            restored.restored_leader_keys
+        );
+        for backup_node in backup_nodes.into_iter().flatten() {
+            blocks::fault_tolerance::create_new_backup_replica(
+                &connection_pool,
+                &leader,
+                backup_node,
+            )
+            .await;
+        }
+    }
+
+    // replace the recovered state in the data directory and keep taking snapshots
+    if let Some(durability) = durability {
+        if let Err(error) = write_snapshot(&durability, this_node_id, &leader, &backup).await {
+            panic!("failed to write the initial snapshot ({})", error);
+        }
+        let leader_clone = leader.clone();
+        let backup_clone = backup.clone();
+        let snapshot_interval = config.snapshot_interval;
+        tokio::task::spawn(async move {
+            take_snapshots(
+                durability,
+                snapshot_interval,
+                this_node_id,
+                leader_clone,
+                backup_clone,
+            )
+            .await;
+        });
+    }
+
+    // remove the expired values in the background, reads hide them meanwhile
+    let leader_clone = leader.clone();
+    let backup_clone = backup.clone();
+    let expiry_sweep_interval = config.expiry_sweep_interval;
+    tokio::task::spawn(async move {
+        sweep_expired(expiry_sweep_interval, leader_clone, backup_clone).await;
+    });
+
     // start the blocks
     let (leader_sender, leader_receiver) = mpsc::unbounded_channel();
     let leader_sender = Arc::new(leader_sender);
     let node_list_clone = Arc::clone(&node_list);
+    let connection_pool_clone = Arc::clone(&connection_pool);
+    let leader_clone = leader.clone();
+    let tombstone_lifetime = config.tombstone_lifetime;
+    let history = config.history;
     tokio::task::spawn(async move {
         blocks::leader::leader_block(
             leader_receiver,
-            initial_leader_kv_pairs,
+            leader_clone,
             node_list_clone,
             this_node_id,
+            connection_pool_clone,
+            tombstone_lifetime,
+            history,
         )
         .await;
     });
@@ -36,8 +156,19 @@ pub async fn start_node(known_node_host: Option<String>) {
     let (client_sender, client_receiver) = mpsc::unbounded_channel();
     let client_sender = Arc::new(client_sender);
     let node_list_clone = Arc::clone(&node_list);
+    let connection_pool_clone = Arc::clone(&connection_pool);
+    let leader_clone = leader.clone();
+    let backup_clone = backup.clone();
     tokio::task::spawn(async move {
-        blocks::client::client_block(client_receiver, node_list_clone).await;
+        blocks::client::client_block(
+            client_receiver,
+            node_list_clone,
+            connection_pool_clone,
+            leader_clone,
+            backup_clone,
+            budget,
+        )
+        .await;
     });
 
     let (peer_sender, peer_receiver) = mpsc::unbounded_channel();
@@ -49,24 +180,29 @@ pub async fn start_node(known_node_host: Option<String>) {
 
     let (backup_sender, backup_receiver) = mpsc::unbounded_channel();
     let backup_sender = Arc::new(backup_sender);
+    let backup_clone = backup.clone();
     tokio::task::spawn(async move {
-        blocks::backup::backup_block(backup_receiver, initial_backup_kv_pairs).await;
+        blocks::backup::backup_block(backup_receiver, backup_clone).await;
     });
 
     let (fault_tolerance_sender, fault_tolerance_receiver) = mpsc::unbounded_channel();
     let fault_tolerance_sender = Arc::new(fault_tolerance_sender);
     let node_list_clone = Arc::clone(&node_list);
+    let connection_pool_clone = Arc::clone(&connection_pool);
     tokio::task::spawn(async move {
         blocks::fault_tolerance::fault_tolerance_block(
             fault_tolerance_receiver,
             node_list_clone,
             this_node_id,
+            connection_pool_clone,
+            leader,
+            backup,
         )
         .await;
     });
 
     // infinitely listen for incoming connections and direct them to respective blocks
-    let mut incoming_connections_stream = listen_messages().await;
+    let mut incoming_connections_stream = listen_messages(config.bind_address, config.port).await;
 
     while let Some(mut connection) = incoming_connections_stream.next().await {
         let leader_sender_clone = Arc::clone(&leader_sender);
@@ -76,22 +212,178 @@ pub async fn start_node(known_node_host: Option<String>) {
         let fault_tolerance_sender_clone = Arc::clone(&fault_tolerance_sender);
 
         tokio::task::spawn(async move {
-            let message = connection.read_message().await;
+            let message = match connection.read_message().await {
+                Ok(message) => message,
+                Err(error) => {
+                    println!(
+                        "received invalid message from {} ({}), dropping",
+                        connection.address, error
+                    );
+                    match error {
+                        ConnectionError::Decode(_) | ConnectionError::MessageTooLong { .. } => {
+                            connection
+                                .send_error(ErrorCode::BadRequest, &error.to_string())
+                                .await;
+                        }
+                        ConnectionError::ChecksumMismatch => {
+                            connection
+                                .send_error(ErrorCode::Corrupted, &error.to_string())
+                                .await;
+                        }
+                        _ => {}
+                    }
+                    return;
+                }
+            };
+
+            if let Err(reason) = connection.authorize(&message) {
+                println!(
+                    "refused unauthorized request from {} ({})",
+                    connection.address, reason
+                );
+                connection.send_error(ErrorCode::Unauthorized, reason).await;
+                return;
+            }
 
-            match message.first() {
-                Some(1) | Some(2) | Some(11) | Some(12) | Some(33) => {
+            match message {
+                Message::LeaderRead { .. }
+                | Message::LeaderWrite { .. }
+                | Message::LeaderDelete { .. }
+                | Message::LeaderTransferRequest { .. }
+                | Message::BackupRequest => {
                     leader_sender_clone.send((connection, message)).unwrap()
                 }
-                Some(10) | Some(13) => peer_sender_clone.send((connection, message)).unwrap(),
-                Some(20) | Some(21) | Some(32) => {
+                Message::NodeListRequest | Message::JoinAnnouncement { .. } => {
+                    peer_sender_clone.send((connection, message)).unwrap()
+                }
+                Message::BackupWrite { .. } | Message::BackupArrayWrite { .. } => {
                     backup_sender_clone.send((connection, message)).unwrap()
                 }
-                Some(30) | Some(31) => fault_tolerance_sender_clone
-                    .send((connection, message))
-                    .unwrap(),
-                Some(200) | Some(202) => client_sender_clone.send((connection, message)).unwrap(),
-                _ => println!("received invalid message, dropping"),
+                Message::NeighborDown { .. } | Message::PeerDown { .. } => {
+                    fault_tolerance_sender_clone
+                        .send((connection, message))
+                        .unwrap()
+                }
+                Message::ClientRead { .. }
+                | Message::ClientWrite { .. }
+                | Message::UsageRequest
+                | Message::ClientDelete { .. }
+                | Message::ExportRequest { .. } => {
+                    client_sender_clone.send((connection, message)).unwrap()
+                }
+                Message::Response(_)
+                | Message::CompareAndSwap { .. }
+                | Message::Error { .. }
+                | Message::OpenLink { .. }
+                | Message::Authenticate { .. }
+                | Message::EnableChecksums => {
+                    println!("received unexpected response, dropping");
+                    connection
+                        .send_error(ErrorCode::BadRequest, "expected a request message")
+                        .await;
+                }
             };
         });
     }
 }
+
+/// Opens the shards of the leader storage like `open_storage` does.
+fn open_leader_storage(
+    config: &Config,
+    durability: &Option<Arc<Durability>>,
+    budget: &Arc<MemoryBudget>,
+) -> ShardedStorage {
+    let shards = (0..config.leader_shards.get())
+        .map(|shard| {
+            let name = format!("leader-{}", shard);
+            open_storage(config, durability, budget, StorageKind::Leader, &name)
+        })
+        .collect();
+    ShardedStorage::new(shards, Arc::clone(budget), durability.clone())
+}
+
+/// Opens the storage engine of the given name selected by the configuration,
+/// logging its changes if the node has a data directory and counting it in the memory budget.
+/// Panics if the storage cannot be opened.
+fn open_storage(
+    config: &Config,
+    durability: &Option<Arc<Durability>>,
+    budget: &Arc<MemoryBudget>,
+    kind: StorageKind,
+    name: &str,
+) -> Box<dyn StorageEngine> {
+    let storage = match config.storage.open(name) {
+        Ok(storage) => storage,
+        Err(error) => panic!("failed to open {} storage ({})", name, error),
+    };
+    let storage = match durability {
+        Some(durability) => durability.logged(kind, storage),
+        None => storage,
+    };
+    match kind {
+        StorageKind::Leader => budget.accounted_evictable(storage),
+        StorageKind::Backup => budget.accounted(storage),
+    }
+}
+
+/// Periodically replaces the log in the data directory with a snapshot of the storages.
+async fn take_snapshots(
+    durability: Arc<Durability>,
+    interval: Duration,
+    this_node_id: u64,
+    leader: LeaderHandle,
+    backup: BackupHandle,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if !durability.has_changes() {
+            continue;
+        }
+        match write_snapshot(&durability, this_node_id, &leader, &backup).await {
+            Ok(()) => println!("wrote a snapshot of the storages"),
+            Err(error) => println!("failed to write a snapshot ({}), keeping the log", error),
+        }
+    }
+}
+
+/// Periodically removes the expired values from the storages.
+/// The leader and the backups remove their copies independently, since the expiry time is replicated.
+async fn sweep_expired(interval: Duration, leader: LeaderHandle, backup: BackupHandle) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match leader.remove_expired().await {
+            Ok(keys) if !keys.is_empty() => println!("removed expired leader keys {:?}", keys),
+            Ok(_) => {}
+            Err(error) => println!("failed to remove expired leader keys ({})", error),
+        }
+        match backup.remove_expired().await {
+            Ok(keys) if !keys.is_empty() => println!("removed expired backup keys {:?}", keys),
+            Ok(_) => {}
+            Err(error) => println!("failed to remove expired backup keys ({})", error),
+        }
+    }
+}
+
+/// Writes a snapshot of the storages and removes the log files it replaces.
+/// The files are written on a blocking thread, so that the runtime does not wait for the disk.
+async fn write_snapshot(
+    durability: &Arc<Durability>,
+    this_node_id: u64,
+    leader: &LeaderHandle,
+    backup: &BackupHandle,
+) -> Result<(), StorageError> {
+    let first_kept_sequence = durability.rotate()?;
+    let leader_kv_pairs = leader.snapshot().await?;
+    let backup_kv_pairs = backup.snapshot().await?;
+    let durability = Arc::clone(durability);
+    tokio::task::spawn_blocking(move || {
+        durability.write_snapshot(
+            this_node_id,
+            &leader_kv_pairs,
+            &backup_kv_pairs,
+            first_kept_sequence,
+        )
+    })
+    .await
+    .expect("writing a snapshot panicked")
+}
@@ -1,9 +1,6 @@
-use ds_project::start_node;
-use std::env;
+use ds_project::{start_node, Config};
 
 #[tokio::main]
 async fn main() {
-    let known_node_host = env::var("DS_KNOWN_NODE").ok();
-
-    start_node(known_node_host).await;
+    start_node(Config::from_env()).await;
 }
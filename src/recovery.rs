@@ -0,0 +1,112 @@
+use crate::helpers::entry::Entry;
+use crate::helpers::neighbors::{find_neighbors_wrapping, leader_key_range};
+use crate::helpers::storage::RecoveredState;
+use crate::PeerNode;
+use std::collections::HashSet;
+
+/// The recovered contents of the storages of a node that has rejoined the ring after a restart,
+/// to be added to the key-value pairs received when joining.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RestoredKvPairs {
+    pub leader_kv_pairs: Vec<(u64, Entry)>,
+    pub backup_kv_pairs: Vec<(u64, Entry)>,
+    /// The leader keys that only this node had, whose backups have to be created again.
+    pub restored_leader_keys: Vec<u64>,
+}
+
+/// Returns the recovered key-value pairs to be added to the ones received from the other nodes
+/// when joining the ring, given the keys that were received.
+/// The received pairs are newer, so a recovered pair is only kept if its key was not received
+/// and it belongs to the leader storage or the backup storage of this node in the current ring.
+pub fn restore_kv_pairs(
+    recovered: RecoveredState,
+    this_node_id: u64,
+    node_list: &[PeerNode],
+    received_leader_keys: &HashSet<u64>,
+    received_backup_keys: &HashSet<u64>,
+) -> RestoredKvPairs {
+    let leader_range = leader_key_range(this_node_id, node_list);
+    let backup_ranges: Vec<_> = find_neighbors_wrapping(this_node_id, node_list)
+        .into_iter()
+        .flatten()
+        .map(|neighbor| leader_key_range(neighbor.id, node_list))
+        .collect();
+
+    let leader_kv_pairs =
+        missing_kv_pairs(recovered.leader_kv_pairs, received_leader_keys, |key| {
+            leader_range.contains(&key)
+        });
+    let backup_kv_pairs =
+        missing_kv_pairs(recovered.backup_kv_pairs, received_backup_keys, |key| {
+            backup_ranges.iter().any(|range| range.contains(&key))
+        });
+
+    let restored_leader_keys = leader_kv_pairs.iter().map(|(key, _)| *key).collect();
+
+    RestoredKvPairs {
+        leader_kv_pairs,
+        backup_kv_pairs,
+        restored_leader_keys,
+    }
+}
+
+/// Returns the recovered pairs that are accepted by the filter and whose keys were not received.
+fn missing_kv_pairs(
+    recovered: Vec<(u64, Entry)>,
+    received_keys: &HashSet<u64>,
+    accept: impl Fn(u64) -> bool,
+) -> Vec<(u64, Entry)> {
+    recovered
+        .into_iter()
+        .filter(|(key, _)| accept(*key) && !received_keys.contains(key))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node(id: u64) -> PeerNode {
+        PeerNode {
+            id,
+            ip_address: "192.168.0.1".parse().unwrap(),
+            port: 52525,
+        }
+    }
+
+    #[test]
+    fn received_pairs_take_precedence() {
+        // this node 200 leads 101..=200 and backs up 0..=100 and 201..=MAX
+        let node_list = vec![node(100), node(200), node(300)];
+        let recovered = RecoveredState {
+            node_id: Some(200),
+            leader_kv_pairs: vec![
+                (150, Entry::new(vec![1])),
+                (160, Entry::new(vec![1])),
+                (250, Entry::new(vec![1])),
+            ],
+            backup_kv_pairs: vec![
+                (50, Entry::new(vec![1])),
+                (60, Entry::new(vec![1])),
+                (180, Entry::new(vec![1])),
+            ],
+        };
+
+        let restored = restore_kv_pairs(
+            recovered,
+            200,
+            &node_list,
+            &HashSet::from([150]),
+            &HashSet::from([50]),
+        );
+
+        assert_eq!(
+            restored,
+            RestoredKvPairs {
+                leader_kv_pairs: vec![(160, Entry::new(vec![1]))],
+                backup_kv_pairs: vec![(60, Entry::new(vec![1]))],
+                restored_leader_keys: vec![160],
+            }
+        );
+    }
+}